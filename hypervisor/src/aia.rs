@@ -0,0 +1,59 @@
+// AIA support
+/////
+// With QEMU virt machine (`-machine virt,aia=aplic-imsic,aia-guests=N`), the IMSIC of each hart has
+// N guest interrupt files next to its S-level interrupt file.
+// A guest interrupt file is assigned to a guest and mapped at the address of the S-level IMSIC in its
+// guest physical address space, so that the guest can receive MSIs without traps.
+// NOTE: only hart 0 is supported.
+
+use crate::memlayout;
+use crate::riscv;
+
+// the number of guest interrupt files
+static mut GEILEN: usize = 0;
+// the bitmap of the guest interrupt files in use
+static mut USED_FILES: usize = 0;
+
+pub fn init() {
+    // hgeie: only the bits of implemented guest interrupt files (1 - GEILEN) are writable
+    riscv::csr::hgeie::write(!0);
+    let implemented = riscv::csr::hgeie::read();
+    riscv::csr::hgeie::write(0);
+    unsafe {
+        GEILEN = (implemented >> 1).count_ones() as usize;
+        log::info!("guest interrupt files: {}", GEILEN);
+    }
+}
+
+pub fn is_available() -> bool {
+    unsafe { GEILEN > 0 }
+}
+
+// This function assigns a free guest interrupt file, and returns its number (1 - GEILEN).
+pub fn alloc_guest_file() -> Option<usize> {
+    unsafe {
+        let file = (1..=GEILEN).find(|f| USED_FILES & (1 << f) == 0)?;
+        USED_FILES |= 1 << file;
+        Some(file)
+    }
+}
+
+// This function gives back the guest interrupt file `file`.
+// TODO (enhancement): clear the pending interrupts in the file (through vsiselect/vsireg)
+pub fn free_guest_file(file: usize) {
+    unsafe {
+        USED_FILES &= !(1 << file);
+    }
+}
+
+// the host physical address of the guest interrupt file `file`
+pub fn guest_file_base(file: usize) -> usize {
+    memlayout::IMSIC_S_BASE + file * memlayout::IMSIC_FILE_SIZE
+}
+
+// This function sends the MSI `eiid` to the guest interrupt file `file` (through seteipnum_le).
+pub fn send_msi(file: usize, eiid: u32) {
+    unsafe {
+        (guest_file_base(file) as *mut u32).write_volatile(eiid);
+    }
+}
@@ -0,0 +1,238 @@
+// Block backends for guests
+/////
+// A guest disk is an image stored in a range of sectors on the host virtio disk.
+// The image is either raw sectors or a qcow2 image (see `qcow2`).
+// Optionally, the writes from a guest can be kept in an overlay on the hypervisor memory,
+// so that the guest believes it has a writable disk while the host image stays untouched.
+
+pub mod overlay;
+pub mod qcow2;
+
+use crate::virtio;
+
+#[derive(Debug)]
+pub enum Error {
+    NoDevice,
+    OutOfRange,
+    ReadOnly,
+    NoSpace,
+    OutOfMemory,
+    InvalidImage,
+    Unsupported,
+    Device(virtio::RequestError),
+}
+
+impl From<virtio::RequestError> for Error {
+    fn from(e: virtio::RequestError) -> Error {
+        Error::Device(e)
+    }
+}
+
+#[derive(Copy, Clone)]
+pub enum Format {
+    Raw,
+    Qcow2,
+}
+
+#[derive(Copy, Clone)]
+pub struct Config {
+    // the first sector of the guest disk in the host disk
+    pub start_sector: u64,
+    // the number of sectors; `None` means "until the end of the host disk"
+    pub sectors: Option<u64>,
+    // the format of the image in the region
+    pub format: Format,
+    // keep the writes in a RAM overlay instead of writing them back to the host disk
+    pub copy_on_write: bool,
+}
+
+fn host_queue() -> Result<&'static mut virtio::Queue, Error> {
+    unsafe {
+        match virtio::QUEUE {
+            Some(q) => Ok(&mut *q),
+            None => Err(Error::NoDevice),
+        }
+    }
+}
+
+// Region
+/////
+
+// A range of sectors on the host disk.
+#[derive(Copy, Clone)]
+pub struct Region {
+    pub start: u64,
+    pub sectors: u64,
+}
+
+impl Region {
+    pub fn new(config: &Config) -> Result<Region, Error> {
+        let capacity = host_queue()?.capacity();
+        let sectors = match config.sectors {
+            Some(n) => n,
+            None => capacity.saturating_sub(config.start_sector),
+        };
+        match config.start_sector.checked_add(sectors) {
+            Some(end) if end <= capacity => Ok(Region {
+                start: config.start_sector,
+                sectors: sectors,
+            }),
+            _ => Err(Error::OutOfRange),
+        }
+    }
+
+    fn check_range(&self, sector: u64, count: usize) -> Result<(), Error> {
+        match sector.checked_add(count as u64) {
+            Some(end) if end <= self.sectors => Ok(()),
+            _ => Err(Error::OutOfRange),
+        }
+    }
+
+    pub fn read(&self, sector: u64, buf: *mut u8, count: usize) -> Result<(), Error> {
+        self.check_range(sector, count)?;
+        Ok(host_queue()?.read(self.start + sector, buf, count)?)
+    }
+
+    pub fn write(&self, sector: u64, buf: *const u8, count: usize) -> Result<(), Error> {
+        self.check_range(sector, count)?;
+        Ok(host_queue()?.write(self.start + sector, buf, count)?)
+    }
+
+    pub fn flush(&self) -> Result<(), Error> {
+        Ok(host_queue()?.flush()?)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        match host_queue() {
+            Ok(q) => q.is_read_only(),
+            Err(_) => true,
+        }
+    }
+}
+
+// Image
+/////
+
+pub enum Image {
+    Raw(Region),
+    Qcow2(qcow2::Image),
+}
+
+impl Image {
+    pub fn open(config: &Config) -> Result<Image, Error> {
+        let region = Region::new(config)?;
+        match config.format {
+            Format::Raw => Ok(Image::Raw(region)),
+            Format::Qcow2 => Ok(Image::Qcow2(qcow2::Image::open(region)?)),
+        }
+    }
+
+    // the number of sectors visible to the guest
+    pub fn sectors(&self) -> u64 {
+        match self {
+            Image::Raw(region) => region.sectors,
+            Image::Qcow2(image) => image.sectors(),
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        match self {
+            Image::Raw(region) => region.is_read_only(),
+            Image::Qcow2(image) => image.is_read_only(),
+        }
+    }
+
+    pub fn read(&mut self, sector: u64, buf: *mut u8, count: usize) -> Result<(), Error> {
+        match self {
+            Image::Raw(region) => region.read(sector, buf, count),
+            Image::Qcow2(image) => image.read(sector, buf, count),
+        }
+    }
+
+    pub fn write(&mut self, sector: u64, buf: *const u8, count: usize) -> Result<(), Error> {
+        match self {
+            Image::Raw(region) => {
+                if region.is_read_only() {
+                    return Err(Error::ReadOnly);
+                }
+                region.write(sector, buf, count)
+            }
+            Image::Qcow2(image) => image.write(sector, buf, count),
+        }
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        match self {
+            Image::Raw(region) => region.flush(),
+            Image::Qcow2(image) => image.flush(),
+        }
+    }
+}
+
+// Disk
+/////
+
+pub struct Disk {
+    image: Image,
+    overlay: Option<overlay::Overlay>,
+}
+
+impl Disk {
+    pub fn new(config: &Config) -> Result<Disk, Error> {
+        Ok(Disk {
+            image: Image::open(config)?,
+            overlay: if config.copy_on_write {
+                Some(overlay::Overlay::new())
+            } else {
+                None
+            },
+        })
+    }
+
+    // the number of sectors visible to the guest
+    pub fn capacity(&self) -> u64 {
+        self.image.sectors()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        match self.overlay {
+            Some(_) => false,
+            None => self.image.is_read_only(),
+        }
+    }
+
+    pub fn read(&mut self, sector: u64, buf: *mut u8, count: usize) -> Result<(), Error> {
+        match &self.overlay {
+            Some(overlay) => overlay.read(&mut self.image, sector, buf, count),
+            None => self.image.read(sector, buf, count),
+        }
+    }
+
+    pub fn write(&mut self, sector: u64, buf: *const u8, count: usize) -> Result<(), Error> {
+        match &mut self.overlay {
+            Some(overlay) => overlay.write(&mut self.image, sector, buf, count),
+            None => self.image.write(sector, buf, count),
+        }
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        match self.overlay {
+            // the overlay lives in RAM; there is nothing to persist
+            Some(_) => Ok(()),
+            None => self.image.flush(),
+        }
+    }
+
+    // the copy-on-write overlay, if any
+    pub fn overlay(&mut self) -> Option<&mut overlay::Overlay> {
+        self.overlay.as_mut()
+    }
+
+    // This function must be called when the guest is reset.
+    // It drops all the writes kept in the overlay, so the guest sees the original image again.
+    pub fn reset(&mut self) {
+        if let Some(overlay) = &mut self.overlay {
+            overlay.discard();
+        }
+    }
+}
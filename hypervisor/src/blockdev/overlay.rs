@@ -0,0 +1,233 @@
+// Copy-on-write overlay
+/////
+// The overlay keeps the written sectors of a guest disk in hypervisor pages.
+// Each page holds 8 consecutive sectors (a "chunk"), and chunks are looked up by a sparse
+// 3-level radix tree whose nodes are pages of 512 entries, like the page tables.
+// A chunk is filled with the original contents of the lower disk when it is first written,
+// so a chunk in the overlay always has the up-to-date data of all of its sectors.
+
+use super::{Error, Image};
+use crate::memlayout::PAGE_SIZE;
+use crate::paging;
+use crate::virtio::SECTOR_SIZE;
+
+const SECTORS_PER_CHUNK: u64 = (PAGE_SIZE as u64) / (SECTOR_SIZE as u64);
+const LEVELS: usize = 3;
+
+pub struct Overlay {
+    root: Option<paging::Page>,
+    chunks: usize,
+}
+
+fn to_indices(chunk: u64) -> [usize; LEVELS] {
+    [
+        ((chunk >> 18) & 0x1ff) as usize,
+        ((chunk >> 9) & 0x1ff) as usize,
+        (chunk & 0x1ff) as usize,
+    ]
+}
+
+impl Overlay {
+    pub fn new() -> Overlay {
+        Overlay {
+            root: None,
+            chunks: 0,
+        }
+    }
+
+    // the number of pages used to keep the written data
+    pub fn chunks(&self) -> usize {
+        self.chunks
+    }
+
+    fn lookup(&self, chunk: u64) -> Option<*mut u8> {
+        let mut node = match &self.root {
+            Some(root) => root.address().to_usize() as *mut usize,
+            None => return None,
+        };
+        for index in to_indices(chunk).iter() {
+            let entry = unsafe { node.add(*index).read() };
+            if entry == 0 {
+                return None;
+            }
+            node = entry as *mut usize;
+        }
+        Some(node as *mut u8)
+    }
+
+    // This function returns the page for `chunk` and whether the page was newly allocated.
+    fn lookup_or_insert(&mut self, chunk: u64) -> Result<(*mut u8, bool), Error> {
+        if self.root.is_none() {
+            self.root = Some(paging::alloc().map_err(|_| Error::OutOfMemory)?);
+        }
+        let mut node = self.root.as_ref().unwrap().address().to_usize() as *mut usize;
+        let mut created = false;
+        for index in to_indices(chunk).iter() {
+            let mut entry = unsafe { node.add(*index).read() };
+            if entry == 0 {
+                entry = paging::alloc()
+                    .map_err(|_| Error::OutOfMemory)?
+                    .address()
+                    .to_usize();
+                unsafe { node.add(*index).write(entry) };
+                created = true;
+            }
+            node = entry as *mut usize;
+        }
+        if created {
+            self.chunks += 1;
+        }
+        Ok((node as *mut u8, created))
+    }
+
+    pub fn read(
+        &self,
+        lower: &mut Image,
+        sector: u64,
+        buf: *mut u8,
+        count: usize,
+    ) -> Result<(), Error> {
+        match sector.checked_add(count as u64) {
+            Some(end) if end <= lower.sectors() => {}
+            _ => return Err(Error::OutOfRange),
+        }
+
+        // consecutive sectors missing in the overlay are read from the lower disk at once
+        let mut pending_start = sector;
+        let mut pending_count = 0;
+        let mut done = 0;
+        while done < count {
+            let current = sector + done as u64;
+            let chunk = current / SECTORS_PER_CHUNK;
+            let within = current % SECTORS_PER_CHUNK;
+            let n = core::cmp::min((SECTORS_PER_CHUNK - within) as usize, count - done);
+
+            match self.lookup(chunk) {
+                Some(page) => {
+                    if pending_count > 0 {
+                        let dest =
+                            unsafe { buf.add((pending_start - sector) as usize * SECTOR_SIZE) };
+                        lower.read(pending_start, dest, pending_count)?;
+                        pending_count = 0;
+                    }
+                    unsafe {
+                        core::ptr::copy(
+                            page.add(within as usize * SECTOR_SIZE),
+                            buf.add(done * SECTOR_SIZE),
+                            n * SECTOR_SIZE,
+                        );
+                    }
+                }
+                None => {
+                    if pending_count == 0 {
+                        pending_start = current;
+                    }
+                    pending_count += n;
+                }
+            }
+            done += n;
+        }
+        if pending_count > 0 {
+            let dest = unsafe { buf.add((pending_start - sector) as usize * SECTOR_SIZE) };
+            lower.read(pending_start, dest, pending_count)?;
+        }
+        Ok(())
+    }
+
+    pub fn write(
+        &mut self,
+        lower: &mut Image,
+        sector: u64,
+        buf: *const u8,
+        count: usize,
+    ) -> Result<(), Error> {
+        match sector.checked_add(count as u64) {
+            Some(end) if end <= lower.sectors() => {}
+            _ => return Err(Error::OutOfRange),
+        }
+
+        let mut done = 0;
+        while done < count {
+            let current = sector + done as u64;
+            let chunk = current / SECTORS_PER_CHUNK;
+            let within = current % SECTORS_PER_CHUNK;
+            let n = core::cmp::min((SECTORS_PER_CHUNK - within) as usize, count - done);
+
+            let (page, created) = self.lookup_or_insert(chunk)?;
+            if created && (n as u64) < SECTORS_PER_CHUNK {
+                // copy up the original contents (the last chunk may be shorter than a page)
+                let first = chunk * SECTORS_PER_CHUNK;
+                let len = core::cmp::min(SECTORS_PER_CHUNK, lower.sectors() - first);
+                lower.read(first, page, len as usize)?;
+            }
+            unsafe {
+                core::ptr::copy(
+                    buf.add(done * SECTOR_SIZE),
+                    page.add(within as usize * SECTOR_SIZE),
+                    n * SECTOR_SIZE,
+                );
+            }
+            done += n;
+        }
+        Ok(())
+    }
+
+    // This function calls `f` with the number and the page of each chunk in the overlay.
+    pub fn for_each_chunk<F: FnMut(u64, *const u8)>(&self, mut f: F) {
+        if let Some(root) = &self.root {
+            walk_node(root.address().to_usize(), 0, 0, &mut f);
+        }
+    }
+
+    // This function replaces `chunk` with the page at `data` (e.g. restored from a snapshot).
+    pub fn put_chunk(&mut self, chunk: u64, data: *const u8) -> Result<(), Error> {
+        let (page, _) = self.lookup_or_insert(chunk)?;
+        unsafe {
+            core::ptr::copy(data, page, PAGE_SIZE as usize);
+        }
+        Ok(())
+    }
+
+    // This function forgets all the written data, and gives back the pages.
+    pub fn discard(&mut self) {
+        log::debug!("discarding {} chunks in the overlay", self.chunks);
+        if let Some(root) = self.root.take() {
+            free_node(root.address().to_usize(), 0);
+        }
+        self.chunks = 0;
+    }
+}
+
+// This function calls `f` with the chunks under the node at `addr` in the `level` of the tree.
+// `prefix` is the part of the chunk number given by the upper levels.
+fn walk_node<F: FnMut(u64, *const u8)>(addr: usize, level: usize, prefix: u64, f: &mut F) {
+    let node = addr as *const usize;
+    for i in 0..512 {
+        let entry = unsafe { node.add(i).read() };
+        if entry == 0 {
+            continue;
+        }
+        let chunk = (prefix << 9) | i as u64;
+        if level + 1 < LEVELS {
+            walk_node(entry, level + 1, chunk, f);
+        } else {
+            f(chunk, entry as *const u8);
+        }
+    }
+}
+
+// This function frees the node at `addr` in the `level` of the tree and all of its descendants.
+fn free_node(addr: usize, level: usize) {
+    if level < LEVELS {
+        let node = addr as *const usize;
+        for i in 0..512 {
+            let entry = unsafe { node.add(i).read() };
+            if entry != 0 {
+                free_node(entry, level + 1);
+            }
+        }
+    }
+    paging::free(paging::Page::from_address(paging::PhysicalAddress::new(
+        addr,
+    )));
+}
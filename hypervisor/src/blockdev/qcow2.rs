@@ -0,0 +1,437 @@
+// qcow2 images
+/////
+// This module reads and writes qcow2 images (version 2 and 3) stored in a region of the host disk.
+// Supported: L1/L2 tables, cluster allocation on write and refcount updates (8 to 64-bit refcounts).
+// Not supported: backing files, encryption, compression, external data files, extended L2 entries,
+// and writes to images with internal snapshots (they are opened read-only).
+// NOTE: all the on-disk values are big-endian.
+
+use super::{Error, Region};
+use crate::memlayout::PAGE_SIZE;
+use crate::paging;
+use crate::virtio::SECTOR_SIZE;
+
+const QCOW2_MAGIC: u32 = 0x5146_49fb; // "QFI\xfb"
+
+// header offsets
+const HDR_MAGIC: usize = 0;
+const HDR_VERSION: usize = 4;
+const HDR_BACKING_FILE_OFFSET: usize = 8;
+const HDR_CLUSTER_BITS: usize = 20;
+const HDR_SIZE: usize = 24;
+const HDR_CRYPT_METHOD: usize = 32;
+const HDR_L1_SIZE: usize = 36;
+const HDR_L1_TABLE_OFFSET: usize = 40;
+const HDR_REFCOUNT_TABLE_OFFSET: usize = 48;
+const HDR_REFCOUNT_TABLE_CLUSTERS: usize = 56;
+const HDR_NB_SNAPSHOTS: usize = 60;
+const HDR_INCOMPATIBLE_FEATURES: usize = 72;
+const HDR_AUTOCLEAR_FEATURES: usize = 88;
+const HDR_REFCOUNT_ORDER: usize = 96;
+
+// entries of L1/L2/refcount tables
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const FLAG_COPIED: u64 = 1 << 63;
+const FLAG_COMPRESSED: u64 = 1 << 62;
+const FLAG_ZERO: u64 = 1 << 0;
+
+fn be(buf: &[u8], off: usize, width: usize) -> u64 {
+    let mut v: u64 = 0;
+    for i in 0..width {
+        v = (v << 8) | buf[off + i] as u64;
+    }
+    v
+}
+
+fn set_be(buf: &mut [u8], off: usize, width: usize, v: u64) {
+    for i in 0..width {
+        buf[off + i] = (v >> (8 * (width - 1 - i))) as u8;
+    }
+}
+
+pub struct Image {
+    region: Region,
+    writable: bool,
+
+    cluster_bits: u32,
+    size: u64,
+    l1_size: u64,
+    l1_table_offset: u64,
+    refcount_table_offset: u64,
+    refcount_table_entries: u64,
+    refcount_bytes: usize,
+
+    // the first cluster which might be free
+    free_hint: u64,
+
+    // a cache of a single metadata sector
+    meta: [u8; SECTOR_SIZE],
+    meta_sector: Option<u64>,
+
+    // a buffer to fill a newly allocated cluster
+    scratch: *mut u8,
+}
+
+impl Image {
+    pub fn open(region: Region) -> Result<Image, Error> {
+        let mut header = [0u8; SECTOR_SIZE];
+        region.read(0, header.as_mut_ptr(), 1)?;
+
+        if be(&header, HDR_MAGIC, 4) as u32 != QCOW2_MAGIC {
+            return Err(Error::InvalidImage);
+        }
+        let version = be(&header, HDR_VERSION, 4);
+        if version != 2 && version != 3 {
+            return Err(Error::Unsupported);
+        }
+        if be(&header, HDR_BACKING_FILE_OFFSET, 8) != 0 {
+            log::info!("qcow2: backing files are not supported");
+            return Err(Error::Unsupported);
+        }
+        if be(&header, HDR_CRYPT_METHOD, 4) != 0 {
+            log::info!("qcow2: encrypted images are not supported");
+            return Err(Error::Unsupported);
+        }
+
+        let cluster_bits = be(&header, HDR_CLUSTER_BITS, 4) as u32;
+        if cluster_bits < 9 || cluster_bits > 21 {
+            return Err(Error::InvalidImage);
+        }
+
+        let (refcount_order, autoclear) = if version == 3 {
+            if be(&header, HDR_INCOMPATIBLE_FEATURES, 8) != 0 {
+                log::info!("qcow2: the image has unknown incompatible features (or is dirty)");
+                return Err(Error::Unsupported);
+            }
+            (
+                be(&header, HDR_REFCOUNT_ORDER, 4),
+                be(&header, HDR_AUTOCLEAR_FEATURES, 8),
+            )
+        } else {
+            (4, 0)
+        };
+        // TODO (enhancement): support sub-byte refcounts
+        if refcount_order < 3 || refcount_order > 6 {
+            log::info!("qcow2: refcount_order={} is not supported", refcount_order);
+            return Err(Error::Unsupported);
+        }
+
+        let cluster_size = 1u64 << cluster_bits;
+        let snapshots = be(&header, HDR_NB_SNAPSHOTS, 4);
+        if snapshots > 0 {
+            log::info!("qcow2: the image has internal snapshots; opened as read-only");
+        }
+
+        let scratch_pages = core::cmp::max(cluster_size as usize / PAGE_SIZE as usize, 1);
+        let scratch = paging::alloc_continuous(scratch_pages)
+            .map_err(|_| Error::OutOfMemory)?
+            .address()
+            .to_usize() as *mut u8;
+
+        let mut image = Image {
+            region: region,
+            writable: !region.is_read_only() && snapshots == 0,
+            cluster_bits: cluster_bits,
+            size: be(&header, HDR_SIZE, 8),
+            l1_size: be(&header, HDR_L1_SIZE, 4),
+            l1_table_offset: be(&header, HDR_L1_TABLE_OFFSET, 8),
+            refcount_table_offset: be(&header, HDR_REFCOUNT_TABLE_OFFSET, 8),
+            refcount_table_entries: be(&header, HDR_REFCOUNT_TABLE_CLUSTERS, 4) * cluster_size / 8,
+            refcount_bytes: 1 << (refcount_order - 3),
+            free_hint: 0,
+            meta: [0; SECTOR_SIZE],
+            meta_sector: None,
+            scratch: scratch,
+        };
+
+        // NOTE (from the qcow2 specification):
+        // a program which does not understand an autoclear feature must clear it when it writes the image.
+        if image.writable && autoclear != 0 {
+            image.write_be(HDR_AUTOCLEAR_FEATURES as u64, 8, 0)?;
+        }
+
+        log::info!(
+            "qcow2: version={}, size={} bytes, cluster={} bytes, refcount={} bits",
+            version,
+            image.size,
+            cluster_size,
+            image.refcount_bytes * 8
+        );
+        Ok(image)
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    // the number of sectors visible to the guest
+    pub fn sectors(&self) -> u64 {
+        self.size / SECTOR_SIZE as u64
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        !self.writable
+    }
+
+    // metadata accessors
+    /////
+
+    fn load_meta(&mut self, sector: u64) -> Result<(), Error> {
+        if self.meta_sector != Some(sector) {
+            self.meta_sector = None;
+            self.region.read(sector, self.meta.as_mut_ptr(), 1)?;
+            self.meta_sector = Some(sector);
+        }
+        Ok(())
+    }
+
+    // This function reads a `width`-byte big-endian value at the byte offset `off` in the image.
+    fn read_be(&mut self, off: u64, width: usize) -> Result<u64, Error> {
+        self.load_meta(off / SECTOR_SIZE as u64)?;
+        Ok(be(&self.meta, (off % SECTOR_SIZE as u64) as usize, width))
+    }
+
+    fn write_be(&mut self, off: u64, width: usize, v: u64) -> Result<(), Error> {
+        let sector = off / SECTOR_SIZE as u64;
+        self.load_meta(sector)?;
+        set_be(
+            &mut self.meta,
+            (off % SECTOR_SIZE as u64) as usize,
+            width,
+            v,
+        );
+        self.region.write(sector, self.meta.as_ptr(), 1)
+    }
+
+    fn check_cluster(&self, offset: u64) -> Result<u64, Error> {
+        if offset % self.cluster_size() != 0
+            || offset + self.cluster_size() > self.region.sectors * SECTOR_SIZE as u64
+        {
+            return Err(Error::InvalidImage);
+        }
+        Ok(offset)
+    }
+
+    // refcounts
+    /////
+
+    fn refcounts_per_block(&self) -> u64 {
+        self.cluster_size() / self.refcount_bytes as u64
+    }
+
+    // This function returns the offset of the refcount block covering `cluster` (or 0 if missing).
+    fn refcount_block(&mut self, cluster: u64) -> Result<u64, Error> {
+        let index = cluster / self.refcounts_per_block();
+        if index >= self.refcount_table_entries {
+            return Ok(0);
+        }
+        let entry = self.read_be(self.refcount_table_offset + index * 8, 8)?;
+        Ok(entry & OFFSET_MASK)
+    }
+
+    fn get_refcount(&mut self, cluster: u64) -> Result<u64, Error> {
+        let block = self.refcount_block(cluster)?;
+        if block == 0 {
+            return Ok(0);
+        }
+        let index = cluster % self.refcounts_per_block();
+        let width = self.refcount_bytes;
+        self.read_be(block + index * width as u64, width)
+    }
+
+    fn set_refcount(&mut self, block: u64, cluster: u64, v: u64) -> Result<(), Error> {
+        let index = cluster % self.refcounts_per_block();
+        let width = self.refcount_bytes;
+        self.write_be(block + index * width as u64, width, v)
+    }
+
+    // cluster allocation
+    /////
+
+    fn zero_cluster(&mut self, offset: u64) -> Result<(), Error> {
+        let size = self.cluster_size() as usize;
+        unsafe { core::ptr::write_bytes(self.scratch, 0, size) };
+        self.meta_sector = None;
+        self.region.write(
+            offset / SECTOR_SIZE as u64,
+            self.scratch,
+            size / SECTOR_SIZE,
+        )
+    }
+
+    // This function allocates a cluster, sets its refcount to 1 and returns its offset in the image.
+    fn alloc_cluster(&mut self) -> Result<u64, Error> {
+        let limit = self.region.sectors * SECTOR_SIZE as u64 / self.cluster_size();
+        let mut cluster = self.free_hint;
+        loop {
+            if cluster >= limit {
+                return Err(Error::NoSpace);
+            }
+            if self.get_refcount(cluster)? != 0 {
+                cluster += 1;
+                continue;
+            }
+
+            let block = self.refcount_block(cluster)?;
+            if block != 0 {
+                self.set_refcount(block, cluster, 1)?;
+                self.free_hint = cluster + 1;
+                return Ok(cluster << self.cluster_bits);
+            }
+
+            // no refcount block covers `cluster` yet.
+            // the free cluster itself becomes the new refcount block, which counts itself.
+            let index = cluster / self.refcounts_per_block();
+            if index >= self.refcount_table_entries {
+                // TODO (enhancement): grow the refcount table
+                log::info!("qcow2: the refcount table is full");
+                return Err(Error::NoSpace);
+            }
+            let block = cluster << self.cluster_bits;
+            self.zero_cluster(block)?;
+            self.set_refcount(block, cluster, 1)?;
+            self.write_be(self.refcount_table_offset + index * 8, 8, block)?;
+            cluster += 1;
+        }
+    }
+
+    // address translation
+    /////
+
+    // This function returns the L2 entry for the guest byte offset `off`.
+    // If `allocate` is true, a missing L2 table is allocated.
+    // The returned value is a pair of the offset of the entry and the entry itself.
+    fn l2_entry(&mut self, off: u64, allocate: bool) -> Result<Option<(u64, u64)>, Error> {
+        let l2_bits = self.cluster_bits - 3;
+        let l1_index = off >> (self.cluster_bits + l2_bits);
+        let l2_index = (off >> self.cluster_bits) & ((1 << l2_bits) - 1);
+        if l1_index >= self.l1_size {
+            return Err(Error::OutOfRange);
+        }
+
+        let l1_entry_offset = self.l1_table_offset + l1_index * 8;
+        let l1_entry = self.read_be(l1_entry_offset, 8)?;
+        let mut l2_table = l1_entry & OFFSET_MASK;
+        if l2_table == 0 {
+            if !allocate {
+                return Ok(None);
+            }
+            l2_table = self.alloc_cluster()?;
+            self.zero_cluster(l2_table)?;
+            self.write_be(l1_entry_offset, 8, l2_table | FLAG_COPIED)?;
+        } else {
+            self.check_cluster(l2_table)?;
+            if allocate && l1_entry & FLAG_COPIED == 0 {
+                // the table is shared with a snapshot
+                return Err(Error::Unsupported);
+            }
+        }
+
+        let l2_entry_offset = l2_table + l2_index * 8;
+        let l2_entry = self.read_be(l2_entry_offset, 8)?;
+        Ok(Some((l2_entry_offset, l2_entry)))
+    }
+
+    // read & write
+    /////
+
+    pub fn read(&mut self, sector: u64, buf: *mut u8, count: usize) -> Result<(), Error> {
+        match sector.checked_add(count as u64) {
+            Some(end) if end <= self.sectors() => {}
+            _ => return Err(Error::OutOfRange),
+        }
+
+        let sectors_per_cluster = self.cluster_size() / SECTOR_SIZE as u64;
+        let mut done = 0;
+        while done < count {
+            let current = sector + done as u64;
+            let within = current % sectors_per_cluster;
+            let n = core::cmp::min((sectors_per_cluster - within) as usize, count - done);
+            let dest = unsafe { buf.add(done * SECTOR_SIZE) };
+
+            let entry = match self.l2_entry(current * SECTOR_SIZE as u64, false)? {
+                Some((_, entry)) => entry,
+                None => 0,
+            };
+            if entry & FLAG_COMPRESSED != 0 {
+                log::info!("qcow2: compressed clusters are not supported");
+                return Err(Error::Unsupported);
+            }
+            let host = entry & OFFSET_MASK;
+            if host == 0 || entry & FLAG_ZERO != 0 {
+                // unallocated clusters read as zeros since there is no backing file
+                unsafe { core::ptr::write_bytes(dest, 0, n * SECTOR_SIZE) };
+            } else {
+                self.check_cluster(host)?;
+                self.region
+                    .read(host / SECTOR_SIZE as u64 + within, dest, n)?;
+            }
+            done += n;
+        }
+        Ok(())
+    }
+
+    pub fn write(&mut self, sector: u64, buf: *const u8, count: usize) -> Result<(), Error> {
+        if !self.writable {
+            return Err(Error::ReadOnly);
+        }
+        match sector.checked_add(count as u64) {
+            Some(end) if end <= self.sectors() => {}
+            _ => return Err(Error::OutOfRange),
+        }
+
+        let sectors_per_cluster = self.cluster_size() / SECTOR_SIZE as u64;
+        let mut done = 0;
+        while done < count {
+            let current = sector + done as u64;
+            let within = current % sectors_per_cluster;
+            let n = core::cmp::min((sectors_per_cluster - within) as usize, count - done);
+            let src = unsafe { buf.add(done * SECTOR_SIZE) };
+
+            let (entry_offset, entry) = match self.l2_entry(current * SECTOR_SIZE as u64, true)? {
+                Some(v) => v,
+                None => return Err(Error::InvalidImage),
+            };
+            if entry & FLAG_COMPRESSED != 0 {
+                return Err(Error::Unsupported);
+            }
+
+            let host = entry & OFFSET_MASK;
+            if host != 0 && entry & FLAG_COPIED != 0 && entry & FLAG_ZERO == 0 {
+                // the cluster is owned only by us; overwrite it in place
+                self.check_cluster(host)?;
+                self.region
+                    .write(host / SECTOR_SIZE as u64 + within, src, n)?;
+            } else if host != 0 && entry & FLAG_COPIED == 0 {
+                // the cluster is shared with a snapshot
+                return Err(Error::Unsupported);
+            } else {
+                // the cluster is unallocated or reads as zeros.
+                // build the whole cluster (zeros + new data) and write it at once.
+                let host = if host != 0 {
+                    self.check_cluster(host)?
+                } else {
+                    self.alloc_cluster()?
+                };
+                let size = self.cluster_size() as usize;
+                unsafe {
+                    core::ptr::write_bytes(self.scratch, 0, size);
+                    core::ptr::copy(
+                        src,
+                        self.scratch.add(within as usize * SECTOR_SIZE),
+                        n * SECTOR_SIZE,
+                    );
+                }
+                self.region
+                    .write(host / SECTOR_SIZE as u64, self.scratch, size / SECTOR_SIZE)?;
+                self.write_be(entry_offset, 8, host | FLAG_COPIED)?;
+            }
+            done += n;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.region.flush()
+    }
+}
@@ -0,0 +1,53 @@
+// Configuration of guests
+/////
+// TODO (enhancement): load this from somewhere (e.g. the disk or the device tree) at runtime
+
+use crate::blockdev;
+use crate::guest;
+use crate::paging::PageTableEntryFlag;
+
+pub static GUESTS: [guest::Config; 1] = [guest::Config {
+    name: "guest01",
+    disk: blockdev::Config {
+        start_sector: 0,
+        sectors: None,
+        format: blockdev::Format::Raw,
+        copy_on_write: true,
+    },
+    // NOTE: these values should be page-aligned.
+    dram_start: 0x8000_0000,
+    dram_size: 0x0200_0000,
+    // e.g. another bank of RAM and ROM loaded from the disk:
+    // guest::Region { gpa: 0x1_0000_0000, size: 0x0100_0000, kind: guest::RegionKind::Ram },
+    // guest::Region { gpa: 0x2000_0000, size: 0x10_0000, kind: guest::RegionKind::Rom { sector: 0x8000 } },
+    // NOTE: the regions must not overlap the RAM or the emulated devices; see `guest::check_layout`.
+    regions: &[
+        // the UART of the host (`memlayout::UART_BASE`) at the same address
+        guest::Region {
+            gpa: 0x1000_0000,
+            size: 0x1000,
+            kind: guest::RegionKind::Mmio {
+                hpa: 0x1000_0000,
+                perm: (PageTableEntryFlag::Read as u16) | (PageTableEntryFlag::Write as u16),
+            },
+        },
+    ],
+    // e.g. Some(guest::Passthrough { slot: 1, gpa: 0x1000_1000, irq: 1 })
+    // NOTE: such a guest needs identity-mapped RAM; see `guest::Passthrough`.
+    passthrough: None,
+    rtc_offset_secs: 0,
+    // e.g. &[crate::vdev::virtio::DeviceType::Block, crate::vdev::virtio::DeviceType::Rng]
+    virtio_pci: &[],
+    // e.g. Some(blockdev::Region { start: 0x10_0000, sectors: 0x2_0000 })
+    // NOTE: the snapshot is taken by the guest through SBI (see `sbi`), and restored at boot.
+    snapshot: None,
+}];
+
+// whether identical pages of guests are merged while guests are idle (see `ksm`)
+pub static SAME_PAGE_MERGING: bool = false;
+
+// whether the host has a Goldfish RTC at `memlayout::RTC_BASE` (QEMU virt machine has one)
+pub static HOST_RTC: bool = true;
+
+// the wall-clock time at boot (seconds since the UNIX epoch) when the host has no RTC
+pub static RTC_EPOCH_SECS: u64 = 1_600_000_000;
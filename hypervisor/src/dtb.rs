@@ -0,0 +1,320 @@
+// Device tree for guests
+/////
+// The hypervisor passes a device tree to each guest in a1, which describes the RAM and
+// the devices visible to the guest (emulated or passed through).
+
+use crate::guest::{Guest, RegionKind};
+use crate::memlayout;
+use crate::mkernel;
+use crate::syscon;
+use crate::util::fdt::Builder;
+use crate::vdev;
+use core::fmt::{Error, Write};
+
+// the space reserved for the device tree at the end of the RAM of a guest
+pub const MAX_SIZE: usize = 0x2000;
+
+// the number of interrupt identities of an IMSIC interrupt file (QEMU virt machine)
+const IMSIC_NUM_IDS: u32 = 255;
+
+static mut BUF: [u8; MAX_SIZE] = [0; MAX_SIZE];
+
+// phandles
+const CPU_INTC: u32 = 1;
+const PLIC: u32 = 2;
+const APLIC: u32 = 5;
+const TEST: u32 = 3;
+const IMSIC: u32 = 4;
+
+// the interrupt type in the specifiers for the APLIC
+const IRQ_TYPE_LEVEL_HIGH: u32 = 4;
+
+// a node name with a unit address (e.g. "memory@80000000")
+struct Name {
+    buf: [u8; 64],
+    len: usize,
+}
+
+impl Write for Name {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        if self.len + s.len() > self.buf.len() {
+            return Err(Error);
+        }
+        self.buf[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+        self.len += s.len();
+        Ok(())
+    }
+}
+
+fn name(node: &str, address: usize) -> Name {
+    let mut n = Name {
+        buf: [0; 64],
+        len: 0,
+    };
+    // NOTE: this never fails as the names are short enough
+    let _ = write!(n, "{}@{:x}", node, address);
+    n
+}
+
+impl Name {
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+// This function adds the interrupt of a device, which is routed to the interrupt controller of `guest`.
+fn interrupt(fdt: &mut Builder, guest: &Guest, irq: u32) {
+    match guest.irqchip {
+        vdev::Irqchip::Plic(_) => {
+            fdt.property_u32("interrupt-parent", PLIC);
+            fdt.property_u32("interrupts", irq);
+        }
+        vdev::Irqchip::Aplic(_) => {
+            fdt.property_u32("interrupt-parent", APLIC);
+            fdt.property_cells("interrupts", &[irq, IRQ_TYPE_LEVEL_HIGH]);
+        }
+    }
+}
+
+// This function adds the routing of INTx of the PCIe devices (swizzled as on QEMU virt machine).
+fn pci_interrupt_map(fdt: &mut Builder, guest: &Guest) {
+    // (child unit address (3 cells), pin, parent phandle, parent specifier (up to 2 cells)) for
+    // 4 devices x 4 pins
+    let mut map = [0u32; 4 * 4 * 7];
+    let mut len = 0;
+    for device in 0..vdev::pci::MAX_FUNCTIONS as u32 {
+        for pin in 1..=4 {
+            let irq = memlayout::GUEST_PCIE_IRQ as u32 + (device + pin - 1) % 4;
+            let entry: &[u32] = match guest.irqchip {
+                vdev::Irqchip::Plic(_) => &[device << 11, 0, 0, pin, PLIC, irq],
+                vdev::Irqchip::Aplic(_) => {
+                    &[device << 11, 0, 0, pin, APLIC, irq, IRQ_TYPE_LEVEL_HIGH]
+                }
+            };
+            map[len..len + entry.len()].copy_from_slice(entry);
+            len += entry.len();
+        }
+    }
+    fdt.property_cells("interrupt-map", &map[..len]);
+    fdt.property_cells("interrupt-map-mask", &[0x1800, 0, 0, 7]);
+}
+
+// This function builds the device tree of `guest`.
+pub fn build(guest: &Guest) -> Result<&'static [u8], Error> {
+    let buf = unsafe { &mut BUF };
+    let mut fdt = Builder::new(buf);
+
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_str("compatible", "riscv-virtio");
+    fdt.property_str("model", "rvvisor,guest");
+
+    // the serial port is the UART of the host, if it is given to the guest
+    let serial = guest.regions.iter().find_map(|r| match r.kind {
+        RegionKind::Mmio { hpa, .. } if hpa == memlayout::UART_BASE => Some(r.gpa),
+        _ => None,
+    });
+
+    fdt.begin_node("chosen");
+    if let Some(gpa) = serial {
+        fdt.property_str("stdout-path", name("/soc/serial", gpa).as_str());
+    }
+    fdt.end_node();
+
+    // cpus
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", memlayout::TIMEBASE_FREQUENCY as u32);
+    fdt.begin_node("cpu@0");
+    fdt.property_str("device_type", "cpu");
+    fdt.property_u32("reg", 0);
+    fdt.property_str("status", "okay");
+    fdt.property_str("compatible", "riscv");
+    fdt.property_str("mmu-type", "riscv,sv39");
+    if mkernel::has_sstc() {
+        fdt.property_str("riscv,isa", "rv64imafdc_zicsr_zifencei_sstc");
+        fdt.property_strs(
+            "riscv,isa-extensions",
+            &["i", "m", "a", "f", "d", "c", "zicsr", "zifencei", "sstc"],
+        );
+    } else {
+        fdt.property_str("riscv,isa", "rv64imafdc_zicsr_zifencei");
+        fdt.property_strs(
+            "riscv,isa-extensions",
+            &["i", "m", "a", "f", "d", "c", "zicsr", "zifencei"],
+        );
+    }
+    fdt.property_str("riscv,isa-base", "rv64i");
+    fdt.begin_node("interrupt-controller");
+    fdt.property_u32("#interrupt-cells", 1);
+    fdt.property_empty("interrupt-controller");
+    fdt.property_str("compatible", "riscv,cpu-intc");
+    fdt.property_u32("phandle", CPU_INTC);
+    fdt.end_node();
+    fdt.end_node();
+    fdt.end_node();
+
+    // memory (the main RAM and the other banks)
+    for (start, size) in guest.memory().ram_ranges() {
+        fdt.begin_node(name("memory", start).as_str());
+        fdt.property_str("device_type", "memory");
+        fdt.property_reg("reg", start as u64, size as u64);
+        fdt.end_node();
+    }
+
+    // devices
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_str("compatible", "simple-bus");
+    fdt.property_empty("ranges");
+
+    fdt.begin_node(name("test", memlayout::GUEST_TEST_BASE).as_str());
+    fdt.property_strs("compatible", &["sifive,test1", "sifive,test0", "syscon"]);
+    fdt.property_reg(
+        "reg",
+        memlayout::GUEST_TEST_BASE as u64,
+        memlayout::GUEST_TEST_SIZE as u64,
+    );
+    fdt.property_u32("phandle", TEST);
+    fdt.end_node();
+
+    fdt.begin_node("poweroff");
+    fdt.property_str("compatible", "syscon-poweroff");
+    fdt.property_u32("regmap", TEST);
+    fdt.property_u32("offset", 0);
+    fdt.property_u32("value", syscon::FINISHER_PASS);
+    fdt.end_node();
+
+    fdt.begin_node("reboot");
+    fdt.property_str("compatible", "syscon-reboot");
+    fdt.property_u32("regmap", TEST);
+    fdt.property_u32("offset", 0);
+    fdt.property_u32("value", syscon::FINISHER_RESET);
+    fdt.end_node();
+
+    fdt.begin_node(name("rtc", memlayout::GUEST_RTC_BASE).as_str());
+    fdt.property_str("compatible", "google,goldfish-rtc");
+    fdt.property_reg(
+        "reg",
+        memlayout::GUEST_RTC_BASE as u64,
+        memlayout::GUEST_RTC_SIZE as u64,
+    );
+    interrupt(&mut fdt, guest, memlayout::RTC_IRQ as u32);
+    fdt.end_node();
+
+    // NOTE: the interrupts of the UART are not delivered to guests; the driver polls it.
+    if let Some(gpa) = serial {
+        fdt.begin_node(name("serial", gpa).as_str());
+        fdt.property_str("compatible", "ns16550a");
+        fdt.property_reg("reg", gpa as u64, 0x100);
+        fdt.property_u32("clock-frequency", 0x38_4000);
+        fdt.end_node();
+    }
+
+    // ROM is shown as a read-only flash
+    // NOTE: the other windows of host MMIO are not described; the guest must know the devices.
+    for r in guest.regions.iter() {
+        if let RegionKind::Rom { .. } = r.kind {
+            fdt.begin_node(name("rom", r.gpa).as_str());
+            fdt.property_str("compatible", "mtd-rom");
+            fdt.property_reg("reg", r.gpa as u64, r.size as u64);
+            fdt.property_u32("bank-width", 4);
+            fdt.end_node();
+        }
+    }
+
+    if let Some(p) = &guest.passthrough {
+        fdt.begin_node(name("virtio_mmio", p.gpa).as_str());
+        fdt.property_str("compatible", "virtio,mmio");
+        fdt.property_reg("reg", p.gpa as u64, memlayout::VIRTIO_MMIO_SLOT_SIZE as u64);
+        interrupt(&mut fdt, guest, p.irq);
+        fdt.end_node();
+    }
+
+    // the guest interrupt file assigned to the guest (see `aia`)
+    if guest.imsic_file.is_some() {
+        fdt.begin_node(name("imsics", memlayout::GUEST_IMSIC_BASE).as_str());
+        fdt.property_strs("compatible", &["qemu,imsics", "riscv,imsics"]);
+        fdt.property_reg(
+            "reg",
+            memlayout::GUEST_IMSIC_BASE as u64,
+            memlayout::IMSIC_FILE_SIZE as u64,
+        );
+        fdt.property_u32("#interrupt-cells", 0);
+        fdt.property_empty("interrupt-controller");
+        fdt.property_empty("msi-controller");
+        fdt.property_u32("riscv,num-ids", IMSIC_NUM_IDS);
+        fdt.property_cells("interrupts-extended", &[CPU_INTC, 9]);
+        fdt.property_u32("phandle", IMSIC);
+        fdt.end_node();
+    }
+
+    // the emulated PCIe host bridge (see `vdev::pci`)
+    if guest.pci.is_some() {
+        fdt.begin_node(name("pci", memlayout::GUEST_PCIE_ECAM_BASE).as_str());
+        fdt.property_str("compatible", "pci-host-ecam-generic");
+        fdt.property_str("device_type", "pci");
+        fdt.property_u32("#address-cells", 3);
+        fdt.property_u32("#size-cells", 2);
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_cells("bus-range", &[0, 0]);
+        fdt.property_reg(
+            "reg",
+            memlayout::GUEST_PCIE_ECAM_BASE as u64,
+            memlayout::GUEST_PCIE_ECAM_SIZE as u64,
+        );
+        // 32-bit memory space (identity-mapped)
+        let mmio_base = memlayout::GUEST_PCIE_MMIO_BASE as u32;
+        let mmio_size = memlayout::GUEST_PCIE_MMIO_SIZE as u32;
+        fdt.property_cells(
+            "ranges",
+            &[0x0200_0000, 0, mmio_base, 0, mmio_base, 0, mmio_size],
+        );
+        fdt.property_empty("dma-coherent");
+        pci_interrupt_map(&mut fdt, guest);
+        fdt.end_node();
+    }
+
+    match guest.irqchip {
+        // the virtual PLIC serves only the S-mode context (see `vdev::plic`)
+        vdev::Irqchip::Plic(_) => {
+            fdt.begin_node(name("plic", memlayout::GUEST_PLIC_BASE).as_str());
+            fdt.property_strs("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+            fdt.property_reg(
+                "reg",
+                memlayout::GUEST_PLIC_BASE as u64,
+                memlayout::GUEST_PLIC_SIZE as u64,
+            );
+            fdt.property_u32("#address-cells", 0);
+            fdt.property_u32("#interrupt-cells", 1);
+            fdt.property_empty("interrupt-controller");
+            fdt.property_u32("riscv,ndev", vdev::plic::NUM_SOURCES as u32 - 1);
+            fdt.property_cells("interrupts-extended", &[CPU_INTC, 0xffff_ffff, CPU_INTC, 9]);
+            fdt.property_u32("phandle", PLIC);
+        }
+        // the virtual APLIC forwards interrupts as MSIs to the guest interrupt file
+        vdev::Irqchip::Aplic(_) => {
+            fdt.begin_node(name("aplic", memlayout::GUEST_APLIC_BASE).as_str());
+            fdt.property_str("compatible", "riscv,aplic");
+            fdt.property_reg(
+                "reg",
+                memlayout::GUEST_APLIC_BASE as u64,
+                memlayout::GUEST_APLIC_SIZE as u64,
+            );
+            fdt.property_u32("#address-cells", 0);
+            fdt.property_u32("#interrupt-cells", 2);
+            fdt.property_empty("interrupt-controller");
+            fdt.property_u32("riscv,num-sources", vdev::aplic::NUM_SOURCES as u32 - 1);
+            fdt.property_u32("msi-parent", IMSIC);
+            fdt.property_u32("phandle", APLIC);
+        }
+    }
+    fdt.end_node();
+
+    fdt.end_node();
+    let size = fdt.finish()?;
+    Ok(unsafe { &BUF[..size] })
+}
@@ -1,60 +1,698 @@
+use crate::aia;
+use crate::blockdev;
+use crate::dtb;
+use crate::ksm;
 use crate::memlayout;
+use crate::mkernel;
 use crate::paging;
+use crate::plic;
 use crate::riscv;
+use crate::snapshot;
+use crate::vdev;
 use crate::virtio;
+use alloc::vec::Vec;
 use core::fmt::Error;
 use elf_rs::Elf;
 
+pub struct Config {
+    pub name: &'static str,
+    pub disk: blockdev::Config,
+    // the guest physical address range of the main RAM (where the kernel and the device tree are loaded)
+    pub dram_start: usize,
+    pub dram_size: usize,
+    // the other regions of the guest physical address space (e.g. RAM banks, ROM and host MMIO)
+    pub regions: &'static [Region],
+    pub passthrough: Option<Passthrough>,
+    // the initial difference between the wall-clock time of the guest and the host (in seconds)
+    pub rtc_offset_secs: i64,
+    // the devices presented to the guest as virtio-pci functions (up to `vdev::pci::MAX_FUNCTIONS`)
+    // NOTE: a block device serves the disk of the guest.
+    pub virtio_pci: &'static [vdev::virtio::DeviceType],
+    // the region of the host disk where the snapshot of the guest is kept (see `snapshot`)
+    // NOTE: it must not overlap the disk of the guest.
+    pub snapshot: Option<blockdev::Region>,
+}
+
+// A virtio-mmio device of QEMU virt machine which is directly assigned to a guest.
+// NOTE: the device does DMA with host physical addresses, while the guest driver gives it guest
+// physical addresses. Thus the RAM of a guest with a passthrough device is identity-mapped:
+// [dram_start, dram_start + dram_size) must be free host memory, and the guest kernel must be
+// linked to run there.
+#[derive(Copy, Clone)]
+pub struct Passthrough {
+    // the index of the virtio-mmio slot (0 - 7)
+    pub slot: usize,
+    // the guest physical address where the MMIO page of the device is mapped
+    pub gpa: usize,
+    // the interrupt source number in the virtual PLIC of the guest
+    pub irq: u32,
+}
+
+impl Passthrough {
+    pub fn host_irq(&self) -> u32 {
+        virtio::slot_irq(self.slot)
+    }
+}
+
+// A region of the guest physical address space besides the main RAM.
+// NOTE: `gpa`, `size` (and `hpa`) must be page-aligned. The regions must not overlap each other,
+// the main RAM or the emulated devices (see `check_layout`).
+#[derive(Copy, Clone)]
+pub struct Region {
+    pub gpa: usize,
+    pub size: usize,
+    pub kind: RegionKind,
+}
+
+#[derive(Copy, Clone)]
+pub enum RegionKind {
+    // another bank of RAM
+    Ram,
+    // read-only memory, which is loaded from the disk of the guest starting at `sector`
+    Rom { sector: u64 },
+    // a window of host MMIO at `hpa`, which the guest accesses directly with `perm` (R/W/X)
+    // NOTE: the host UART (`memlayout::UART_BASE`) is shown to the guest as its serial port.
+    Mmio { hpa: usize, perm: u16 },
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum State {
+    Running,
+    // the guest requested a reboot
+    Rebooting,
+    // the guest was powered off with the exit code
+    Stopped(u32),
+}
+
 pub struct Guest {
     pub name: &'static str,
     pub hgatp: riscv::csr::hgatp::Setting,
+    // the G-stage translation mode (see `paging::gstage_mode_for`)
+    pub gpat_mode: paging::Mode,
     pub sepc: usize,
+    pub state: State,
+    // the guest physical address of the device tree
+    pub dtb: usize,
+    // the deadline of the timer of the vCPU (vstimecmp, or the value given by SBI set_timer without Sstc)
+    pub timer: u64,
+    // the time of the guest minus the time of the host (htimedelta)
+    pub time_delta: u64,
+    pub disk: blockdev::Disk,
+    pub dram_start: usize,
+    pub dram_size: usize,
+    pub regions: &'static [Region],
+    pub passthrough: Option<Passthrough>,
+    pub irqchip: vdev::Irqchip,
+    // the guest interrupt file of the IMSIC assigned to the vCPU (with AIA)
+    pub imsic_file: Option<usize>,
+    pub rtc: vdev::rtc::Rtc,
+    // the emulated PCIe host bridge (only if the guest has virtio-pci devices)
+    pub pci: Option<vdev::pci::Bridge>,
+    // the bitmap of the pages of RAM written since the last reset (only while it is tracked)
+    dirty_log: Option<Vec<u64>>,
+    pub snapshot: Option<blockdev::Region>,
+    // the vCPU to start from instead of the reset state (restored from the snapshot)
+    pub resume_vcpu: Option<snapshot::Vcpu>,
     // TODO: other CSRs & registers
 }
 
 impl Guest {
-    pub fn new(name: &'static str) -> Guest {
+    pub fn new(config: &Config) -> Guest {
+        // guest interrupt file
+        let imsic_file = if aia::is_available() {
+            let file = aia::alloc_guest_file();
+            if file.is_none() {
+                log::info!("-> no guest interrupt file is left; MSIs are not available");
+            }
+            file
+        } else {
+            None
+        };
+
         // hgatp
-        let root_pt = prepare_gpat_pt().unwrap();
+        let root_pt = match prepare_gpat_pt(config, imsic_file) {
+            Ok(pt) => pt,
+            Err(e) => panic!("failed to prepare memory for {}: {:?}", config.name, e),
+        };
+        let vmid = paging::alloc_vmid();
+        log::info!("-> G-stage translation: {:?} (VMID {})", root_pt.mode, vmid);
+        for m in root_pt.mappings() {
+            log::debug!("   {}", m);
+        }
         let hgatp = riscv::csr::hgatp::Setting::new(
-            riscv::csr::hgatp::Mode::Sv39x4,
-            0,
+            root_pt.mode.hgatp_mode(),
+            vmid,
             root_pt.page.address().to_ppn(),
         );
 
-        Guest {
-            name: name,
+        // disk
+        let disk = match blockdev::Disk::new(&config.disk) {
+            Ok(d) => d,
+            Err(e) => panic!("failed to prepare a disk for {}: {:?}", config.name, e),
+        };
+
+        // passthrough device
+        if let Some(p) = &config.passthrough {
+            log::info!(
+                "-> virtio slot {} is passed through at 0x{:016x} (irq {} -> {})",
+                p.slot,
+                p.gpa,
+                p.host_irq(),
+                p.irq
+            );
+            plic::set_priority(p.host_irq(), 1);
+            plic::enable(p.host_irq());
+        }
+
+        // virtio-pci devices
+        let pci = if config.virtio_pci.is_empty() {
+            None
+        } else {
+            log::info!("-> virtio-pci devices: {:?}", config.virtio_pci);
+            Some(vdev::pci::Bridge::new(config.virtio_pci, &disk))
+        };
+        // the identity-mapped RAM can not be replaced with other pages
+        if config.passthrough.is_some()
+            && config
+                .virtio_pci
+                .contains(&vdev::virtio::DeviceType::Balloon)
+        {
+            panic!(
+                "{}: balloon is not available with a passthrough device",
+                config.name
+            );
+        }
+
+        let mut guest = Guest {
+            name: config.name,
             hgatp: hgatp,
-            sepc: memlayout::GUEST_DRAM_START,
+            gpat_mode: root_pt.mode,
+            sepc: config.dram_start,
+            state: State::Running,
+            dtb: config.dram_start + config.dram_size - dtb::MAX_SIZE,
+            timer: u64::MAX,
+            time_delta: 0,
+            disk: disk,
+            dram_start: config.dram_start,
+            dram_size: config.dram_size,
+            regions: config.regions,
+            passthrough: config.passthrough,
+            irqchip: if imsic_file.is_some() {
+                vdev::Irqchip::Aplic(vdev::aplic::Aplic::new())
+            } else {
+                vdev::Irqchip::Plic(vdev::plic::Plic::new())
+            },
+            imsic_file: imsic_file,
+            rtc: vdev::rtc::Rtc::new(config.rtc_offset_secs),
+            pci: pci,
+            dirty_log: None,
+            snapshot: config.snapshot,
+            resume_vcpu: None,
+        };
+        guest.load_roms();
+        guest
+    }
+
+    // This function handles a load from an emulated device.
+    // It returns `None` if no device is found at `gpa`.
+    pub fn mmio_read(&mut self, gpa: usize, width: usize) -> Option<u64> {
+        if in_range(gpa, self.irqchip.base(), self.irqchip.size()) {
+            let value = self.irqchip.read(gpa - self.irqchip.base());
+            self.complete_interrupt();
+            return Some(value as u64);
+        }
+        if in_range(gpa, memlayout::GUEST_RTC_BASE, memlayout::GUEST_RTC_SIZE) {
+            return Some(self.rtc.read(gpa - memlayout::GUEST_RTC_BASE) as u64);
+        }
+        if in_range(gpa, memlayout::GUEST_TEST_BASE, memlayout::GUEST_TEST_SIZE) {
+            return Some(0);
+        }
+        if let Some(pci) = &mut self.pci {
+            if in_range(
+                gpa,
+                memlayout::GUEST_PCIE_ECAM_BASE,
+                memlayout::GUEST_PCIE_ECAM_SIZE,
+            ) {
+                return Some(pci.read_config(gpa - memlayout::GUEST_PCIE_ECAM_BASE, width));
+            }
+            if in_range(
+                gpa,
+                memlayout::GUEST_PCIE_MMIO_BASE,
+                memlayout::GUEST_PCIE_MMIO_SIZE,
+            ) {
+                // unclaimed addresses read as all ones
+                return Some(pci.read_bar(gpa, width).unwrap_or(u64::MAX));
+            }
+        }
+        None
+    }
+
+    // This function handles a store to an emulated device.
+    // It returns `false` if no device is found at `gpa`.
+    pub fn mmio_write(&mut self, gpa: usize, width: usize, value: u64) -> bool {
+        if in_range(gpa, self.irqchip.base(), self.irqchip.size()) {
+            let offset = gpa - self.irqchip.base();
+            self.irqchip.write(offset, value as u32);
+            self.complete_interrupt();
+            return true;
+        }
+        if in_range(gpa, memlayout::GUEST_RTC_BASE, memlayout::GUEST_RTC_SIZE) {
+            self.rtc
+                .write(gpa - memlayout::GUEST_RTC_BASE, value as u32);
+            return true;
+        }
+        if in_range(gpa, memlayout::GUEST_TEST_BASE, memlayout::GUEST_TEST_SIZE) {
+            match vdev::syscon::write(gpa - memlayout::GUEST_TEST_BASE, value as u32) {
+                Some(vdev::syscon::Request::PowerOff(code)) => self.state = State::Stopped(code),
+                Some(vdev::syscon::Request::Reboot) => self.state = State::Rebooting,
+                None => {}
+            }
+            return true;
+        }
+        let mem = self.memory();
+        if let Some(pci) = &mut self.pci {
+            if in_range(
+                gpa,
+                memlayout::GUEST_PCIE_ECAM_BASE,
+                memlayout::GUEST_PCIE_ECAM_SIZE,
+            ) {
+                pci.write_config(gpa - memlayout::GUEST_PCIE_ECAM_BASE, width, value);
+                return true;
+            }
+            if in_range(
+                gpa,
+                memlayout::GUEST_PCIE_MMIO_BASE,
+                memlayout::GUEST_PCIE_MMIO_SIZE,
+            ) {
+                // writes to unclaimed addresses are ignored
+                pci.write_bar(gpa, width, value, &mem, &mut self.disk);
+                return true;
+            }
+        }
+        false
+    }
+
+    // This function is called after accesses to the interrupt controller, in which the guest
+    // may have finished handling an interrupt.
+    fn complete_interrupt(&mut self) {
+        let irq = match self.irqchip.take_completed() {
+            Some(irq) => irq,
+            None => return,
+        };
+        // the interrupt from a passthrough device was masked in the host PLIC until the guest handles it
+        if let Some(p) = &self.passthrough {
+            if p.irq == irq {
+                self.irqchip.lower(irq);
+                plic::enable(p.host_irq());
+            }
+        }
+    }
+
+    // This function forwards the interrupt `host_irq` if it comes from a device of this guest.
+    // It returns whether the interrupt was consumed.
+    pub fn forward_interrupt(&mut self, host_irq: u32) -> bool {
+        match &self.passthrough {
+            Some(p) if p.host_irq() == host_irq => {
+                // the interrupt is level-triggered. mask it until the guest completes it.
+                plic::disable(host_irq);
+                self.irqchip.raise(p.irq);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // This function updates the interrupt lines of the emulated devices,
+    // and delivers interrupts from the virtual interrupt controller to the guest (hvip or MSIs).
+    // NOTE: this must be called only for the running guest.
+    pub fn update_interrupts(&mut self) {
+        if self.rtc.poll() {
+            self.irqchip.raise(memlayout::RTC_IRQ as u32);
+        } else {
+            self.irqchip.lower(memlayout::RTC_IRQ as u32);
+        }
+        if let Some(pci) = &self.pci {
+            for (i, level) in pci.intx_levels().iter().enumerate() {
+                let irq = memlayout::GUEST_PCIE_IRQ as u32 + i as u32;
+                if *level {
+                    self.irqchip.raise(irq);
+                } else {
+                    self.irqchip.lower(irq);
+                }
+            }
+        }
+
+        // interrupts forwarded as MSIs go to the guest interrupt file
+        if let Some(file) = self.imsic_file {
+            while let Some(eiid) = self.irqchip.next_msi() {
+                aia::send_msi(file, eiid);
+            }
+        }
+
+        let hvip = riscv::csr::hvip::read();
+        if self.irqchip.eip() {
+            riscv::csr::hvip::write(hvip | riscv::csr::hvip::VSEIP);
+        } else {
+            riscv::csr::hvip::write(hvip & !riscv::csr::hvip::VSEIP);
+        }
+    }
+
+    // This function resets the guest to the initial state: RAM and devices are reset,
+    // and the kernel image is loaded again.
+    // NOTE: the vCPU is reset by the hypervisor when it enters the guest.
+    pub fn reboot(&mut self) {
+        // clear RAM
+        let gpat_pt = self.gpat_pt();
+        let page_size = memlayout::PAGE_SIZE as usize;
+        for (start, size) in self.memory().ram_ranges() {
+            for vaddr in (start..start + size).step_by(page_size) {
+                // a shared page is replaced with a private one before it is cleared
+                if let Err(e) = self.memory().unshare(vaddr) {
+                    panic!("failed to unshare RAM at 0x{:016x}: {:?}", vaddr, e);
+                }
+                match gpat_pt.resolve(&paging::VirtualAddress::new(vaddr)) {
+                    Ok(paddr) => paging::Page::from_address(paddr).clear(),
+                    // the page was taken by the balloon. a new (cleared) page is given.
+                    Err(paging::WalkError::NotMapped(_)) => {
+                        if let Err(e) = self.memory().populate(vaddr) {
+                            panic!("failed to give back RAM at 0x{:016x}: {:?}", vaddr, e);
+                        }
+                    }
+                    Err(e) => panic!("RAM at 0x{:016x} is not mapped: {:?}", vaddr, e),
+                }
+            }
+        }
+
+        // reset devices
+        self.irqchip.reset();
+        self.rtc.reset();
+        if let Some(pci) = &mut self.pci {
+            pci.reset();
+        }
+        if let Some(p) = &self.passthrough {
+            virtio::reset_slot(p.slot);
+            plic::enable(p.host_irq());
+        }
+        self.disk.reset();
+        self.timer = u64::MAX;
+        // TODO (enhancement): clear the guest interrupt file (through vsiselect/vsireg)
+
+        self.load_from_disk();
+        self.load_device_tree();
+        self.state = State::Running;
+    }
+
+    // This function sets the timer of the vCPU (SBI set_timer).
+    // NOTE: this must be called only for the running guest.
+    pub fn set_timer(&mut self, time: u64) {
+        self.timer = time;
+        self.restore_timer();
+    }
+
+    // This function saves the timer of the vCPU when the guest is switched out.
+    pub fn save_timer(&mut self) {
+        if mkernel::has_sstc() {
+            self.timer = riscv::csr::vstimecmp::read() as u64;
+        }
+    }
+
+    // This function loads the timer of the vCPU to the hardware.
+    // Without Sstc, the timer interrupt of HS-mode is used and forwarded to the guest (see `hypervisor`).
+    pub fn restore_timer(&self) {
+        if mkernel::has_sstc() {
+            riscv::csr::vstimecmp::write(self.timer as usize);
+        } else {
+            // the pending timer interrupt is withdrawn until the new deadline
+            let hvip = riscv::csr::hvip::read();
+            riscv::csr::hvip::write(hvip & !riscv::csr::hvip::VSTIP);
+            // the deadline is given in the time of the guest
+            let deadline = match self.timer {
+                u64::MAX => u64::MAX,
+                t => t.wrapping_sub(self.time_delta),
+            };
+            mkernel::set_timer(deadline);
+            let sie = riscv::csr::sie::read();
+            riscv::csr::sie::write(sie | riscv::csr::sie::STIE);
+        }
+    }
+
+    // This function writes the device tree of the guest at the end of its RAM.
+    pub fn load_device_tree(&mut self) {
+        match dtb::build(self) {
+            Ok(blob) => {
+                if let Err(e) = self.memory().write(self.dtb, blob) {
+                    panic!("failed to write the device tree of {}: {:?}", self.name, e);
+                }
+                log::info!("-> device tree: 0x{:016x} ({} bytes)", self.dtb, blob.len());
+            }
+            Err(e) => panic!("failed to build a device tree for {}: {:?}", self.name, e),
+        }
+    }
+
+    // This function returns the accessor to the RAM of the guest (e.g. for DMA of emulated devices).
+    pub fn memory(&self) -> Memory {
+        Memory {
+            root: self.hgatp.ppn << 12,
+            mode: self.gpat_mode,
+            vmid: self.hgatp.vmid,
+            start: self.dram_start,
+            size: self.dram_size,
+            regions: self.regions,
+        }
+    }
+
+    // This function sets the amount of RAM which the balloon device should take from the guest.
+    pub fn set_balloon_target(&mut self, size: usize) -> Result<(), Error> {
+        if size > self.dram_size {
+            return Err(Error);
+        }
+        let pages = (size / memlayout::PAGE_SIZE as usize) as u32;
+        let balloon = self
+            .pci
+            .as_mut()
+            .and_then(|pci| pci.balloon())
+            .ok_or(Error)?;
+        log::info!(
+            "{}: balloon: {} -> {} pages",
+            self.name,
+            balloon.balloon_size(),
+            pages
+        );
+        balloon.set_balloon_target(pages);
+        Ok(())
+    }
+
+    // This function starts tracking the pages of RAM written by the guest (or by the hypervisor
+    // on behalf of the guest). The superpages are split, so that each page can be tracked.
+    // Writes are detected with the Dirty bits of the G-stage table, which are cleared here and
+    // set by the hardware or by `handle_dirty_fault`.
+    // On failure, the log is stopped (the superpages split so far are left, which map the same).
+    // NOTE: all banks of RAM are tracked (see `Memory::ram_ranges`).
+    pub fn start_dirty_log(&mut self) -> Result<(), Error> {
+        self.dirty_log = None;
+        let gpat_pt = self.gpat_pt();
+        let superpage = paging::level_size(1);
+        let mut pages = 0;
+        for (start, size) in self.memory().ram_ranges() {
+            let mut addr = start - start % superpage;
+            while addr < start + size {
+                let vaddr = paging::VirtualAddress::new(addr);
+                match gpat_pt.leaf(&vaddr) {
+                    Ok((_, level)) if level > 0 => gpat_pt.split(&vaddr)?,
+                    Ok(_) => {}
+                    // the page was taken by the balloon
+                    Err(paging::WalkError::NotMapped(_)) => {}
+                    Err(_) => return Err(Error),
+                }
+                addr += superpage;
+            }
+            gpat_pt.take_dirty(start, size, |_| {});
+            pages += size / memlayout::PAGE_SIZE as usize;
+        }
+        riscv::instruction::hfence_gvma_vmid(self.hgatp.vmid);
+        self.dirty_log = Some(alloc::vec![0; (pages + 63) / 64]);
+        Ok(())
+    }
+
+    pub fn stop_dirty_log(&mut self) {
+        self.dirty_log = None;
+    }
+
+    // This function returns the bitmap of the pages of RAM written since the last reset
+    // (bit `i` is for the `i`-th page of RAM; see `Memory::ram_page`), or `None` if they are not
+    // tracked.
+    pub fn dirty_log(&mut self) -> Option<&[u64]> {
+        self.sync_dirty_log();
+        self.dirty_log.as_deref()
+    }
+
+    pub fn reset_dirty_log(&mut self) {
+        self.sync_dirty_log();
+        if let Some(log) = &mut self.dirty_log {
+            for word in log.iter_mut() {
+                *word = 0;
+            }
+        }
+    }
+
+    // This function moves the Dirty bits of the G-stage table into the bitmap.
+    fn sync_dirty_log(&mut self) {
+        let gpat_pt = self.gpat_pt();
+        let mem = self.memory();
+        if let Some(log) = &mut self.dirty_log {
+            let mut base = 0;
+            for (start, size) in mem.ram_ranges() {
+                gpat_pt.take_dirty(start, size, |addr| {
+                    let page = base + (addr - start) / memlayout::PAGE_SIZE as usize;
+                    log[page / 64] |= 1 << (page % 64);
+                });
+                base += size / memlayout::PAGE_SIZE as usize;
+            }
+            riscv::instruction::hfence_gvma_vmid(self.hgatp.vmid);
+        }
+    }
+
+    // This function handles a store guest-page fault at `gpa` which is caused by the clear Dirty
+    // bit (without the hardware update of the A/D bits). It returns whether the fault was handled.
+    pub fn handle_dirty_fault(&mut self, gpa: usize) -> bool {
+        let mem = self.memory();
+        if !mem.mark_dirty(gpa) {
+            return false;
+        }
+        if let (Some(log), Some(page)) = (&mut self.dirty_log, mem.ram_page(gpa)) {
+            log[page / 64] |= 1 << (page % 64);
+        }
+        true
+    }
+
+    // This function copies the guest memory at `gpa` into `buf`.
+    pub fn copy_from_guest(&self, gpa: usize, buf: &mut [u8]) -> Result<(), Error> {
+        self.memory().read(gpa, buf)
+    }
+
+    // This function copies `data` into the guest memory at `gpa`.
+    pub fn copy_to_guest(&self, gpa: usize, data: &[u8]) -> Result<(), Error> {
+        self.memory().write(gpa, data)
+    }
+
+    // These functions are the same as above, but take a guest virtual address.
+    // NOTE: see `Memory::translate_virt`.
+    pub fn copy_from_guest_virt(&self, gva: usize, buf: &mut [u8]) -> Result<(), Error> {
+        self.memory().read_virt(gva, buf)
+    }
+
+    pub fn copy_to_guest_virt(&self, gva: usize, data: &[u8]) -> Result<(), Error> {
+        self.memory().write_virt(gva, data)
+    }
+
+    // This function removes the mappings of [gpa, gpa + size) from the guest.
+    pub fn unmap(&mut self, gpa: usize, size: usize) -> Result<(), Error> {
+        let result = self.gpat_pt().unmap(gpa, size);
+        riscv::instruction::hfence_gvma_vmid(self.hgatp.vmid);
+        result
+    }
+
+    // This function changes the permissions of [gpa, gpa + size) for the guest.
+    pub fn protect(&mut self, gpa: usize, size: usize, perm: u16) -> Result<(), Error> {
+        let result = self.gpat_pt().protect(gpa, size, perm);
+        riscv::instruction::hfence_gvma_vmid(self.hgatp.vmid);
+        result
+    }
+
+    // This function releases all the resources of the guest: its memory (cleared), the page
+    // tables, the VMID, the guest interrupt file and the devices.
+    // NOTE: hgatp must not point to the page table of the guest.
+    pub fn destroy(mut self) {
+        log::info!("destroying {}", self.name);
+        if let Some(p) = &self.passthrough {
+            plic::disable(p.host_irq());
+            virtio::reset_slot(p.slot);
+            // the identity-mapped RAM is not owned by the page table
+            for (start, size) in self.memory().ram_ranges() {
+                for addr in (start..start + size).step_by(memlayout::PAGE_SIZE as usize) {
+                    paging::Page::from_address(paging::PhysicalAddress::new(addr)).clear();
+                }
+                paging::release(start, size);
+            }
+        }
+        self.disk.reset();
+        self.gpat_pt().destroy();
+        paging::free_vmid(self.hgatp.vmid);
+        if let Some(file) = self.imsic_file {
+            aia::free_guest_file(file);
+        }
+        log::info!("-> free memory: {:?}", paging::stats());
+    }
+
+    fn gpat_pt(&self) -> paging::PageTable {
+        paging::PageTable::from_page(
+            paging::Page::from_address(paging::PhysicalAddress::new(self.hgatp.ppn << 12)),
+            self.gpat_mode,
+        )
+    }
+
+    // This function loads the ROM regions from the disk.
+    // NOTE: ROM is loaded only once; it is kept across reboots and restores from the snapshot.
+    fn load_roms(&mut self) {
+        let gpat_pt = self.gpat_pt();
+        let page_size = memlayout::PAGE_SIZE as usize;
+        let sectors = page_size / virtio::SECTOR_SIZE;
+        for r in self.regions.iter() {
+            let sector = match r.kind {
+                RegionKind::Rom { sector } => sector,
+                _ => continue,
+            };
+            for offset in (0..r.size).step_by(page_size) {
+                let gpa = r.gpa + offset;
+                let page = match gpat_pt.resolve(&paging::VirtualAddress::new(gpa)) {
+                    Ok(paddr) => paddr.to_usize(),
+                    Err(e) => panic!("ROM at 0x{:016x} is not mapped: {:?}", gpa, e),
+                };
+                let start = sector + (offset / virtio::SECTOR_SIZE) as u64;
+                if let Err(e) = self.disk.read(start, page as *mut u8, sectors) {
+                    panic!(
+                        "failed to load ROM at 0x{:016x} of {}: {:?}",
+                        gpa, self.name, e
+                    );
+                }
+            }
+            log::info!(
+                "-> ROM: 0x{:016x} - 0x{:016x} (from sector {})",
+                r.gpa,
+                r.gpa + r.size,
+                sector
+            );
         }
     }
 
     pub fn load_from_disk(&mut self) {
         let load_size = 1024 * 1024 * 2;
-        let buf_page = paging::alloc_continuous(load_size / memlayout::PAGE_SIZE as usize);
+        let buf_page = match paging::alloc_continuous(load_size / memlayout::PAGE_SIZE as usize) {
+            Ok(p) => p,
+            Err(e) => panic!("failed to allocate a buffer for the guest image: {:?}", e),
+        };
         let buf_addr = buf_page.address().to_usize() as *mut u8;
-        unsafe {
-            let sector_max: u64 = load_size as u64 / virtio::SECTOR_SIZE as u64;
-            if let Some(_queue) = virtio::QUEUE {
-                for sector in 0..sector_max {
-                    (*_queue).read(
-                        sector as u64,
-                        buf_addr.offset(sector as isize * virtio::SECTOR_SIZE as isize)
-                            as *const (),
-                    );
-                    if sector % 10 == 9 {
-                        log::debug!("progress: {} / {}", sector + 1, sector_max)
-                    }
-                }
+        // read the image in chunks so that we can report the progress
+        // NOTE: the image can be smaller than `load_size`
+        let chunk_sectors: u64 = 128;
+        let sector_max: u64 = core::cmp::min(
+            load_size as u64 / virtio::SECTOR_SIZE as u64,
+            self.disk.capacity(),
+        );
+        let mut sector = 0;
+        while sector < sector_max {
+            let count = core::cmp::min(chunk_sectors, sector_max - sector);
+            let dest = unsafe { buf_addr.offset(sector as isize * virtio::SECTOR_SIZE as isize) };
+            if let Err(e) = self.disk.read(sector, dest, count as usize) {
+                panic!("failed to read the guest image: {:?}", e);
             }
-            log::debug!("an ELF was copied into a buffer")
+            sector += count;
+            log::debug!("progress: {} / {}", sector, sector_max)
         }
+        log::debug!("an ELF was copied into a buffer");
 
-        let gpat_pt = paging::PageTable::from_page(paging::Page::from_address(
-            paging::PhysicalAddress::new(self.hgatp.ppn << 12),
-        ));
         unsafe {
-            let buf: &mut [u8] = core::slice::from_raw_parts_mut(buf_addr, load_size as usize);
+            let buf: &[u8] = core::slice::from_raw_parts(buf_addr, load_size as usize);
 
             // TODO (enhancement): care about page permissions
             let elf = Elf::from_bytes(buf);
@@ -63,7 +701,7 @@ impl Guest {
                     // change entrypoint
                     self.sepc = e.header().entry_point() as usize;
                     log::info!("-> entrypoint: 0x{:016x}", self.sepc);
-                    // copy each section (page to page)
+                    // copy each section
                     for s in e.section_header_iter() {
                         if s.sh.addr() > 0 && s.sh.sh_type() == elf_rs::SectionType::SHT_PROGBITS {
                             log::info!(
@@ -72,38 +710,13 @@ impl Guest {
                                 s.sh.addr(),
                                 s.sh.size()
                             );
-                            let start_page_head = s.sh.addr() >> 12 << 12;
-                            let end_page_head = (s.sh.addr() + s.sh.size()) >> 12 << 12;
-                            let last_idx =
-                                (end_page_head - start_page_head) / (memlayout::PAGE_SIZE as u64);
-                            let mut seek = (s.sh.addr() & 0xfff) as usize;
-
-                            for i in 0..=last_idx {
-                                let dest_base_vaddr = paging::VirtualAddress::new(
-                                    (start_page_head + i * (memlayout::PAGE_SIZE as u64)) as usize,
-                                );
-                                let dest_addr = (gpat_pt.resolve(&dest_base_vaddr).to_usize()
-                                    as *mut u8)
-                                    .add(seek % (memlayout::PAGE_SIZE) as usize);
-                                let src_addr = buf_addr
-                                    .offset(s.sh.offset() as isize)
-                                    .add(seek - (s.sh.addr() & 0xfff) as usize);
-                                let copy_size = core::cmp::min(
-                                    (i + 1) as usize * memlayout::PAGE_SIZE as usize,
-                                    ((s.sh.addr() + s.sh.size()) as usize)
-                                        - (start_page_head as usize),
-                                ) as usize
-                                    - seek;
-                                seek += copy_size;
-                                core::ptr::copy(src_addr, dest_addr, copy_size);
-                                log::debug!(
-                                    "{:016x}: {:0x} {:0x} {:0x} {:0x}",
-                                    dest_base_vaddr.to_usize(),
-                                    dest_addr.read_volatile(),
-                                    dest_addr.add(1).read_volatile(),
-                                    dest_addr.add(2).read_volatile(),
-                                    dest_addr.add(3).read_volatile()
-                                );
+                            let offset = s.sh.offset() as usize;
+                            let data = match buf.get(offset..offset + s.sh.size() as usize) {
+                                Some(d) => d,
+                                None => panic!("section {} is out of the image", s.section_name()),
+                            };
+                            if let Err(e) = self.copy_to_guest(s.sh.addr() as usize, data) {
+                                panic!("section {} is out of RAM: {:?}", s.section_name(), e);
                             }
                         }
                     }
@@ -117,11 +730,500 @@ impl Guest {
                 }
             }
         }
+        paging::free(buf_page);
+    }
+}
+
+// The RAM of a guest seen from the hypervisor.
+// Accesses are given guest physical addresses, which must be inside the RAM of the guest.
+#[derive(Copy, Clone)]
+pub struct Memory {
+    // the root page table for the G-stage translation
+    root: usize,
+    mode: paging::Mode,
+    vmid: u16,
+    start: usize,
+    size: usize,
+    // the other regions, in which RAM banks are accessed as well
+    regions: &'static [Region],
+}
+
+impl Memory {
+    // whether `gpa` is in the main RAM or another bank of RAM
+    fn is_ram(&self, gpa: usize) -> bool {
+        self.ram_ranges()
+            .any(|(start, size)| in_range(gpa, start, size))
+    }
+
+    // the ranges (start, size) of RAM: the main RAM followed by the other banks
+    pub fn ram_ranges(&self) -> impl Iterator<Item = (usize, usize)> {
+        let banks = self.regions.iter().filter_map(|r| match r.kind {
+            RegionKind::Ram => Some((r.gpa, r.size)),
+            _ => None,
+        });
+        core::iter::once((self.start, self.size)).chain(banks)
+    }
+
+    // This function returns the index of the page at `gpa` among the pages of RAM, which are
+    // numbered in the order of `ram_ranges` (as in the dirty log).
+    pub fn ram_page(&self, gpa: usize) -> Option<usize> {
+        let page_size = memlayout::PAGE_SIZE as usize;
+        let mut base = 0;
+        for (start, size) in self.ram_ranges() {
+            if in_range(gpa, start, size) {
+                return Some(base + (gpa - start) / page_size);
+            }
+            base += size / page_size;
+        }
+        None
+    }
+
+    // This function returns the host physical address of `gpa`.
+    // For a `write`, the page is made private to the guest (see `unshare`).
+    fn translate(&self, gpa: usize, write: bool) -> Result<usize, Error> {
+        if !self.is_ram(gpa) {
+            return Err(Error);
+        }
+        if write {
+            self.unshare(gpa)?;
+            self.mark_dirty(gpa);
+        }
+        self.page_table()
+            .resolve(&paging::VirtualAddress::new(gpa))
+            .map(|paddr| paddr.to_usize())
+            .map_err(|_| Error)
+    }
+
+    // This function calls `f(host address, position, length)` for each piece of [gpa, gpa + len)
+    // which does not cross a page boundary.
+    fn for_each_piece<F: FnMut(usize, usize, usize)>(
+        &self,
+        gpa: usize,
+        len: usize,
+        write: bool,
+        mut f: F,
+    ) -> Result<(), Error> {
+        let page_size = memlayout::PAGE_SIZE as usize;
+        let mut done = 0;
+        while done < len {
+            let addr = gpa.checked_add(done).ok_or(Error)?;
+            let piece = core::cmp::min(len - done, page_size - addr % page_size);
+            f(self.translate(addr, write)?, done, piece);
+            done += piece;
+        }
+        Ok(())
+    }
+
+    // This function copies the guest memory at `gpa` into `buf`.
+    pub fn read(&self, gpa: usize, buf: &mut [u8]) -> Result<(), Error> {
+        let dest = buf.as_mut_ptr();
+        self.for_each_piece(gpa, buf.len(), false, |addr, pos, len| unsafe {
+            core::ptr::copy_nonoverlapping(addr as *const u8, dest.add(pos), len);
+        })
+    }
+
+    // This function copies `data` into the guest memory at `gpa`.
+    pub fn write(&self, gpa: usize, data: &[u8]) -> Result<(), Error> {
+        let src = data.as_ptr();
+        self.for_each_piece(gpa, data.len(), true, |addr, pos, len| unsafe {
+            core::ptr::copy_nonoverlapping(src.add(pos), addr as *mut u8, len);
+        })
+    }
+
+    // This function unmaps the page at `gpa` and gives it back to the page allocator.
+    pub fn discard(&self, gpa: usize) -> Result<(), Error> {
+        let page_size = memlayout::PAGE_SIZE as usize;
+        if !self.is_ram(gpa) || gpa % page_size != 0 {
+            return Err(Error);
+        }
+        let result = self.page_table().unmap(gpa, page_size);
+        riscv::instruction::hfence_gvma_vmid(self.vmid);
+        result
+    }
+
+    // This function maps a new (cleared) page at `gpa` if it is not mapped (e.g. discarded).
+    // It returns whether a page was mapped.
+    pub fn populate(&self, gpa: usize) -> Result<bool, Error> {
+        let page_size = memlayout::PAGE_SIZE as usize;
+        let gpa = gpa - gpa % page_size;
+        if self.translate(gpa, false).is_ok() {
+            return Ok(false);
+        }
+        if !self.is_ram(gpa) {
+            return Err(Error);
+        }
+        let page = paging::alloc()?;
+        if let Err(e) = self.page_table().map(
+            paging::VirtualAddress::new(gpa),
+            &page,
+            RAM_PERM | paging::PageTableEntryFlag::Owned as u16,
+        ) {
+            paging::free(page);
+            return Err(e);
+        }
+        riscv::instruction::hfence_gvma_vmid(self.vmid);
+        Ok(true)
+    }
+
+    // This function sets the Dirty bit of the page at `gpa` in RAM, as a store by the guest does.
+    // It returns whether the bit was clear.
+    pub fn mark_dirty(&self, gpa: usize) -> bool {
+        if !self.is_ram(gpa) {
+            return false;
+        }
+        match self
+            .page_table()
+            .set_dirty(&paging::VirtualAddress::new(gpa))
+        {
+            Ok(true) => {
+                riscv::instruction::hfence_gvma_vmid(self.vmid);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // the start of the main RAM
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // This function returns the host physical address of the page at `gpa` if it is owned by the
+    // guest alone (the candidates for same-page merging).
+    pub fn private_page(&self, gpa: usize) -> Option<usize> {
+        let owned = paging::PageTableEntryFlag::Owned as u16;
+        match self.page_table().leaf(&paging::VirtualAddress::new(gpa)) {
+            Ok((entry, level)) if entry.flags & owned != 0 => Some(
+                entry.next_page().address().to_usize()
+                    + (gpa
+                        & (paging::level_size(level) - 1)
+                        & !(memlayout::PAGE_SIZE as usize - 1)),
+            ),
+            _ => None,
+        }
+    }
+
+    // This function replaces the private page at `gpa` with the shared page at `hpa`, which is
+    // mapped read-only. The private page is freed unless it is `hpa` itself.
+    // NOTE: the reference to `hpa` is counted by the caller (see `ksm`).
+    pub fn share(&self, gpa: usize, hpa: usize) -> Result<(), Error> {
+        let old = self.private_page(gpa).ok_or(Error)?;
+        let pt = self.page_table();
+        let vaddr = paging::VirtualAddress::new(gpa);
+        pt.split(&vaddr)?;
+        let (entry, _) = pt.leaf(&vaddr).map_err(|_| Error)?;
+        let perm = entry.flags & paging::PERM_MASK & !(paging::PageTableEntryFlag::Write as u16);
+        pt.map(
+            vaddr,
+            &paging::Page::from_address(paging::PhysicalAddress::new(hpa)),
+            perm | paging::PageTableEntryFlag::Shared as u16,
+        )?;
+        riscv::instruction::hfence_gvma_vmid(self.vmid);
+        if old != hpa {
+            paging::free(paging::Page::from_address(paging::PhysicalAddress::new(
+                old,
+            )));
+        }
+        Ok(())
+    }
+
+    // This function gives the guest a private copy of the shared page at `gpa` (e.g. on a store).
+    // It returns whether the page was shared.
+    // NOTE: the copy is writable, even if the permissions were changed while it was shared.
+    pub fn unshare(&self, gpa: usize) -> Result<bool, Error> {
+        let pt = self.page_table();
+        let vaddr = paging::VirtualAddress::new(gpa);
+        let entry = match pt.leaf(&vaddr) {
+            Ok((entry, _)) if entry.flags & paging::PageTableEntryFlag::Shared as u16 != 0 => entry,
+            _ => return Ok(false),
+        };
+        let shared = entry.next_page().address().to_usize();
+        let page = paging::alloc()?;
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                shared as *const u8,
+                page.address().to_usize() as *mut u8,
+                memlayout::PAGE_SIZE as usize,
+            );
+        }
+        let perm = (entry.flags & paging::PERM_MASK)
+            | paging::PageTableEntryFlag::Write as u16
+            | paging::PageTableEntryFlag::Owned as u16;
+        if let Err(e) = pt.map(vaddr, &page, perm) {
+            paging::free(page);
+            return Err(e);
+        }
+        riscv::instruction::hfence_gvma_vmid(self.vmid);
+        ksm::put(shared);
+        ksm::count_break();
+        Ok(true)
+    }
+
+    fn page_table(&self) -> paging::PageTable {
+        paging::PageTable::from_page(
+            paging::Page::from_address(paging::PhysicalAddress::new(self.root)),
+            self.mode,
+        )
+    }
+
+    fn read_u64(&self, gpa: usize) -> Result<u64, Error> {
+        let mut buf = [0; 8];
+        self.read(gpa, &mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    // This function translates the guest virtual address `gva` into the guest physical address
+    // with the VS-stage page table (vsatp), as the guest would access it in the mode it trapped
+    // from (hstatus.SPVP), like HLV / HLVX do.
+    // The A/D bits are updated as the hardware does (Svadu).
+    // NOTE: the VS-level CSRs hold the values of the current guest, so this function (and the
+    // functions below taking guest virtual addresses) must be called for the current guest.
+    pub fn translate_virt(&self, gva: usize, access: Access) -> Result<usize, Error> {
+        let vsatp = riscv::csr::vsatp::read();
+        let levels = match vsatp >> 60 {
+            // Bare
+            0 => return Ok(gva),
+            // Sv39, Sv48, Sv57
+            8 => 3,
+            9 => 4,
+            10 => 5,
+            _ => return Err(Error),
+        };
+        // the upper bits must be the copies of the highest bit
+        let top = (gva as isize) >> (11 + 9 * levels);
+        if top != 0 && top != -1 {
+            return Err(Error);
+        }
+        let vsstatus = riscv::csr::vsstatus::read();
+        let sum = vsstatus & (1 << 18) != 0;
+        let mxr = vsstatus & (1 << 19) != 0;
+        let supervisor = riscv::csr::hstatus::spvp();
+
+        let flag = |pte: u64, f: paging::PageTableEntryFlag| pte & (f as u64) != 0;
+        let ppn = |pte: u64| ((pte >> 10) & ((1 << 44) - 1)) as usize;
+        let mut table = (vsatp & ((1 << 44) - 1)) << 12;
+        for level in (0..levels).rev() {
+            let pte_addr = table + ((gva >> (12 + 9 * level)) & 0x1ff) * 8;
+            let mut pte = self.read_u64(pte_addr)?;
+            if !flag(pte, paging::PageTableEntryFlag::Valid) {
+                return Err(Error);
+            }
+            let read = flag(pte, paging::PageTableEntryFlag::Read);
+            let write = flag(pte, paging::PageTableEntryFlag::Write);
+            let execute = flag(pte, paging::PageTableEntryFlag::Execute);
+            if !read && !write && !execute {
+                // a pointer to the next table
+                table = ppn(pte) << 12;
+                continue;
+            }
+
+            // a leaf (which may be a superpage)
+            let size = paging::level_size(level);
+            if (write && !read) || (ppn(pte) << 12) % size != 0 {
+                return Err(Error);
+            }
+            // U-mode accesses only user pages. S-mode accesses them only with SUM (not executes).
+            let user = flag(pte, paging::PageTableEntryFlag::User);
+            let allowed = if supervisor {
+                !user || (sum && access != Access::Execute)
+            } else {
+                user
+            };
+            let permitted = allowed
+                && match access {
+                    Access::Read => read || (mxr && execute),
+                    Access::Write => write,
+                    Access::Execute => execute,
+                };
+            if !permitted {
+                return Err(Error);
+            }
+            let mut updated = pte | paging::PageTableEntryFlag::Access as u64;
+            if access == Access::Write {
+                updated |= paging::PageTableEntryFlag::Dirty as u64;
+            }
+            if updated != pte {
+                pte = updated;
+                self.write(pte_addr, &pte.to_le_bytes())?;
+            }
+            return Ok((ppn(pte) << 12) | (gva & (size - 1)));
+        }
+        Err(Error)
+    }
+
+    // This function calls `f(guest physical address, position, length)` for each piece of
+    // [gva, gva + len) which does not cross a page boundary.
+    fn for_each_virt_piece<F: FnMut(usize, usize, usize) -> Result<(), Error>>(
+        &self,
+        gva: usize,
+        len: usize,
+        access: Access,
+        mut f: F,
+    ) -> Result<(), Error> {
+        let page_size = memlayout::PAGE_SIZE as usize;
+        let mut done = 0;
+        while done < len {
+            let addr = gva.checked_add(done).ok_or(Error)?;
+            let piece = core::cmp::min(len - done, page_size - addr % page_size);
+            f(self.translate_virt(addr, access)?, done, piece)?;
+            done += piece;
+        }
+        Ok(())
+    }
+
+    // This function copies the guest memory at the guest virtual address `gva` into `buf`.
+    pub fn read_virt(&self, gva: usize, buf: &mut [u8]) -> Result<(), Error> {
+        self.for_each_virt_piece(gva, buf.len(), Access::Read, |gpa, pos, len| {
+            self.read(gpa, &mut buf[pos..pos + len])
+        })
+    }
+
+    // This function copies `data` into the guest memory at the guest virtual address `gva`.
+    pub fn write_virt(&self, gva: usize, data: &[u8]) -> Result<(), Error> {
+        self.for_each_virt_piece(gva, data.len(), Access::Write, |gpa, pos, len| {
+            self.write(gpa, &data[pos..pos + len])
+        })
+    }
+
+    // This function fetches the instruction at the guest virtual address `gva`.
+    pub fn fetch(&self, gva: usize) -> Result<u32, Error> {
+        let mut buf = [0; 4];
+        self.for_each_virt_piece(gva, 2, Access::Execute, |gpa, pos, len| {
+            self.read(gpa, &mut buf[pos..pos + len])
+        })?;
+        // the upper half is fetched only for uncompressed instructions
+        if buf[0] & 0b11 == 0b11 {
+            self.for_each_virt_piece(gva + 2, 2, Access::Execute, |gpa, pos, len| {
+                self.read(gpa, &mut buf[2 + pos..2 + pos + len])
+            })?;
+        }
+        Ok(u32::from_le_bytes(buf))
+    }
+}
+
+// The kind of an access by a guest virtual address, which decides the permission to check.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+// the permissions of the RAM of guests
+const RAM_PERM: u16 = (paging::PageTableEntryFlag::Read as u16)
+    | (paging::PageTableEntryFlag::Write as u16)
+    | (paging::PageTableEntryFlag::Execute as u16)
+    | (paging::PageTableEntryFlag::User as u16); // required!
+
+// the permissions of the ROM of guests
+const ROM_PERM: u16 = (paging::PageTableEntryFlag::Read as u16)
+    | (paging::PageTableEntryFlag::Execute as u16)
+    | (paging::PageTableEntryFlag::User as u16); // required!
+
+fn in_range(addr: usize, base: usize, size: usize) -> bool {
+    base <= addr && addr < base + size
+}
+
+// This function checks the guest physical address space given by `config`: the main RAM, the regions
+// and the emulated devices must be page-aligned and must not overlap each other.
+// It returns the end of the address space.
+fn check_layout(config: &Config, imsic_file: Option<usize>) -> Result<usize, Error> {
+    let page_size = memlayout::PAGE_SIZE as usize;
+    let mut layout: Vec<(&'static str, usize, usize)> = Vec::new();
+    layout.push(("RAM", config.dram_start, config.dram_size));
+    for r in config.regions.iter() {
+        let kind = match r.kind {
+            RegionKind::Ram => "RAM",
+            RegionKind::Rom { .. } => "ROM",
+            RegionKind::Mmio { hpa, perm } => {
+                let read = paging::PageTableEntryFlag::Read as u16;
+                let write = paging::PageTableEntryFlag::Write as u16;
+                let execute = paging::PageTableEntryFlag::Execute as u16;
+                // the host RAM must not be given to the guest as MMIO
+                if hpa % page_size != 0
+                    || (hpa < memlayout::DRAM_END && memlayout::DRAM_START < hpa + r.size)
+                    || perm & !(read | write | execute) != 0
+                    || perm & (read | execute) == 0
+                    || (perm & write != 0 && perm & read == 0)
+                {
+                    log::info!(
+                        "-> invalid MMIO region: 0x{:016x} (host: 0x{:016x})",
+                        r.gpa,
+                        hpa
+                    );
+                    return Err(Error);
+                }
+                "MMIO"
+            }
+        };
+        layout.push((kind, r.gpa, r.size));
+    }
+    if let Some(p) = &config.passthrough {
+        layout.push(("passthrough device", p.gpa, page_size));
+    }
+    if imsic_file.is_some() {
+        layout.push((
+            "APLIC",
+            memlayout::GUEST_APLIC_BASE,
+            memlayout::GUEST_APLIC_SIZE,
+        ));
+        layout.push(("IMSIC", memlayout::GUEST_IMSIC_BASE, page_size));
+    } else {
+        layout.push((
+            "PLIC",
+            memlayout::GUEST_PLIC_BASE,
+            memlayout::GUEST_PLIC_SIZE,
+        ));
+    }
+    layout.push(("RTC", memlayout::GUEST_RTC_BASE, memlayout::GUEST_RTC_SIZE));
+    layout.push((
+        "test device",
+        memlayout::GUEST_TEST_BASE,
+        memlayout::GUEST_TEST_SIZE,
+    ));
+    if !config.virtio_pci.is_empty() {
+        layout.push((
+            "PCIe ECAM",
+            memlayout::GUEST_PCIE_ECAM_BASE,
+            memlayout::GUEST_PCIE_ECAM_SIZE,
+        ));
+        layout.push((
+            "PCIe MMIO",
+            memlayout::GUEST_PCIE_MMIO_BASE,
+            memlayout::GUEST_PCIE_MMIO_SIZE,
+        ));
+    }
+
+    let mut end = 0;
+    for (i, (kind, base, size)) in layout.iter().enumerate() {
+        if *size == 0 || base % page_size != 0 || size % page_size != 0 {
+            log::info!("-> {} at 0x{:016x} is not page-aligned", kind, base);
+            return Err(Error);
+        }
+        let e = base.checked_add(*size).ok_or(Error)?;
+        for (other, other_base, other_size) in layout[..i].iter() {
+            if *base < other_base + other_size && *other_base < e {
+                log::info!(
+                    "-> {} at 0x{:016x} overlaps {} at 0x{:016x}",
+                    kind,
+                    base,
+                    other,
+                    other_base
+                );
+                return Err(Error);
+            }
+        }
+        end = core::cmp::max(end, e);
     }
+    Ok(end)
 }
 
 // This function return newly allocated page table for Guest Physical Address Translation.
-fn prepare_gpat_pt() -> Result<paging::PageTable, Error> {
+fn prepare_gpat_pt(config: &Config, imsic_file: Option<usize>) -> Result<paging::PageTable, Error> {
     // NOTE (from the RISC-V specification):
     // As explained in Section 5.5.1, for the paged virtual-memory schemes (Sv32x4, Sv39x4, and Sv48x4),
     // the root page table is 16 KiB and must be aligned to a 16-KiB boundary. In these modes, the lowest
@@ -129,43 +1231,148 @@ fn prepare_gpat_pt() -> Result<paging::PageTable, Error> {
     // that supports only the defined paged virtual-memory schemes and/or Bare may hardwire PPN[1:0]
     // to zero
 
+    // choose the translation mode which covers all the guest physical addresses
+    let end = check_layout(config, imsic_file)?;
+    let mode = paging::gstage_mode_for(end).ok_or(Error)?;
+
     // get a 16KiB-aligned & 16KiB page
-    // TODO (enhancement): this is a native implementation. we need to implement a more clean allocator in `paging` crate.
-    let root_page = paging::alloc_16();
+    let root_page = paging::alloc_order(mode.root_order())?;
     log::info!(
         "a page 0x{:016x} was allocated for a guest page address translation page table",
         root_page.address().to_usize()
     );
-    let root_pt = paging::PageTable::from_page(root_page);
-
-    // create an identity map for UART MMIO
-    let vaddr = memlayout::GUEST_UART_BASE;
-    let page = paging::Page::from_address(paging::PhysicalAddress::new(vaddr));
-    root_pt.map(
-        paging::VirtualAddress::new(vaddr),
-        &page,
-        (paging::PageTableEntryFlag::Read as u16)
-            | (paging::PageTableEntryFlag::Write as u16)
-            | (paging::PageTableEntryFlag::Execute as u16)
-            | (paging::PageTableEntryFlag::User as u16), // required!
-    );
+    let root_pt = paging::PageTable::from_page(root_page, mode);
 
-    // allocating new pages and map GUEST_DRAM_START ~ GUEST_DRAM_END into those pages for guest kernel
-    let map_page_num = (memlayout::GUEST_DRAM_END - memlayout::GUEST_DRAM_START)
-        / (memlayout::PAGE_SIZE as usize)
-        + 1;
-    for i in 0..map_page_num {
-        let vaddr = memlayout::GUEST_DRAM_START + i * (memlayout::PAGE_SIZE as usize);
-        let page = paging::alloc();
+    // map the windows of host MMIO (e.g. the UART)
+    for r in config.regions.iter() {
+        if let RegionKind::Mmio { hpa, perm } = r.kind {
+            for offset in (0..r.size).step_by(memlayout::PAGE_SIZE as usize) {
+                let page = paging::Page::from_address(paging::PhysicalAddress::new(hpa + offset));
+                root_pt.map(
+                    paging::VirtualAddress::new(r.gpa + offset),
+                    &page,
+                    perm | (paging::PageTableEntryFlag::User as u16), // required!
+                )?;
+            }
+        }
+    }
+
+    // map the MMIO page of a passthrough device
+    if let Some(p) = &config.passthrough {
+        if p.slot >= memlayout::VIRTIO_MMIO_SLOTS {
+            return Err(Error);
+        }
+        let page = paging::Page::from_address(paging::PhysicalAddress::new(
+            virtio::slot_base(p.slot) as usize,
+        ));
         root_pt.map(
-            paging::VirtualAddress::new(vaddr),
+            paging::VirtualAddress::new(p.gpa),
             &page,
             (paging::PageTableEntryFlag::Read as u16)
                 | (paging::PageTableEntryFlag::Write as u16)
-                | (paging::PageTableEntryFlag::Execute as u16)
                 | (paging::PageTableEntryFlag::User as u16), // required!
-        )
+        )?;
+    }
+
+    // map the guest interrupt file as the S-level IMSIC of the guest
+    if let Some(file) = imsic_file {
+        let page =
+            paging::Page::from_address(paging::PhysicalAddress::new(aia::guest_file_base(file)));
+        log::info!(
+            "-> guest interrupt file {} is mapped at 0x{:016x}",
+            file,
+            memlayout::GUEST_IMSIC_BASE
+        );
+        root_pt.map(
+            paging::VirtualAddress::new(memlayout::GUEST_IMSIC_BASE),
+            &page,
+            (paging::PageTableEntryFlag::Read as u16)
+                | (paging::PageTableEntryFlag::Write as u16)
+                | (paging::PageTableEntryFlag::User as u16), // required!
+        )?;
+    }
+
+    // map RAM (the main RAM and the other banks) and ROM.
+    // if the RAM must be identity-mapped, the same host physical range is reserved.
+    let identity = config.passthrough.is_some();
+    map_memory(
+        &root_pt,
+        config.dram_start,
+        config.dram_size,
+        RAM_PERM,
+        identity,
+    )?;
+    for r in config.regions.iter() {
+        match r.kind {
+            RegionKind::Ram => map_memory(&root_pt, r.gpa, r.size, RAM_PERM, identity)?,
+            // the contents are loaded by `Guest::load_roms`
+            RegionKind::Rom { .. } => map_memory(&root_pt, r.gpa, r.size, ROM_PERM, false)?,
+            RegionKind::Mmio { .. } => {}
+        }
     }
 
     Ok(root_pt)
 }
+
+// This function maps [gpa, gpa + size) to new pages, or to the same host physical range if `identity`.
+fn map_memory(
+    root_pt: &paging::PageTable,
+    gpa: usize,
+    size: usize,
+    perm: u16,
+    identity: bool,
+) -> Result<(), Error> {
+    let identity_base = if identity {
+        let base = paging::reserve(gpa, size)?;
+        log::info!(
+            "-> RAM is identity-mapped at 0x{:016x}",
+            base.address().to_usize()
+        );
+        Some(base.address().to_usize())
+    } else {
+        None
+    };
+    // the largest superpages are used where the addresses (and the host memory) allow
+    let mut perm = perm;
+    let mut offset = 0;
+    while offset < size {
+        let vaddr = gpa + offset;
+        let left = size - offset;
+        let fits = |level: usize| {
+            vaddr % paging::level_size(level) == 0 && left >= paging::level_size(level)
+        };
+        let (page, level) = match identity_base {
+            Some(base) => {
+                let level = (0..=2).rev().find(|l| fits(*l)).unwrap_or(0);
+                let addr = base + offset;
+                for i in 0..paging::level_size(level) / (memlayout::PAGE_SIZE as usize) {
+                    paging::Page::from_address(paging::PhysicalAddress::new(
+                        addr + i * (memlayout::PAGE_SIZE as usize),
+                    ))
+                    .clear();
+                }
+                (
+                    paging::Page::from_address(paging::PhysicalAddress::new(addr)),
+                    level,
+                )
+            }
+            // NOTE: gigapages are larger than the blocks of the page allocator
+            None => {
+                perm |= paging::PageTableEntryFlag::Owned as u16;
+                // fall back to 4 KiB pages if there is no free 2 MiB block
+                let huge = if fits(1) {
+                    paging::alloc_order(9).ok()
+                } else {
+                    None
+                };
+                match huge {
+                    Some(p) => (p, 1),
+                    None => (paging::alloc()?, 0),
+                }
+            }
+        };
+        root_pt.map_superpage(paging::VirtualAddress::new(vaddr), &page, perm, level)?;
+        offset += paging::level_size(level);
+    }
+    Ok(())
+}
@@ -0,0 +1,136 @@
+// Heap for the hypervisor
+/////
+// The global allocator, which enables the `alloc` crate (`Box`, `Vec`, `BTreeMap`, `String`, ...).
+// Small objects are carved out of pages by size class (16 B - 2 KiB), and freed objects are kept
+// in a free list per class. Larger objects are served directly by the page allocator.
+// NOTE: the heap can be used only after `paging::init`.
+
+use crate::memlayout::PAGE_SIZE;
+use crate::paging;
+use core::alloc::{GlobalAlloc, Layout};
+
+const MIN_CLASS_SHIFT: usize = 4;
+const MAX_CLASS_SHIFT: usize = 11;
+const NUM_CLASSES: usize = MAX_CLASS_SHIFT - MIN_CLASS_SHIFT + 1;
+
+pub struct Heap;
+
+#[global_allocator]
+static HEAP: Heap = Heap;
+
+// the heads of the free lists of each size class (the link is stored in the free object)
+// NOTE: the heap is used only by the boot hart with interrupts disabled, so no lock is taken.
+static mut FREE_OBJECTS: [usize; NUM_CLASSES] = [0; NUM_CLASSES];
+
+#[derive(Debug)]
+pub struct Stats {
+    // the bytes allocated and not freed yet (rounded up to the size class or pages)
+    pub used: usize,
+    // the pages taken from the page allocator for small objects
+    pub slab_pages: usize,
+}
+
+static mut STATS: Stats = Stats {
+    used: 0,
+    slab_pages: 0,
+};
+
+pub fn stats() -> &'static Stats {
+    unsafe { &STATS }
+}
+
+// This function returns the size class of `layout`, or `None` if it is too large for the slabs.
+fn class_of(layout: &Layout) -> Option<usize> {
+    let size = core::cmp::max(layout.size(), layout.align()).next_power_of_two();
+    let shift = core::cmp::max(size.trailing_zeros() as usize, MIN_CLASS_SHIFT);
+    if shift <= MAX_CLASS_SHIFT {
+        Some(shift - MIN_CLASS_SHIFT)
+    } else {
+        None
+    }
+}
+
+// This function returns the order of the pages for `layout` (for objects too large for the slabs).
+fn order_of(layout: &Layout) -> usize {
+    let page_size = PAGE_SIZE as usize;
+    let pages = (layout.size() + page_size - 1) / page_size;
+    let mut order = pages.next_power_of_two().trailing_zeros() as usize;
+    // a block of pages is aligned to its size
+    while (page_size << order) < layout.align() {
+        order += 1;
+    }
+    order
+}
+
+fn class_size(class: usize) -> usize {
+    1 << (class + MIN_CLASS_SHIFT)
+}
+
+// This function fills the free list of `class` with the objects in a new page.
+unsafe fn refill(class: usize) -> bool {
+    let page = match paging::alloc() {
+        Ok(p) => p.address().to_usize(),
+        Err(_) => return false,
+    };
+    let size = class_size(class);
+    for i in (0..(PAGE_SIZE as usize) / size).rev() {
+        let object = page + i * size;
+        (object as *mut usize).write(FREE_OBJECTS[class]);
+        FREE_OBJECTS[class] = object;
+    }
+    STATS.slab_pages += 1;
+    true
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match class_of(&layout) {
+            Some(class) => {
+                if FREE_OBJECTS[class] == 0 && !refill(class) {
+                    return core::ptr::null_mut();
+                }
+                let object = FREE_OBJECTS[class];
+                FREE_OBJECTS[class] = (object as *const usize).read();
+                STATS.used += class_size(class);
+                object as *mut u8
+            }
+            None => {
+                let order = order_of(&layout);
+                match paging::alloc_order(order) {
+                    Ok(p) => {
+                        STATS.used += (PAGE_SIZE as usize) << order;
+                        p.address().to_usize() as *mut u8
+                    }
+                    Err(_) => core::ptr::null_mut(),
+                }
+            }
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match class_of(&layout) {
+            Some(class) => {
+                // TODO (enhancement): give empty pages back to the page allocator
+                (ptr as *mut usize).write(FREE_OBJECTS[class]);
+                FREE_OBJECTS[class] = ptr as usize;
+                STATS.used -= class_size(class);
+            }
+            None => {
+                paging::free(paging::Page::from_address(paging::PhysicalAddress::new(
+                    ptr as usize,
+                )));
+                STATS.used -= (PAGE_SIZE as usize) << order_of(&layout);
+            }
+        }
+    }
+}
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    panic!(
+        "out of memory: failed to allocate {} bytes (align: {}); {:?}",
+        layout.size(),
+        layout.align(),
+        paging::stats()
+    );
+}
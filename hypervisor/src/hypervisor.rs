@@ -1,11 +1,21 @@
 global_asm!(include_str!("hypervisor.S"));
 
-use crate::guest::Guest;
+use crate::aia;
+use crate::config;
+use crate::guest::{Guest, Memory, State};
+use crate::ksm;
 use crate::memlayout;
+use crate::mkernel;
 use crate::paging;
+use crate::pci;
 use crate::plic;
 use crate::riscv;
+use crate::rtc;
+use crate::sbi;
+use crate::snapshot;
+use crate::syscon;
 use crate::uart;
+use crate::vdev;
 use crate::virtio;
 use core::fmt::Error;
 
@@ -15,6 +25,36 @@ extern "C" {
 
     #[link_name = "trap_to_hypervisor"]
     pub fn trap();
+
+    #[link_name = "return_to_guest"]
+    fn return_to_guest(sepc: usize) -> !;
+}
+
+// guests
+/////
+
+pub const MAX_GUESTS: usize = 4;
+static mut GUESTS: [Option<Guest>; MAX_GUESTS] = [None, None, None, None];
+static mut CURRENT: usize = 0;
+// the exit code of the hypervisor: the first non-zero one among the guests
+static mut EXIT_CODE: u32 = 0;
+
+// the memory of each guest (e.g. for same-page merging)
+fn memories() -> [Option<Memory>; MAX_GUESTS] {
+    let mut memories = [None; MAX_GUESTS];
+    for (i, g) in unsafe { GUESTS.iter().enumerate() } {
+        memories[i] = g.as_ref().map(|g| g.memory());
+    }
+    memories
+}
+
+pub fn current_guest() -> &'static mut Guest {
+    unsafe {
+        match &mut GUESTS[CURRENT] {
+            Some(g) => g,
+            None => panic!("no guest is running"),
+        }
+    }
 }
 
 #[no_mangle]
@@ -26,24 +66,72 @@ pub fn rust_hypervisor_entrypoint() -> ! {
     }
     log::info!("succeeded in initializing rvvisor");
 
+    if config::GUESTS.len() > MAX_GUESTS {
+        panic!("too many guests: {}", config::GUESTS.len());
+    }
+    for (i, config) in config::GUESTS.iter().enumerate() {
+        log::info!("a new guest instance: {}", config.name);
+        log::info!("-> create metadata set");
+        let mut guest = Guest::new(config);
+        let restored = match snapshot::restore(&mut guest) {
+            Ok(()) => true,
+            Err(snapshot::Error::NotFound) => false,
+            Err(snapshot::Error::Incompatible) => {
+                log::info!("-> the snapshot does not match the guest. ignore it");
+                false
+            }
+            Err(e) => {
+                // the guest may be partially restored (e.g. on an error of the disk).
+                // start over with a fresh one.
+                log::info!("-> failed to restore from the snapshot: {:?}. ignore it", e);
+                guest.destroy();
+                guest = Guest::new(config);
+                false
+            }
+        };
+        if !restored {
+            log::info!("-> load a tiny kernel image");
+            guest.load_from_disk();
+            guest.load_device_tree();
+        }
+        unsafe {
+            GUESTS[i] = Some(guest);
+        }
+    }
+
     // TODO (enhnancement): multiplex here
-    let guest_name = "guest01";
-    log::info!("a new guest instance: {}", guest_name);
-    log::info!("-> create metadata set");
-    let mut guest = Guest::new(guest_name);
-    log::info!("-> load a tiny kernel image");
-    guest.load_from_disk();
-
-    log::info!("switch to guest");
-    switch_to_guest(&guest);
+    // NOTE: interrupts are masked so that the prepared trap frame is not overwritten before sret
+    riscv::csr::sstatus::set_sie(false);
+    unsafe {
+        CURRENT = 0;
+        let frame = riscv::csr::sscratch::read() as *mut TrapFrame;
+        return_to_guest(enter_guest(frame));
+    }
 }
 
 pub fn init() -> Result<(), Error> {
     // inti memory allocator
     paging::init();
+    paging::enable_hs_paging()?;
+    paging::probe_gstage_modes();
 
-    // init virtio
-    virtio::init();
+    // init wall-clock time
+    rtc::init();
+
+    // init AIA (guest interrupt files)
+    aia::init();
+
+    // init PCI
+    pci::init();
+
+    // init virtio, except for the devices which are passed through to guests
+    let mut reserved_slots = 0;
+    for config in config::GUESTS.iter() {
+        if let Some(p) = &config.passthrough {
+            reserved_slots |= 1 << p.slot;
+        }
+    }
+    virtio::init(reserved_slots);
 
     // hedeleg: delegate some synchoronous exceptions
     riscv::csr::hedeleg::write((1 << 0) | (1 << 3) | (1 << 8) | (1 << 12) | (1 << 13) | (1 << 15));
@@ -56,6 +144,22 @@ pub fn init() -> Result<(), Error> {
     // hvip: clear all interrupts first
     riscv::csr::hvip::write(0);
 
+    // hcounteren: allow guests to read the counters (e.g. time)
+    riscv::csr::hcounteren::write(
+        riscv::csr::hcounteren::CY | riscv::csr::hcounteren::TM | riscv::csr::hcounteren::IR,
+    );
+
+    // henvcfg: let guests program vstimecmp directly if Sstc is available
+    if mkernel::has_sstc() {
+        log::info!("Sstc is available");
+        riscv::csr::henvcfg::write(riscv::csr::henvcfg::read() | riscv::csr::henvcfg::STCE);
+    } else {
+        log::info!("Sstc is not available. the timer of guests is emulated with SBI");
+    }
+
+    // hstatus: trap WFI in guests so that we can poll emulated devices while guests are idle
+    riscv::csr::hstatus::set_vtw(true);
+
     // stvec: set handler
     riscv::csr::stvec::set(&(trap as unsafe extern "C" fn()));
     assert_eq!(
@@ -64,7 +168,7 @@ pub fn init() -> Result<(), Error> {
     );
 
     // allocate memory region for TrapFrame and set it sscratch
-    let trap_frame = paging::alloc();
+    let trap_frame = paging::alloc()?;
     riscv::csr::sscratch::write(trap_frame.address().to_usize());
     log::info!("sscratch: {:016x}", riscv::csr::sscratch::read());
 
@@ -83,6 +187,10 @@ fn enable_interrupt() {
 
     // configure PLIC
     plic::enable_interrupt();
+    if let Some(irq) = virtio::irq() {
+        plic::set_priority(irq, 1);
+        plic::enable(irq);
+    }
 
     // sie; enable external interrupt
     // TODO (enhancement): timer interrupt
@@ -90,29 +198,16 @@ fn enable_interrupt() {
     let current_sie = riscv::csr::sie::read();
     riscv::csr::sie::write(current_sie | (riscv::csr::sie::SEIE as usize));
 
+    // hie: enable guest external interrupts (MSIs to guests which are not running)
+    if aia::is_available() {
+        let current_hie = riscv::csr::hie::read();
+        riscv::csr::hie::write(current_hie | riscv::csr::hie::SGEIE);
+    }
+
     // sstatus: enable global interrupt
     riscv::csr::sstatus::set_sie(true);
 }
 
-pub fn switch_to_guest(target: &Guest) -> ! {
-    // hgatp: set page table for guest physical address translation
-    riscv::csr::hgatp::set(&target.hgatp);
-    riscv::instruction::hfence_gvma();
-    assert_eq!(target.hgatp.to_usize(), riscv::csr::hgatp::read());
-
-    // hstatus: handle SPV change the virtualization mode to 0 after sret
-    riscv::csr::hstatus::set_spv(riscv::csr::VirtualzationMode::Guest);
-
-    // sstatus: handle SPP to 1 to change the privilege level to S-Mode after sret
-    riscv::csr::sstatus::set_spp(riscv::csr::CpuMode::S);
-
-    // sepc: set the addr to jump
-    riscv::csr::sepc::set(&target.sepc);
-
-    // jump!
-    riscv::instruction::sret();
-}
-
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct TrapFrame {
@@ -143,15 +238,17 @@ pub extern "C" fn rust_strap_handler(
             9 => {
                 if let Some(interrupt) = plic::get_claim() {
                     log::debug!("interrupt id: {}", interrupt);
-                    match interrupt {
-                        1..=8 => {
-                            virtio::handle_interrupt(interrupt);
-                        }
-                        10 => {
-                            uart::handle_interrupt();
-                        }
-                        _ => {
-                            unimplemented!()
+                    if !forward_interrupt(interrupt) {
+                        match interrupt {
+                            i if virtio::irq() == Some(i) => {
+                                virtio::handle_interrupt(interrupt);
+                            }
+                            10 => {
+                                uart::handle_interrupt();
+                            }
+                            _ => {
+                                unimplemented!()
+                            }
                         }
                     }
                     plic::complete(interrupt);
@@ -159,32 +256,115 @@ pub extern "C" fn rust_strap_handler(
                     panic!("invalid state")
                 }
             }
-            // timer interrupt & software interrrupt
+            // timer interrupt
+            5 => {
+                // the timer of the current guest has expired (only without Sstc).
+                // it is masked until the guest sets the next one.
+                let sie = riscv::csr::sie::read();
+                riscv::csr::sie::write(sie & !riscv::csr::sie::STIE);
+                let hvip = riscv::csr::hvip::read();
+                riscv::csr::hvip::write(hvip | riscv::csr::hvip::VSTIP);
+            }
+            // supervisor guest external interrupt
+            12 => {
+                // an MSI arrived at the interrupt file of a guest which is not running.
+                // it stays pending in the file and is seen by the guest through VGEIN when it runs.
+                // until then, the file is masked.
+                let pending = riscv::csr::hgeip::read();
+                log::debug!("guest external interrupt: 0x{:x}", pending);
+                let hgeie = riscv::csr::hgeie::read();
+                riscv::csr::hgeie::write(hgeie & !pending);
+            }
+            // software interrrupt
             _ => {
                 unimplemented!();
             }
         }
     } else {
         match cause_code {
+            12 | 13 | 15 => {
+                // page faults of guests are delegated to them, so this is raised by the hypervisor
+                // itself (e.g. a wild pointer, a write to the text or a stack overflow into a guard page)
+                panic!(
+                    "page fault in the hypervisor at 0x{:016x} (scause: {}, address: 0x{:016x})",
+                    sepc, cause_code, stval
+                );
+            }
             8 => {
                 log::info!("environment call from U-mode / VU-mode at 0x{:016x}", sepc);
                 // TODO: better handling
                 loop {}
             }
             10 => {
-                log::info!("environment call from VS-mode at 0x{:016x}", sepc);
-                // TODO: better handling
-                loop {}
+                // SBI calls from guests
+                sbi::handle_ecall(current_guest(), frame, sepc);
+                return resume(sepc + 4, frame);
             }
-            21 => {
-                log::info!("exception: load guest page fault at 0x{:016x}", sepc);
-                // TODO (enhancement): demand paging
-                loop {}
+            21 | 23 => {
+                // accesses to emulated devices
+                let gpa = (riscv::csr::htval::read() << 2) | (stval & 0b11);
+                let guest = current_guest();
+                // a store to a shared page: give the guest its own copy
+                if cause_code == 23 {
+                    match guest.memory().unshare(gpa) {
+                        Ok(true) => return resume(sepc, frame),
+                        Ok(false) => {}
+                        Err(e) => log::info!("failed to unshare 0x{:016x}: {:?}", gpa, e),
+                    }
+                    // a store to a page whose Dirty bit was cleared (see `Guest::start_dirty_log`)
+                    if guest.handle_dirty_fault(gpa) {
+                        return resume(sepc, frame);
+                    }
+                }
+                if let Some(next_sepc) = vdev::emulate_mmio(guest, gpa, sepc, frame) {
+                    guest.update_interrupts();
+                    return resume(next_sepc, frame);
+                }
+                // the page may have been taken by the balloon without telling it.
+                // NOTE: a fault on a mapped page (e.g. a store to a read-only one) is not retried.
+                if let Ok(true) = guest.memory().populate(gpa) {
+                    return resume(sepc, frame);
+                }
+
+                if cause_code == 21 {
+                    log::info!(
+                        "exception: load guest page fault at 0x{:016x} (gpa: 0x{:016x})",
+                        sepc,
+                        gpa
+                    );
+                } else {
+                    log::info!(
+                        "exception: store/amo guest-page fault at 0x{:016x} (gpa: 0x{:016x})",
+                        sepc,
+                        gpa
+                    );
+                }
+                // the guest cannot continue
+                guest.state = State::Stopped(1);
+                return resume(sepc, frame);
             }
-            23 => {
-                log::info!("exception: store/amo guest-page fault at 0x{:016x}", sepc);
-                // TODO: better handling
-                loop {}
+            22 => {
+                // NOTE: 0 (an illegal instruction) if the instruction cannot be fetched
+                let inst = match current_guest().memory().fetch(sepc) {
+                    Ok(inst) => inst,
+                    Err(_) => 0,
+                };
+                if inst == riscv::instruction::WFI {
+                    // the guest is idle. poll emulated devices and let it continue.
+                    current_guest().update_interrupts();
+                    if config::SAME_PAGE_MERGING {
+                        ksm::scan(&memories());
+                    }
+                    return sepc + 4;
+                }
+                log::info!(
+                    "exception: virtual instruction at 0x{:016x}: 0x{:08x}",
+                    sepc,
+                    inst
+                );
+                // the guest cannot continue
+                current_guest().state = State::Stopped(1);
+                return resume(sepc, frame);
             }
             _ => {
                 unimplemented!();
@@ -193,3 +373,148 @@ pub extern "C" fn rust_strap_handler(
     }
     sepc
 }
+
+// This function routes the host interrupt to the guest which owns the source.
+// It returns whether the interrupt was consumed by a guest.
+fn forward_interrupt(interrupt: u32) -> bool {
+    unsafe {
+        for (i, slot) in GUESTS.iter_mut().enumerate() {
+            if let Some(guest) = slot {
+                if guest.forward_interrupt(interrupt) {
+                    if i == CURRENT {
+                        guest.update_interrupts();
+                    }
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
+
+// This function handles the state change of the current guest before returning to guests.
+// It returns the address where the execution resumes.
+// NOTE: guests run to completion; the next guest starts when the current one stops.
+fn resume(sepc: usize, frame: *mut TrapFrame) -> usize {
+    let guest = current_guest();
+    match guest.state {
+        State::Running => sepc,
+        State::Rebooting => {
+            log::info!("{} is rebooting", guest.name);
+            guest.reboot();
+            enter_guest(frame)
+        }
+        State::Stopped(code) => {
+            log::info!("{} has stopped (exit code: {})", guest.name, code);
+            riscv::csr::hgatp::write(0);
+            unsafe {
+                if EXIT_CODE == 0 {
+                    EXIT_CODE = code;
+                }
+                if let Some(guest) = GUESTS[CURRENT].take() {
+                    guest.destroy();
+                }
+            }
+            match next_runnable_guest() {
+                Some(i) => {
+                    unsafe {
+                        CURRENT = i;
+                    }
+                    enter_guest(frame)
+                }
+                None => power_off(),
+            }
+        }
+    }
+}
+
+fn next_runnable_guest() -> Option<usize> {
+    unsafe {
+        for d in 1..=MAX_GUESTS {
+            let i = (CURRENT + d) % MAX_GUESTS;
+            if let Some(guest) = &GUESTS[i] {
+                if guest.state == State::Running {
+                    return Some(i);
+                }
+            }
+        }
+    }
+    None
+}
+
+// This function prepares the current guest to start from its entrypoint when the trap handler returns.
+// It returns the entrypoint.
+fn enter_guest(frame: *mut TrapFrame) -> usize {
+    let guest = current_guest();
+    log::info!("switch to {}", guest.name);
+
+    // hgatp: set page table for guest physical address translation
+    // the TLB entries are tagged with the VMID, except for the guests sharing VMID 0
+    riscv::csr::hgatp::set(&guest.hgatp);
+    if guest.hgatp.vmid == 0 {
+        riscv::instruction::hfence_gvma();
+    }
+
+    // load the vCPU restored from the snapshot, or
+    // reset the vCPU. a0: hart ID, a1: device tree
+    let pc = match guest.resume_vcpu.take() {
+        Some(vcpu) => {
+            guest.time_delta = vcpu.load(unsafe { &mut *frame });
+            vcpu.pc
+        }
+        None => {
+            unsafe {
+                *frame = TrapFrame {
+                    regs: [0; 32],
+                    fregs: [0; 32],
+                    pc: 0,
+                };
+                (*frame).regs[11] = guest.dtb;
+            }
+            riscv::csr::vsstatus::write(0);
+            riscv::csr::vsie::write(0);
+            riscv::csr::vstvec::write(0);
+            riscv::csr::vsscratch::write(0);
+            riscv::csr::vsepc::write(0);
+            riscv::csr::vsatp::write(0);
+            riscv::csr::hvip::write(0);
+            guest.sepc
+        }
+    };
+    riscv::csr::htimedelta::write(guest.time_delta as usize);
+    riscv::csr::hstatus::set_spv(riscv::csr::VirtualzationMode::Guest);
+    riscv::csr::sstatus::set_spp(riscv::csr::CpuMode::S);
+
+    // hstatus.VGEIN: connect the guest interrupt file to the vCPU, and
+    // hgeie: let the files of the other guests raise guest external interrupts
+    riscv::csr::hstatus::set_vgein(guest.imsic_file.unwrap_or(0));
+    riscv::csr::hgeie::write(waiting_guest_files());
+
+    guest.restore_timer();
+    guest.update_interrupts();
+    pc
+}
+
+// This function returns the mask of the guest interrupt files of the guests waiting to run.
+fn waiting_guest_files() -> usize {
+    let mut mask = 0;
+    unsafe {
+        for (i, slot) in GUESTS.iter().enumerate() {
+            if let Some(guest) = slot {
+                if let (Some(file), State::Running) = (guest.imsic_file, guest.state) {
+                    if i != CURRENT {
+                        mask |= 1 << file;
+                    }
+                }
+            }
+        }
+    }
+    mask
+}
+
+// This function powers off the machine after all guests have stopped.
+fn power_off() -> ! {
+    let code = unsafe { EXIT_CODE };
+    log::info!("all guests have stopped. power off (exit code: {})", code);
+    syscon::power_off(code);
+}
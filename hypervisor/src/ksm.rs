@@ -0,0 +1,208 @@
+// Same-page merging
+/////
+// Identical pages of guests (e.g. running the same image) are merged into a single host page,
+// which is mapped read-only into their G-stage page tables (`PageTableEntryFlag::Shared`).
+// A store to a shared page raises a store guest-page fault, and the guest is given a private copy
+// (see `guest::Memory::unshare`).
+//
+// The pages are scanned a few at a time while guests are idle. A page is merged into a shared page
+// with the same content if any (the stable tree), or kept as a candidate (the unstable tree) until
+// another page with the same content is found in the same pass.
+// TODO (enhancement): skip pages which are changed frequently
+// NOTE: the heap is used for the trees, so this can be used only after `paging::init`.
+
+use crate::guest::Memory;
+use crate::memlayout::PAGE_SIZE;
+use crate::paging;
+use alloc::collections::BTreeMap;
+
+// the number of pages scanned by each `scan`
+const PAGES_PER_SCAN: usize = 64;
+
+// shared page (host physical address) -> (the number of the mappings, the hash)
+static mut SHARED: Option<BTreeMap<usize, (usize, u64)>> = None;
+// hash -> shared page
+static mut STABLE: Option<BTreeMap<u64, usize>> = None;
+// hash -> (guest, guest physical address) of the candidates in the current pass
+static mut UNSTABLE: Option<BTreeMap<u64, (usize, usize)>> = None;
+// the next page to scan: (guest, page index)
+static mut CURSOR: (usize, usize) = (0, 0);
+
+#[derive(Debug)]
+pub struct Stats {
+    // the host pages shared by guests
+    pub shared_pages: usize,
+    // the guest pages mapped to the shared pages
+    pub sharing_pages: usize,
+    // the pages saved by merging (`sharing_pages - shared_pages`)
+    pub saved_pages: usize,
+    // the number of the merges and the copies on stores
+    pub merges: usize,
+    pub breaks: usize,
+    // the number of the passes over all the guests
+    pub passes: usize,
+}
+
+static mut STATS: Stats = Stats {
+    shared_pages: 0,
+    sharing_pages: 0,
+    saved_pages: 0,
+    merges: 0,
+    breaks: 0,
+    passes: 0,
+};
+
+pub fn stats() -> &'static Stats {
+    unsafe { &STATS }
+}
+
+unsafe fn shared() -> &'static mut BTreeMap<usize, (usize, u64)> {
+    SHARED.get_or_insert_with(BTreeMap::new)
+}
+
+unsafe fn stable() -> &'static mut BTreeMap<u64, usize> {
+    STABLE.get_or_insert_with(BTreeMap::new)
+}
+
+unsafe fn unstable() -> &'static mut BTreeMap<u64, (usize, usize)> {
+    UNSTABLE.get_or_insert_with(BTreeMap::new)
+}
+
+fn page(hpa: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(hpa as *const u8, PAGE_SIZE as usize) }
+}
+
+// FNV-1a over the words of the page
+fn hash(hpa: usize) -> u64 {
+    let words = unsafe { core::slice::from_raw_parts(hpa as *const u64, PAGE_SIZE as usize / 8) };
+    words.iter().fold(0xcbf2_9ce4_8422_2325, |h, w| {
+        (h ^ w).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+// This function maps the shared page `hpa` at `gpa` of `mem`, counting the mapping.
+unsafe fn map_shared(mem: &Memory, gpa: usize, hpa: usize) -> bool {
+    if mem.share(gpa, hpa).is_err() {
+        return false;
+    }
+    if let Some((refs, _)) = shared().get_mut(&hpa) {
+        *refs += 1;
+    }
+    STATS.sharing_pages += 1;
+    STATS.saved_pages = STATS.sharing_pages - STATS.shared_pages;
+    true
+}
+
+// This function drops a mapping of the shared page `hpa`. The page is freed with the last one.
+pub fn put(hpa: usize) {
+    unsafe {
+        let (refs, hash) = match shared().get_mut(&hpa) {
+            Some(s) => {
+                s.0 -= 1;
+                *s
+            }
+            None => panic!("0x{:016x} is not a shared page", hpa),
+        };
+        STATS.sharing_pages -= 1;
+        if refs == 0 {
+            shared().remove(&hpa);
+            if stable().get(&hash) == Some(&hpa) {
+                stable().remove(&hash);
+            }
+            let page = paging::Page::from_address(paging::PhysicalAddress::new(hpa));
+            page.clear();
+            paging::free(page);
+            STATS.shared_pages -= 1;
+        }
+        STATS.saved_pages = STATS.sharing_pages - STATS.shared_pages;
+    }
+}
+
+// This function is called when a guest is given a private copy of a shared page.
+pub fn count_break() {
+    unsafe {
+        STATS.breaks += 1;
+    }
+}
+
+// This function scans the next pages of the guests (`guests[i]` is the memory of the `i`-th guest).
+pub fn scan(guests: &[Option<Memory>]) {
+    unsafe {
+        for _ in 0..PAGES_PER_SCAN {
+            let (index, gpa) = match next(guests) {
+                Some(p) => p,
+                None => return,
+            };
+            let mem = match &guests[index] {
+                Some(m) => m,
+                None => continue,
+            };
+            let hpa = match mem.private_page(gpa) {
+                Some(hpa) => hpa,
+                None => continue,
+            };
+            let hash = hash(hpa);
+
+            // a shared page with the same content
+            if let Some(&shared_hpa) = stable().get(&hash) {
+                if page(shared_hpa) == page(hpa) && map_shared(mem, gpa, shared_hpa) {
+                    STATS.merges += 1;
+                }
+                continue;
+            }
+
+            // a candidate with the same content: its page becomes a shared page
+            match unstable().get(&hash) {
+                Some(&(other_index, other_gpa)) if (other_index, other_gpa) != (index, gpa) => {
+                    let other = match guests.get(other_index) {
+                        Some(Some(m)) => m,
+                        _ => continue,
+                    };
+                    let other_hpa = match other.private_page(other_gpa) {
+                        Some(h) if page(h) == page(hpa) => h,
+                        _ => continue,
+                    };
+                    unstable().remove(&hash);
+                    shared().insert(other_hpa, (0, hash));
+                    STATS.shared_pages += 1;
+                    if !map_shared(other, other_gpa, other_hpa) {
+                        // the page is still owned by the other guest
+                        shared().remove(&other_hpa);
+                        STATS.shared_pages -= 1;
+                        continue;
+                    }
+                    stable().insert(hash, other_hpa);
+                    if map_shared(mem, gpa, other_hpa) {
+                        STATS.merges += 1;
+                    }
+                }
+                _ => {
+                    unstable().insert(hash, (index, gpa));
+                }
+            }
+        }
+    }
+}
+
+// This function returns the page to scan, and moves the cursor to the next one.
+unsafe fn next(guests: &[Option<Memory>]) -> Option<(usize, usize)> {
+    // skip the guests which do not exist or whose pages are all scanned
+    for _ in 0..=guests.len() {
+        let (index, page_index) = CURSOR;
+        match guests.get(index) {
+            Some(Some(mem)) if page_index < mem.size() / PAGE_SIZE as usize => {
+                CURSOR = (index, page_index + 1);
+                return Some((index, mem.start() + page_index * PAGE_SIZE as usize));
+            }
+            Some(_) => CURSOR = (index + 1, 0),
+            None => {
+                // the end of a pass: the candidates are compared only within a pass
+                CURSOR = (0, 0);
+                unstable().clear();
+                STATS.passes += 1;
+                log::debug!("same-page merging: {:?}", STATS);
+            }
+        }
+    }
+    None
+}
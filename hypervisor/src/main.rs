@@ -1,8 +1,9 @@
 #![no_main]
 #![cfg_attr(not(test), no_std)]
-#![feature(panic_info_message, global_asm, llvm_asm, asm)]
+#![feature(panic_info_message, global_asm, llvm_asm, asm, alloc_error_handler)]
 
 // extenal crates
+extern crate alloc;
 extern crate elf_rs;
 extern crate log;
 
@@ -11,17 +12,29 @@ extern crate log;
 pub mod uart;
 #[macro_use]
 pub mod riscv;
+pub mod aia;
 pub mod boot;
+pub mod config;
+pub mod dtb;
+pub mod heap;
+pub mod ksm;
 pub mod memlayout;
 pub mod paging;
+pub mod pci;
 pub mod plic;
+pub mod rtc;
+pub mod syscon;
 
 pub mod mkernel;
 
 pub mod guest;
 pub mod hypervisor;
+pub mod sbi;
+pub mod snapshot;
 
 pub mod debug;
 pub mod util;
 
+pub mod blockdev;
+pub mod vdev;
 pub mod virtio;
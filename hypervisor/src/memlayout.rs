@@ -4,12 +4,27 @@
 pub const PAGE_SIZE: u16 = 4096;
 pub const VIRTIO0_IRQ: u16 = 1;
 pub const UART0_IRQ: u16 = 10;
+pub const RTC_IRQ: u16 = 11;
+// INTA - INTD of the PCIe host bridge
+pub const PCIE_IRQ: u16 = 32;
+// INTA - INTD of the emulated PCIe host bridge of guests
+pub const GUEST_PCIE_IRQ: u16 = 32;
+
+// the frequency of the time CSR (QEMU virt machine)
+pub const TIMEBASE_FREQUENCY: u64 = 10_000_000;
 
 // information on hypervisor binary
 /////
 extern "C" {
     static _elf_start: usize;
     static _elf_end: usize;
+    static _mkernel_text_end: usize;
+    static _m_stack_start: usize;
+    static _m_stacks_end: usize;
+    static _text_end: usize;
+    static _rodata_end: usize;
+    static _hs_stack_start: usize;
+    static _intr_stack_start: usize;
 }
 
 pub unsafe fn elf_start() -> usize {
@@ -20,12 +35,60 @@ pub unsafe fn elf_end() -> usize {
     unsafe { &_elf_end as *const usize as usize }
 }
 
+// the end of the code and the trap frame only for M-mode (page-aligned), which start at `elf_start`
+pub unsafe fn mkernel_text_end() -> usize {
+    unsafe { &_mkernel_text_end as *const usize as usize }
+}
+
+// the range of the stacks of M-mode (page-aligned)
+pub unsafe fn m_stacks_start() -> usize {
+    unsafe { &_m_stack_start as *const usize as usize }
+}
+
+pub unsafe fn m_stacks_end() -> usize {
+    unsafe { &_m_stacks_end as *const usize as usize }
+}
+
+// the end of the text (page-aligned), which is followed by rodata
+pub unsafe fn text_end() -> usize {
+    unsafe { &_text_end as *const usize as usize }
+}
+
+// the end of rodata (page-aligned), which is followed by data and bss
+pub unsafe fn rodata_end() -> usize {
+    unsafe { &_rodata_end as *const usize as usize }
+}
+
+// the bottoms of the stacks of HS-mode (page-aligned). the page below each of them is a guard page.
+pub unsafe fn hs_stack_start() -> usize {
+    unsafe { &_hs_stack_start as *const usize as usize }
+}
+
+pub unsafe fn intr_stack_start() -> usize {
+    unsafe { &_intr_stack_start as *const usize as usize }
+}
+
 // information on hardware for hypervisor
 /////
 
 pub static UART_BASE: usize = 0x1000_0000;
 pub static VIRTIO0_BASE: usize = 0x1000_1000;
+pub static VIRTIO_MMIO_SLOTS: usize = 8;
+pub static VIRTIO_MMIO_SLOT_SIZE: usize = 0x1000;
 pub static PLIC_BASE: usize = 0x0c00_0000;
+pub static PLIC_SIZE: usize = 0x0400_0000;
+pub static CLINT_BASE: usize = 0x0200_0000;
+pub static PCIE_ECAM_BASE: usize = 0x3000_0000;
+pub static PCIE_ECAM_SIZE: usize = 0x1000_0000;
+pub static PCIE_MMIO_BASE: usize = 0x4000_0000;
+pub static PCIE_MMIO_SIZE: usize = 0x4000_0000;
+// the S-level IMSIC of hart 0, followed by its guest interrupt files (with `aia=aplic-imsic`)
+pub static IMSIC_S_BASE: usize = 0x2800_0000;
+pub static IMSIC_FILE_SIZE: usize = 0x1000;
+// the S-level interrupt file and up to 63 guest interrupt files
+pub static IMSIC_S_SIZE: usize = 0x0004_0000;
+pub static RTC_BASE: usize = 0x0010_1000;
+pub static TEST_BASE: usize = 0x0010_0000;
 
 // TODO: make this more flexible
 // This value should be page-aligned.
@@ -35,9 +98,20 @@ pub static DRAM_END: usize = 0x9000_0000;
 // information on hardware for guest
 /////
 
-pub static GUEST_UART_BASE: usize = 0x1000_0000;
+pub static GUEST_PLIC_BASE: usize = 0x0c00_0000;
+pub static GUEST_PLIC_SIZE: usize = 0x0400_0000;
+// the APLIC (supervisor-level domain) replaces the PLIC for guests on AIA platforms
+pub static GUEST_APLIC_BASE: usize = 0x0d00_0000;
+pub static GUEST_APLIC_SIZE: usize = 0x8000;
+pub static GUEST_RTC_BASE: usize = 0x0010_1000;
+pub static GUEST_RTC_SIZE: usize = 0x1000;
+pub static GUEST_TEST_BASE: usize = 0x0010_0000;
+pub static GUEST_TEST_SIZE: usize = 0x1000;
+pub static GUEST_IMSIC_BASE: usize = 0x2800_0000;
+// the emulated PCIe host bridge (ECAM for bus 0, and the 32-bit memory window for BARs)
+pub static GUEST_PCIE_ECAM_BASE: usize = 0x3000_0000;
+pub static GUEST_PCIE_ECAM_SIZE: usize = 0x0010_0000;
+pub static GUEST_PCIE_MMIO_BASE: usize = 0x4000_0000;
+pub static GUEST_PCIE_MMIO_SIZE: usize = 0x4000_0000;
 
-// TODO: make this more flexible
-// This value should be page-aligned.
-pub static GUEST_DRAM_START: usize = 0x8000_0000;
-pub static GUEST_DRAM_END: usize = 0x8200_0000;
+// NOTE: the RAM of each guest is defined in `config`.
@@ -13,6 +13,11 @@ extern "C" {
 
 #[no_mangle]
 pub extern "C" fn rust_m_entrypoint() -> ! {
+    // init logger first, so that the initialization below (e.g. PMP) can log once UART is ready.
+    if let Err(e) = util::logger::init() {
+        panic!("Failed to init logger. {:?}", e);
+    }
+
     // init hardware and M-mode registers.
     if let Err(e) = init() {
         panic!("Failed to initialize. {:?}", e);
@@ -22,10 +27,6 @@ pub extern "C" fn rust_m_entrypoint() -> ! {
     println!(" rvvisor");
     println!("-----------------------");
 
-    // init logger.
-    if let Err(e) = util::logger::init() {
-        panic!("Failed to init logger. {:?}", e);
-    }
     log::info!("logger was initialized");
 
     // jump to a next handler while changing CPU mode to HS
@@ -52,7 +53,18 @@ pub fn init() -> Result<(), Error> {
         (trap as unsafe extern "C" fn()) as usize
     );
 
-    // satp: disable paging
+    // mcounteren: allow lower privilege modes to read the counters (e.g. time)
+    riscv::csr::mcounteren::write(
+        riscv::csr::mcounteren::CY | riscv::csr::mcounteren::TM | riscv::csr::mcounteren::IR,
+    );
+
+    // menvcfg: enable stimecmp/vstimecmp if Sstc is available
+    probe_sstc();
+
+    // pmp: protect the memory of M-mode from HS-mode and guests
+    setup_pmp();
+
+    // satp: disable paging (HS-mode enables it with its own page table later)
     riscv::csr::satp::write(0x0);
 
     // leave
@@ -67,7 +79,194 @@ pub fn switch_to_hypervisor<T: util::jump::Target>(target: T) -> ! {
     riscv::instruction::mret();
 }
 
+// timer
+/////
+
+// the function ID of the M-mode service for HS-mode, passed in a7
+pub const SET_TIMER: usize = 0;
+
+const MTIMECMP_OFFSET: usize = 0x4000;
+
+static mut SSTC: bool = false;
+static mut PROBING: bool = false;
+static mut PROBE_FAILED: bool = false;
+
+// This function enables Sstc if the CPU implements it.
+// NOTE: menvcfg does not exist before the privileged spec v1.12. The access raises an illegal
+// instruction exception in that case, which is skipped by the trap handler.
+fn probe_sstc() {
+    unsafe {
+        PROBING = true;
+        PROBE_FAILED = false;
+    }
+    riscv::csr::menvcfg::write(riscv::csr::menvcfg::read() | riscv::csr::menvcfg::STCE);
+    let menvcfg = riscv::csr::menvcfg::read();
+    unsafe {
+        PROBING = false;
+        SSTC = !PROBE_FAILED && (menvcfg & riscv::csr::menvcfg::STCE) != 0;
+    }
+}
+
+// This function returns whether Sstc is available and enabled.
+pub fn has_sstc() -> bool {
+    unsafe { SSTC }
+}
+
+// PMP
+/////
+
+// This function returns the number of PMP entries implemented by the hart (0, 16 or 64).
+// NOTE: the unimplemented pmpaddr CSRs are hardwired to zero. some CPUs raise an illegal instruction
+// exception for them instead, which is skipped as in `probe_sstc`.
+fn probe_pmp_entries() -> usize {
+    let mut entries = 0;
+    unsafe {
+        PROBING = true;
+    }
+    for i in 0..64 {
+        unsafe {
+            PROBE_FAILED = false;
+        }
+        riscv::csr::pmpaddr::write(i, usize::MAX);
+        let value = riscv::csr::pmpaddr::read(i);
+        riscv::csr::pmpaddr::write(i, 0);
+        if unsafe { PROBE_FAILED } || value == 0 {
+            break;
+        }
+        entries += 1;
+    }
+    unsafe {
+        PROBING = false;
+    }
+    entries
+}
+
+// This function configures PMP so that S/U-mode can access DRAM and MMIO except the code, the trap
+// frame and the stacks of M-mode:
+//   0: TOR   [0, DRAM_START)                      RW (MMIO)
+//   1: OFF   (the base of 2)
+//   2: TOR   [elf_start, mkernel_text_end)        -
+//   3: TOR   [mkernel_text_end, rodata_end)       RX
+//   4: OFF   (the base of 5)
+//   5: TOR   [m_stacks_start, m_stacks_end)       -
+//   6: NAPOT [DRAM_START, DRAM_END)               RWX
+// the rest of the code and the read-only data (3) are not writable, since the Rust part of the
+// M-mode handlers (e.g. `rust_mtrap_handler`) and the functions they call are there.
+// NOTE: M-mode is not restricted, since the entries are not locked.
+fn setup_pmp() {
+    use riscv::csr::pmpcfg;
+
+    let entries = probe_pmp_entries();
+    log::info!("PMP entries: {}", entries);
+    if entries == 0 {
+        // every access is allowed without PMP
+        return;
+    }
+    if entries < 7 {
+        // allow everything, since S/U-mode cannot access any memory with no matching entry
+        log::info!("-> too few PMP entries; the memory of M-mode is not protected");
+        riscv::csr::pmpaddr::write(0, usize::MAX);
+        pmpcfg::set(0, pmpcfg::A_NAPOT | pmpcfg::R | pmpcfg::W | pmpcfg::X);
+        return;
+    }
+
+    let dram_size = memlayout::DRAM_END - memlayout::DRAM_START;
+    // NAPOT: the size (a power of 2) is encoded in the trailing ones
+    let dram_napot = (memlayout::DRAM_START | (dram_size / 2 - 1)) >> 2;
+    let regions = unsafe {
+        [
+            (
+                memlayout::DRAM_START >> 2,
+                pmpcfg::A_TOR | pmpcfg::R | pmpcfg::W,
+            ),
+            (memlayout::elf_start() >> 2, pmpcfg::A_OFF),
+            (memlayout::mkernel_text_end() >> 2, pmpcfg::A_TOR),
+            (
+                memlayout::rodata_end() >> 2,
+                pmpcfg::A_TOR | pmpcfg::R | pmpcfg::X,
+            ),
+            (memlayout::m_stacks_start() >> 2, pmpcfg::A_OFF),
+            (memlayout::m_stacks_end() >> 2, pmpcfg::A_TOR),
+            (
+                dram_napot,
+                pmpcfg::A_NAPOT | pmpcfg::R | pmpcfg::W | pmpcfg::X,
+            ),
+        ]
+    };
+    for (i, (addr, cfg)) in regions.iter().enumerate() {
+        riscv::csr::pmpaddr::write(i, *addr);
+        pmpcfg::set(i, *cfg);
+    }
+}
+
+// This function is called from HS-mode to raise the supervisor timer interrupt at `time`.
+// The pending interrupt is cleared at the same time.
+// NOTE: this is used only when Sstc is not available.
+pub fn set_timer(time: u64) {
+    unsafe {
+        asm!("ecall", in("a7") SET_TIMER, in("a0") time);
+    }
+}
+
+fn write_mtimecmp(time: u64) {
+    unsafe {
+        ((memlayout::CLINT_BASE + MTIMECMP_OFFSET) as *mut u64).write_volatile(time);
+    }
+}
+
 #[no_mangle]
-pub extern "C" fn rust_mtrap_handler() {
-    log::info!("trapped to M-mode!");
+pub extern "C" fn rust_mtrap_handler(
+    mepc: usize,                       // a0
+    mtval: usize,                      // a1
+    mcause: usize,                     // a2
+    mstatus: usize,                    // a3
+    frame: *mut hypervisor::TrapFrame, // a4
+) -> usize {
+    let is_async = mcause >> 63 & 1 == 1;
+    let cause_code = mcause & 0xfff;
+    if is_async {
+        match cause_code {
+            // machine timer interrupt: pass it to HS-mode
+            7 => {
+                write_mtimecmp(u64::MAX);
+                riscv::csr::mie::write(riscv::csr::mie::read() & !riscv::csr::mie::MTIE);
+                riscv::csr::mip::write(riscv::csr::mip::read() | riscv::csr::mip::STIP);
+                mepc
+            }
+            _ => {
+                panic!("unexpected interrupt in M-mode: {}", cause_code);
+            }
+        }
+    } else {
+        match cause_code {
+            // illegal instruction while probing CSRs
+            2 if unsafe { PROBING } => {
+                unsafe {
+                    PROBE_FAILED = true;
+                }
+                mepc + 4
+            }
+            // environment call from HS-mode
+            9 => {
+                let (function, arg0) = unsafe { ((*frame).regs[17], (*frame).regs[10]) };
+                match function {
+                    SET_TIMER => {
+                        riscv::csr::mip::write(riscv::csr::mip::read() & !riscv::csr::mip::STIP);
+                        write_mtimecmp(arg0 as u64);
+                        riscv::csr::mie::write(riscv::csr::mie::read() | riscv::csr::mie::MTIE);
+                    }
+                    _ => {
+                        log::info!("unknown M-mode service: {}", function);
+                    }
+                }
+                mepc + 4
+            }
+            _ => {
+                panic!(
+                    "unexpected exception in M-mode: cause={}, mepc=0x{:016x}, mtval=0x{:016x}, mstatus=0x{:016x}",
+                    cause_code, mepc, mtval, mstatus
+                );
+            }
+        }
+    }
 }
@@ -2,7 +2,139 @@
 // if we run more rich guest OS or add more rich features to hypervisor,
 // we need to refine this implmentation :-D
 
-use crate::memlayout::{elf_end, DRAM_END, PAGE_SIZE};
+use crate::ksm;
+use crate::memlayout;
+use crate::memlayout::{elf_end, DRAM_END, DRAM_START, PAGE_SIZE};
+use crate::riscv;
+use core::fmt::{Error, Write};
+
+// Translation modes
+/////
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Mode {
+    // G-stage translation (hgatp): the root page table is 16 KiB, and its index has 2 extra bits
+    Sv39x4,
+    Sv48x4,
+    Sv57x4,
+    // translation of HS-mode (satp)
+    Sv39,
+}
+
+impl Mode {
+    pub fn levels(&self) -> usize {
+        match self {
+            Mode::Sv39x4 | Mode::Sv39 => 3,
+            Mode::Sv48x4 => 4,
+            Mode::Sv57x4 => 5,
+        }
+    }
+
+    // the number of bits of an index in the root page table
+    fn root_index_bits(&self) -> usize {
+        match self {
+            Mode::Sv39 => 9,
+            _ => 9 + 2,
+        }
+    }
+
+    // the order of the pages of the root page table
+    pub fn root_order(&self) -> usize {
+        match self {
+            Mode::Sv39 => 0,
+            _ => 2,
+        }
+    }
+
+    // the width of the addresses which can be translated
+    pub fn address_bits(&self) -> usize {
+        12 + 9 * (self.levels() - 1) + self.root_index_bits()
+    }
+
+    pub fn hgatp_mode(&self) -> riscv::csr::hgatp::Mode {
+        match self {
+            Mode::Sv39x4 => riscv::csr::hgatp::Mode::Sv39x4,
+            Mode::Sv48x4 => riscv::csr::hgatp::Mode::Sv48x4,
+            Mode::Sv57x4 => riscv::csr::hgatp::Mode::Sv57x4,
+            Mode::Sv39 => panic!("{:?} is not a G-stage translation mode", self),
+        }
+    }
+}
+
+const GSTAGE_MODES: [Mode; 3] = [Mode::Sv39x4, Mode::Sv48x4, Mode::Sv57x4];
+// whether each of `GSTAGE_MODES` is supported by the hart
+static mut GSTAGE_SUPPORTED: [bool; 3] = [false; 3];
+
+// This function detects the G-stage translation modes and VMIDs supported by the hart.
+// NOTE: a write of an unsupported mode to hgatp has no effect.
+pub fn probe_gstage_modes() {
+    unsafe {
+        for (i, mode) in GSTAGE_MODES.iter().enumerate() {
+            riscv::csr::hgatp::set(&riscv::csr::hgatp::Setting::new(mode.hgatp_mode(), 0, 0));
+            GSTAGE_SUPPORTED[i] = riscv::csr::hgatp::read() >> 60 == mode.hgatp_mode() as usize;
+            riscv::csr::hgatp::write(0);
+        }
+        probe_vmid();
+        log::info!(
+            "G-stage translation modes: {:?}",
+            GSTAGE_MODES
+                .iter()
+                .zip(GSTAGE_SUPPORTED.iter())
+                .filter(|(_, supported)| **supported)
+                .map(|(mode, _)| mode)
+                .collect::<alloc::vec::Vec<_>>()
+        );
+    }
+}
+
+// VMIDs: 1 - 63 (within VMIDLEN) are given to guests. 0 is shared by the rest.
+static mut VMID_MAX: u16 = 0;
+static mut VMIDS_USED: u64 = 0;
+
+// This function detects the number of VMIDs supported by the hart (VMIDLEN).
+fn probe_vmid() {
+    let mode = riscv::csr::hgatp::Mode::Sv39x4;
+    riscv::csr::hgatp::set(&riscv::csr::hgatp::Setting::new(mode, 0x3fff, 0));
+    let vmid = (riscv::csr::hgatp::read() >> 44) & 0x3fff;
+    riscv::csr::hgatp::write(0);
+    unsafe {
+        VMID_MAX = core::cmp::min(vmid, 63) as u16;
+        log::info!("VMIDs: 1 - {}", VMID_MAX);
+    }
+}
+
+// This function assigns a VMID to a guest. It returns 0 (shared) if no VMID is left.
+pub fn alloc_vmid() -> u16 {
+    unsafe {
+        for vmid in 1..=VMID_MAX {
+            if VMIDS_USED & (1 << vmid) == 0 {
+                VMIDS_USED |= 1 << vmid;
+                return vmid;
+            }
+        }
+    }
+    0
+}
+
+// This function gives back `vmid`. The TLB entries of the VMID are flushed before it is reused.
+pub fn free_vmid(vmid: u16) {
+    riscv::instruction::hfence_gvma_vmid(vmid);
+    unsafe {
+        VMIDS_USED &= !(1 << vmid);
+    }
+}
+
+// This function returns the smallest G-stage translation mode supported by the hart which can
+// translate [0, end).
+pub fn gstage_mode_for(end: usize) -> Option<Mode> {
+    unsafe {
+        GSTAGE_MODES
+            .iter()
+            .zip(GSTAGE_SUPPORTED.iter())
+            .find(|(mode, supported)| **supported && (end - 1) >> mode.address_bits() == 0)
+            .map(|(mode, _)| *mode)
+    }
+}
 
 // VirtualAddress
 /////
@@ -17,16 +149,18 @@ impl VirtualAddress {
         VirtualAddress { addr: addr }
     }
 
-    pub fn to_vpn(&self) -> [usize; 3] {
-        [
-            (self.addr >> 12) & 0x1ff,
-            (self.addr >> 21) & 0x1ff,
-            (self.addr >> 30) & 0x1ff,
-        ]
+    // the index in the page table of `level` (0 is the leaf)
+    pub fn to_vpn(&self, level: usize, mode: Mode) -> usize {
+        let bits = if level == mode.levels() - 1 {
+            mode.root_index_bits()
+        } else {
+            9
+        };
+        (self.addr >> (12 + 9 * level)) & ((1 << bits) - 1)
     }
 
     pub fn to_offset(&self) -> usize {
-        self.addr & 0x3ff
+        self.addr & 0xfff
     }
 
     pub fn to_usize(&self) -> usize {
@@ -99,78 +233,287 @@ impl Page {
     }
 }
 
-// Page Allocator (soooo tiny version)
+// Page Allocator
 /////
+// A buddy allocator over the DRAM after the hypervisor image.
+// Memory is handed out in blocks of 2^order pages, which are aligned to their size.
+// Free blocks are kept in a doubly-linked list per order, whose links are stored in the first
+// page of each block (the next and the previous block).
+
+pub const MAX_ORDER: usize = 14;
+// the number of pages which can be managed from DRAM_START (256 MiB)
+const MAX_PAGES: usize = 0x1_0000;
+
+// the state of the block which starts at a page (0 if no block starts there)
+const BLOCK_FREE: u8 = 0x80;
+const BLOCK_USED: u8 = 0x40;
+const BLOCK_ORDER: u8 = 0x3f;
+
+static mut FREE_LISTS: [usize; MAX_ORDER + 1] = [0; MAX_ORDER + 1];
+static mut BLOCKS: [u8; MAX_PAGES] = [0; MAX_PAGES];
+// the range of the managed memory
+static mut START: usize = 0;
+static mut END: usize = 0;
+static mut INITIALIZED: bool = false;
+
+#[derive(Debug)]
+pub struct Stats {
+    pub total_pages: usize,
+    pub free_pages: usize,
+    // the number of free blocks of each order
+    pub free_blocks: [usize; MAX_ORDER + 1],
+}
+
+fn block_size(order: usize) -> usize {
+    (PAGE_SIZE as usize) << order
+}
+
+unsafe fn block_state(addr: usize) -> &'static mut u8 {
+    &mut BLOCKS[(addr - DRAM_START) / (PAGE_SIZE as usize)]
+}
+
+unsafe fn push(addr: usize, order: usize) {
+    let next = FREE_LISTS[order];
+    let links = addr as *mut usize;
+    links.write(next);
+    links.add(1).write(0);
+    if next != 0 {
+        (next as *mut usize).add(1).write(addr);
+    }
+    FREE_LISTS[order] = addr;
+    *block_state(addr) = BLOCK_FREE | order as u8;
+}
+
+unsafe fn remove(addr: usize, order: usize) {
+    let links = addr as *mut usize;
+    let next = links.read();
+    let prev = links.add(1).read();
+    if prev != 0 {
+        (prev as *mut usize).write(next);
+    } else {
+        FREE_LISTS[order] = next;
+    }
+    if next != 0 {
+        (next as *mut usize).add(1).write(prev);
+    }
+    *block_state(addr) = 0;
+}
 
-static mut base_addr: usize = 0;
-static mut last_index: usize = 0;
-static mut initialized: bool = false;
+// This function returns the free block (address, order) which contains `addr`, if any.
+unsafe fn free_block_of(addr: usize) -> Option<(usize, usize)> {
+    for order in 0..=MAX_ORDER {
+        let head = addr & !(block_size(order) - 1);
+        if head >= START && *block_state(head) == BLOCK_FREE | order as u8 {
+            return Some((head, order));
+        }
+    }
+    None
+}
+
+fn check_initialized() {
+    unsafe {
+        if !INITIALIZED {
+            panic!("page manager was used but not initialized");
+        }
+    }
+}
 
 pub fn init() {
     unsafe {
-        base_addr = (elf_end() & !(0xfff as usize)) + 4096;
-        last_index = 0;
-        initialized = true;
+        let page_size = PAGE_SIZE as usize;
+        START = (elf_end() + page_size - 1) & !(page_size - 1);
+        END = core::cmp::min(DRAM_END, DRAM_START + MAX_PAGES * page_size);
+        FREE_LISTS = [0; MAX_ORDER + 1];
+
+        // split the range into the largest aligned blocks.
+        // they are pushed from the end, so that lower addresses are used first.
+        let mut addr = END;
+        while addr > START {
+            let mut order = MAX_ORDER;
+            while addr % block_size(order) != 0 || addr - START < block_size(order) {
+                order -= 1;
+            }
+            addr -= block_size(order);
+            push(addr, order);
+        }
+        INITIALIZED = true;
     }
+    log::info!("page allocator: {:?}", stats());
 }
 
-pub fn set_alloc_base(addr: usize) {
+// This function allocates 2^`order` continuous pages aligned to their size.
+// The pages are cleared.
+pub fn alloc_order(order: usize) -> Result<Page, Error> {
+    check_initialized();
+    if order > MAX_ORDER {
+        return Err(Error);
+    }
     unsafe {
-        base_addr = addr;
+        let mut o = order;
+        while FREE_LISTS[o] == 0 {
+            o += 1;
+            if o > MAX_ORDER {
+                return Err(Error);
+            }
+        }
+        let addr = FREE_LISTS[o];
+        remove(addr, o);
+        // split the block, keeping the lower half
+        while o > order {
+            o -= 1;
+            push(addr + block_size(o), o);
+        }
+        *block_state(addr) = BLOCK_USED | order as u8;
+
+        for i in 0..(1 << order) {
+            Page::from_address(PhysicalAddress::new(addr + i * (PAGE_SIZE as usize))).clear();
+        }
+        Ok(Page::from_address(PhysicalAddress::new(addr)))
+    }
+}
+
+pub fn alloc() -> Result<Page, Error> {
+    alloc_order(0)
+}
+
+// This function allocates `num` continuous pages.
+// NOTE: `num` is rounded up to a power of 2, and all of them are freed at once by `free`.
+pub fn alloc_continuous(num: usize) -> Result<Page, Error> {
+    if num == 0 {
+        panic!("invalid arg for alloc_continuous: {}", num);
     }
+    let order = num.next_power_of_two().trailing_zeros() as usize;
+    alloc_order(order)
 }
 
-pub fn alloc() -> Page {
-    // TODO: this unsafe block is evil!
+// This function gives back the block which starts at `page`.
+pub fn free(page: Page) {
+    check_initialized();
     unsafe {
-        if !initialized {
-            panic!("page manager was used but not initialized");
+        let mut addr = page.address().to_usize();
+        if addr < START || addr >= END || *block_state(addr) & BLOCK_USED == 0 {
+            panic!("invalid free of a page: 0x{:016x}", addr);
         }
+        let mut order = (*block_state(addr) & BLOCK_ORDER) as usize;
+        *block_state(addr) = 0;
 
-        last_index += 1;
-        let addr = base_addr + (PAGE_SIZE as usize) * (last_index - 1);
-        if addr > DRAM_END {
-            panic!("memory exhausted; 0x{:016x}", addr)
+        // merge with the buddy while it is free
+        while order < MAX_ORDER {
+            let buddy = addr ^ block_size(order);
+            if buddy < START || buddy >= END || *block_state(buddy) != BLOCK_FREE | order as u8 {
+                break;
+            }
+            remove(buddy, order);
+            addr = core::cmp::min(addr, buddy);
+            order += 1;
         }
-        let p = Page::from_address(PhysicalAddress::new(addr));
-        p.clear();
-        p
+        push(addr, order);
     }
 }
 
-pub fn alloc_16() -> Page {
-    let mut root_page = alloc();
-    while root_page.address().to_usize() & (0b11_1111_1111_1111 as usize) > 0 {
-        log::debug!(
-            "a page 0x{:016x} was allocated, but it does not follow 16KiB boundary. drop.",
-            root_page.address().to_usize()
-        );
-        root_page = alloc();
+// This function takes [start, start + size) out of the free memory, so that it can be handed to
+// someone as it is (e.g. guest RAM which must be identity-mapped).
+pub fn reserve(start: usize, size: usize) -> Result<Page, Error> {
+    check_initialized();
+    let page_size = PAGE_SIZE as usize;
+    if start % page_size != 0 || size % page_size != 0 || size == 0 {
+        return Err(Error);
     }
-    alloc();
-    alloc();
-    alloc();
-    root_page
+    unsafe {
+        let end = start.checked_add(size).ok_or(Error)?;
+        if start < START || end > END {
+            return Err(Error);
+        }
+        // the whole range must be free
+        let mut addr = start;
+        while addr < end {
+            match free_block_of(addr) {
+                Some((head, order)) => addr = head + block_size(order),
+                None => return Err(Error),
+            }
+        }
+
+        // take the largest aligned pieces out of the free blocks
+        let mut addr = start;
+        while addr < end {
+            let (mut head, mut o) = free_block_of(addr).ok_or(Error)?;
+            let mut order = o;
+            while addr % block_size(order) != 0 || addr + block_size(order) > end {
+                order -= 1;
+            }
+            remove(head, o);
+            while o > order {
+                o -= 1;
+                let half = block_size(o);
+                if addr >= head + half {
+                    push(head, o);
+                    head += half;
+                } else {
+                    push(head + half, o);
+                }
+            }
+            *block_state(addr) = BLOCK_USED | order as u8;
+            addr += block_size(order);
+        }
+    }
+    Ok(Page::from_address(PhysicalAddress::new(start)))
 }
 
-pub fn alloc_continuous(num: usize) -> Page {
-    if num <= 0 {
-        panic!("invalid arg for alloc_contenious: {}", num);
+// This function gives back [start, start + size) taken by `reserve`.
+pub fn release(start: usize, size: usize) {
+    check_initialized();
+    let mut addr = start;
+    while addr < start + size {
+        let state = unsafe { *block_state(addr) };
+        if state & BLOCK_USED == 0 {
+            panic!("invalid release of a page: 0x{:016x}", addr);
+        }
+        free(Page::from_address(PhysicalAddress::new(addr)));
+        addr += block_size((state & BLOCK_ORDER) as usize);
     }
+}
 
-    let first = alloc();
-    for _ in 0..(num - 1) {
-        let _ = alloc();
+// This function turns the allocated block at `page` into single pages, so that they can be freed
+// one by one (e.g. when a superpage is split).
+pub fn split_block(page: &Page) {
+    check_initialized();
+    unsafe {
+        let addr = page.address().to_usize();
+        if addr < START || addr >= END || *block_state(addr) & BLOCK_USED == 0 {
+            panic!("invalid split of a block: 0x{:016x}", addr);
+        }
+        let order = (*block_state(addr) & BLOCK_ORDER) as usize;
+        for i in 0..(1 << order) {
+            *block_state(addr + i * (PAGE_SIZE as usize)) = BLOCK_USED;
+        }
     }
+}
 
-    first
+pub fn stats() -> Stats {
+    let mut stats = Stats {
+        total_pages: 0,
+        free_pages: 0,
+        free_blocks: [0; MAX_ORDER + 1],
+    };
+    unsafe {
+        stats.total_pages = (END - START) / (PAGE_SIZE as usize);
+        for order in 0..=MAX_ORDER {
+            let mut addr = FREE_LISTS[order];
+            while addr != 0 {
+                stats.free_blocks[order] += 1;
+                stats.free_pages += 1 << order;
+                addr = (addr as *const usize).read();
+            }
+        }
+    }
+    stats
 }
 
 // Page Table
 /////
 
 #[derive(Debug)]
-struct PageTableEntry {
+pub struct PageTableEntry {
     pub ppn: [usize; 3],
     pub flags: u16,
 }
@@ -184,15 +527,25 @@ pub enum PageTableEntryFlag {
     Global = 1 << 5,
     Access = 1 << 6,
     Dirty = 1 << 7,
-    // TODO (enhancement): RSW
+    // (RSW) the page of the leaf was allocated for the table, and is freed with it
+    Owned = 1 << 8,
+    // (RSW) the page of the leaf is shared by the tables (see `ksm`). it is read-only.
+    Shared = 1 << 9,
 }
 
+// the permissions of a leaf
+pub const PERM_MASK: u16 = (PageTableEntryFlag::Read as u16)
+    | (PageTableEntryFlag::Write as u16)
+    | (PageTableEntryFlag::Execute as u16)
+    | (PageTableEntryFlag::User as u16);
+
 impl PageTableEntry {
     pub fn from_value(v: usize) -> PageTableEntry {
         let ppn = [(v >> 10) & 0x1ff, (v >> 19) & 0x1ff, (v >> 28) & 0x3ff_ffff];
         PageTableEntry {
             ppn: ppn,
-            flags: (v & (0x1ff as usize)) as u16,
+            // the flags include RSW (`Owned` and `Shared`)
+            flags: (v & (0x3ff as usize)) as u16,
         }
     }
 
@@ -226,16 +579,29 @@ impl PageTableEntry {
     pub fn is_valid(&self) -> bool {
         self.flags & (PageTableEntryFlag::Valid as u16) != 0
     }
+
+    // whether the permissions are valid (W without R is reserved)
+    pub fn is_well_formed(&self) -> bool {
+        let read = self.flags & (PageTableEntryFlag::Read as u16) != 0;
+        let write = self.flags & (PageTableEntryFlag::Write as u16) != 0;
+        read || !write
+    }
+
+    // whether the entry maps a page (or a superpage) instead of pointing to the next table
+    pub fn is_leaf(&self) -> bool {
+        self.flags
+            & ((PageTableEntryFlag::Read as u16)
+                | (PageTableEntryFlag::Write as u16)
+                | (PageTableEntryFlag::Execute as u16))
+            != 0
+    }
 }
 
 pub struct PageTable {
     pub page: Page,
+    pub mode: Mode,
 }
 
-// TODO (enhancement): this naming is not so good.
-// This implementation assumes the paging would be done with Sv39,
-// but the word 'page table" is a more general idea.
-// We can rename this like `Sv39PageTable` or change the implementation in a more polymorphic way.
 impl PageTable {
     fn set_entry(&self, i: usize, entry: PageTableEntry) {
         let ptr = self.page.address().as_pointer() as *mut usize;
@@ -247,39 +613,176 @@ impl PageTable {
         unsafe { PageTableEntry::from_value(ptr.add(i).read()) }
     }
 
-    pub fn from_page(page: Page) -> PageTable {
-        PageTable { page: page }
+    pub fn from_page(page: Page, mode: Mode) -> PageTable {
+        PageTable {
+            page: page,
+            mode: mode,
+        }
+    }
+
+    pub fn resolve(&self, vaddr: &VirtualAddress) -> Result<PhysicalAddress, WalkError> {
+        let (entry, level) = self.leaf(vaddr)?;
+        // the leaf may be a superpage
+        let addr_base = entry.next_page().address().to_usize();
+        Ok(PhysicalAddress::new(
+            addr_base | (vaddr.addr & (level_size(level) - 1)),
+        ))
+    }
+
+    // This function returns the leaf which maps `vaddr` and its level.
+    pub fn leaf(&self, vaddr: &VirtualAddress) -> Result<(PageTableEntry, usize), WalkError> {
+        let (pt, index, level) = self.find_leaf(vaddr)?;
+        Ok((pt.get_entry(index), level))
+    }
+
+    // This function returns the table which has the leaf mapping `vaddr`, the index of the leaf,
+    // and its level.
+    fn find_leaf(&self, vaddr: &VirtualAddress) -> Result<(PageTable, usize, usize), WalkError> {
+        if vaddr.addr >> self.mode.address_bits() != 0 {
+            return Err(WalkError::OutOfRange);
+        }
+        let mut pt = PageTable::from_page(Page::from_address(self.page.address()), self.mode);
+        let mut level = self.mode.levels() - 1;
+        loop {
+            let index = vaddr.to_vpn(level, self.mode);
+            let entry = pt.get_entry(index);
+            if !entry.is_valid() {
+                return Err(WalkError::NotMapped(level));
+            }
+
+            if entry.is_leaf() {
+                let addr_base = entry.next_page().address().to_usize();
+                if !entry.is_well_formed() || addr_base % level_size(level) != 0 {
+                    return Err(WalkError::Malformed(level));
+                }
+                return Ok((pt, index, level));
+            } else if level == 0 {
+                // a pointer to the next table in the last level
+                return Err(WalkError::Malformed(level));
+            }
+            pt = PageTable::from_page(entry.next_page(), self.mode);
+            level -= 1;
+        }
+    }
+
+    // This function sets the Dirty bit of the writable leaf which maps `vaddr`, as the hardware
+    // does on a store. It returns whether the bit was clear.
+    // NOTE: without the hardware update of the A/D bits, a store to a page whose Dirty bit is
+    // clear raises a page fault.
+    pub fn set_dirty(&self, vaddr: &VirtualAddress) -> Result<bool, WalkError> {
+        let (pt, index, _) = self.find_leaf(vaddr)?;
+        let mut entry = pt.get_entry(index);
+        let dirty = PageTableEntryFlag::Dirty as u16;
+        if entry.flags & (PageTableEntryFlag::Write as u16) == 0 || entry.flags & dirty != 0 {
+            return Ok(false);
+        }
+        entry.flags |= dirty;
+        pt.set_entry(index, entry);
+        Ok(true)
     }
 
-    pub fn resolve(&self, vaddr: &VirtualAddress) -> PhysicalAddress {
-        self.resolve_intl(vaddr, self, 2)
+    // This function clears the Dirty bits of the leaves in [vaddr, vaddr + size), and calls `f`
+    // with the address of each page (in the range) whose leaf was dirty.
+    // NOTE: the TLBs must be flushed if the page table is in use.
+    pub fn take_dirty<F: FnMut(usize)>(&self, vaddr: usize, size: usize, mut f: F) {
+        let end = match vaddr.checked_add(size) {
+            Some(end) => end,
+            None => return,
+        };
+        let root = PageTable::from_page(Page::from_address(self.page.address()), self.mode);
+        self.take_dirty_intl(&root, self.mode.levels() - 1, 0, vaddr, end, &mut f);
     }
 
-    fn resolve_intl(
+    // `base` is the address mapped by the first entry of `pt`.
+    fn take_dirty_intl<F: FnMut(usize)>(
         &self,
-        vaddr: &VirtualAddress,
         pt: &PageTable,
         level: usize,
-    ) -> PhysicalAddress {
-        let vpn = vaddr.to_vpn();
+        base: usize,
+        start: usize,
+        end: usize,
+        f: &mut F,
+    ) {
+        let size = level_size(level);
+        let from = core::cmp::max(start, base);
+        let to = core::cmp::min(end, base + self.entries(level) * size);
+        if from >= to {
+            return;
+        }
+        let dirty = PageTableEntryFlag::Dirty as u16;
+        for index in (from - base) / size..=(to - 1 - base) / size {
+            let entry_base = base + index * size;
+            let mut entry = pt.get_entry(index);
+            if !entry.is_valid() {
+                continue;
+            }
+            if entry.is_leaf() || level == 0 {
+                if entry.flags & dirty != 0 {
+                    entry.flags &= !dirty;
+                    pt.set_entry(index, entry);
+                    // every page of a dirty superpage is reported
+                    let page_size = PAGE_SIZE as usize;
+                    let first = core::cmp::max(entry_base, start);
+                    let last = core::cmp::min(entry_base + size, end);
+                    for addr in (first - first % page_size..last).step_by(page_size) {
+                        f(addr);
+                    }
+                }
+                continue;
+            }
+            let child = PageTable::from_page(entry.next_page(), self.mode);
+            self.take_dirty_intl(&child, level - 1, entry_base, start, end, f);
+        }
+    }
 
-        let entry = pt.get_entry(vpn[level]);
-        if !entry.is_valid() {
-            panic!("failed to resolve vaddr: 0x{:016x}", vaddr.addr)
+    // This function returns the iterator over the mapped ranges (see `Mapping`).
+    pub fn mappings(&self) -> Mappings {
+        let mut stack = [(0, 0, 0); 5];
+        stack[0] = (self.page.address().to_usize(), 0, 0);
+        Mappings {
+            mode: self.mode,
+            stack: stack,
+            depth: 1,
+            pending: None,
         }
+    }
 
-        if level == 0 {
-            let addr_base = entry.next_page().address().to_usize();
-            PhysicalAddress::new(addr_base | vaddr.to_offset())
-        } else {
-            let next_page = entry.next_page();
-            let new_pt = PageTable::from_page(next_page);
-            self.resolve_intl(vaddr, &new_pt, level - 1)
+    // This function prints the mappings (e.g. to the console or to a buffer for logs).
+    pub fn dump<W: Write>(&self, w: &mut W) -> core::fmt::Result {
+        writeln!(
+            w,
+            "page table at 0x{:016x} ({:?})",
+            self.page.address().to_usize(),
+            self.mode
+        )?;
+        for m in self.mappings() {
+            writeln!(w, "  {}", m)?;
         }
+        Ok(())
     }
 
-    pub fn map(&self, vaddr: VirtualAddress, dest: &Page, perm: u16) {
-        self.map_intl(vaddr, dest, self, perm, 2)
+    pub fn map(&self, vaddr: VirtualAddress, dest: &Page, perm: u16) -> Result<(), Error> {
+        self.map_superpage(vaddr, dest, perm, 0)
+    }
+
+    // This function maps a superpage of `level` (1: 2 MiB, 2: 1 GiB, ...) at `vaddr`.
+    // Both `vaddr` and `dest` must be aligned to the size of the superpage.
+    pub fn map_superpage(
+        &self,
+        vaddr: VirtualAddress,
+        dest: &Page,
+        perm: u16,
+        level: usize,
+    ) -> Result<(), Error> {
+        let size = level_size(level);
+        if level >= self.mode.levels()
+            || vaddr.addr >> self.mode.address_bits() != 0
+            || vaddr.addr % size != 0
+            || dest.address().to_usize() % size != 0
+        {
+            return Err(Error);
+        }
+        self.map_intl(vaddr, dest, self, perm, self.mode.levels() - 1, level)
     }
 
     fn map_intl(
@@ -289,10 +792,16 @@ impl PageTable {
         pt: &PageTable,
         perm: u16,
         level: usize,
-    ) {
-        let vpn = vaddr.to_vpn();
+        target: usize,
+    ) -> Result<(), Error> {
+        let index = vaddr.to_vpn(level, self.mode);
 
-        if level == 0 {
+        if level == target {
+            // a table can not be replaced with a leaf (it may map some pages)
+            let old = pt.get_entry(index);
+            if old.is_valid() && !old.is_leaf() {
+                return Err(Error);
+            }
             // register `dest`  addr
             let new_entry = PageTableEntry::from_value(
                 ((dest.address().to_usize() as i64 >> 2) as usize)
@@ -301,25 +810,443 @@ impl PageTable {
                     | (PageTableEntryFlag::Access as usize)
                     | (perm as usize),
             );
-            pt.set_entry(vpn[0], new_entry);
+            pt.set_entry(index, new_entry);
+            Ok(())
         } else {
             // walk the page table
-            let entry = pt.get_entry(vpn[level]);
-            if !entry.is_valid() {
+            let entry = pt.get_entry(index);
+            let next_page = if !entry.is_valid() {
                 // if no entry found, create new page and assign it.
-                let new_page = alloc();
+                let new_page = alloc()?;
                 let new_entry = PageTableEntry::from_value(
                     ((new_page.address().to_usize() as i64 >> 2) as usize)
                         | (PageTableEntryFlag::Valid as usize),
                 );
-                pt.set_entry(vpn[level], new_entry);
-                let new_pt = PageTable::from_page(new_page);
-                self.map_intl(vaddr, dest, &new_pt, perm, level - 1);
+                pt.set_entry(index, new_entry);
+                new_page
+            } else if entry.is_leaf() {
+                // a part of the superpage is mapped in a different way
+                self.split_entry(pt, index, level)?
+            } else {
+                entry.next_page()
+            };
+            let new_pt = PageTable::from_page(next_page, self.mode);
+            self.map_intl(vaddr, dest, &new_pt, perm, level - 1, target)
+        }
+    }
+
+    // This function splits the superpages which contain `vaddr` down to a 4 KiB leaf, so that the
+    // page can have its own permissions.
+    // NOTE: the TLBs must be flushed if the page table is in use.
+    pub fn split(&self, vaddr: &VirtualAddress) -> Result<(), Error> {
+        if vaddr.addr >> self.mode.address_bits() != 0 {
+            return Err(Error);
+        }
+        let mut level = self.mode.levels() - 1;
+        let mut pt = PageTable::from_page(Page::from_address(self.page.address()), self.mode);
+        while level > 0 {
+            let index = vaddr.to_vpn(level, self.mode);
+            let entry = pt.get_entry(index);
+            if !entry.is_valid() {
+                return Err(Error);
+            }
+            let next_page = if entry.is_leaf() {
+                self.split_entry(&pt, index, level)?
+            } else {
+                entry.next_page()
+            };
+            pt = PageTable::from_page(next_page, self.mode);
+            level -= 1;
+        }
+        Ok(())
+    }
+
+    // This function replaces the superpage at `index` of `pt` (in `level`) with a table of the
+    // leaves of the next level, which have the same permissions.
+    // It returns the page of the new table.
+    fn split_entry(&self, pt: &PageTable, index: usize, level: usize) -> Result<Page, Error> {
+        let entry = pt.get_entry(index);
+        let base = entry.next_page().address().to_usize();
+        let table = alloc()?;
+        if entry.flags & (PageTableEntryFlag::Owned as u16) != 0 {
+            split_block(&entry.next_page());
+        }
+        let child = PageTable::from_page(Page::from_address(table.address()), self.mode);
+        for i in 0..512 {
+            let addr = base + i * level_size(level - 1);
+            child.set_entry(
+                i,
+                PageTableEntry::from_value(((addr as i64 >> 2) as usize) | entry.flags as usize),
+            );
+        }
+        pt.set_entry(
+            index,
+            PageTableEntry::from_value(
+                ((table.address().to_usize() as i64 >> 2) as usize)
+                    | (PageTableEntryFlag::Valid as usize),
+            ),
+        );
+        Ok(table)
+    }
+
+    // the number of entries of a table in `level`
+    fn entries(&self, level: usize) -> usize {
+        if level == self.mode.levels() - 1 {
+            1 << self.mode.root_index_bits()
+        } else {
+            512
+        }
+    }
+
+    // This function removes the mappings in [vaddr, vaddr + size).
+    // The pages owned by the table are cleared and freed.
+    // NOTE: the TLBs must be flushed if the page table is in use.
+    pub fn unmap(&self, vaddr: usize, size: usize) -> Result<(), Error> {
+        self.update_range(vaddr, size, None)
+    }
+
+    // This function changes the permissions (R/W/X/U) of the mappings in [vaddr, vaddr + size).
+    // Superpages are split if only a part of them is changed.
+    // NOTE: the TLBs must be flushed if the page table is in use.
+    pub fn protect(&self, vaddr: usize, size: usize, perm: u16) -> Result<(), Error> {
+        let read = PageTableEntryFlag::Read as u16;
+        let write = PageTableEntryFlag::Write as u16;
+        let execute = PageTableEntryFlag::Execute as u16;
+        // a leaf must be readable or executable (W without R is reserved)
+        if perm & (read | execute) == 0 || (perm & write != 0 && perm & read == 0) {
+            return Err(Error);
+        }
+        self.update_range(vaddr, size, Some(perm & PERM_MASK))
+    }
+
+    // This function unmaps (`perm` is `None`) or changes the permissions of [vaddr, vaddr + size).
+    fn update_range(&self, vaddr: usize, size: usize, perm: Option<u16>) -> Result<(), Error> {
+        let page_size = PAGE_SIZE as usize;
+        let end = vaddr.checked_add(size).ok_or(Error)?;
+        if vaddr % page_size != 0 || size % page_size != 0 {
+            return Err(Error);
+        }
+        if size == 0 {
+            return Ok(());
+        }
+        if (end - 1) >> self.mode.address_bits() != 0 {
+            return Err(Error);
+        }
+        let root = PageTable::from_page(Page::from_address(self.page.address()), self.mode);
+        self.update_intl(&root, self.mode.levels() - 1, 0, vaddr, end, perm)
+    }
+
+    // `base` is the address mapped by the first entry of `pt`.
+    fn update_intl(
+        &self,
+        pt: &PageTable,
+        level: usize,
+        base: usize,
+        start: usize,
+        end: usize,
+        perm: Option<u16>,
+    ) -> Result<(), Error> {
+        let size = level_size(level);
+        let from = core::cmp::max(start, base);
+        let to = core::cmp::min(end, base + self.entries(level) * size);
+        if from >= to {
+            return Ok(());
+        }
+        for index in (from - base) / size..=(to - 1 - base) / size {
+            let entry_base = base + index * size;
+            let mut entry = pt.get_entry(index);
+            if !entry.is_valid() {
+                continue;
+            }
+            let next_page = if entry.is_leaf() || level == 0 {
+                if start <= entry_base && entry_base + size <= end {
+                    match perm {
+                        None => {
+                            pt.set_entry(index, PageTableEntry::from_value(0));
+                            release_leaf(&entry, level);
+                        }
+                        Some(perm) => {
+                            // shared pages stay read-only until the sharing is broken
+                            let perm = if entry.flags & (PageTableEntryFlag::Shared as u16) != 0 {
+                                perm & !(PageTableEntryFlag::Write as u16)
+                            } else {
+                                perm
+                            };
+                            entry.flags = (entry.flags & !PERM_MASK) | perm;
+                            pt.set_entry(index, entry);
+                        }
+                    }
+                    continue;
+                }
+                // only a part of the superpage is changed
+                self.split_entry(pt, index, level)?
+            } else {
+                entry.next_page()
+            };
+            let child = PageTable::from_page(next_page, self.mode);
+            self.update_intl(&child, level - 1, entry_base, start, end, perm)?;
+        }
+        Ok(())
+    }
+
+    // This function frees all the tables and the pages owned by the table.
+    // The pages are cleared, so that no data is passed to their next user.
+    pub fn destroy(self) {
+        self.destroy_intl(&self, self.mode.levels() - 1);
+        free(self.page);
+    }
+
+    fn destroy_intl(&self, pt: &PageTable, level: usize) {
+        for index in 0..self.entries(level) {
+            let entry = pt.get_entry(index);
+            if !entry.is_valid() {
+                continue;
+            }
+            if entry.is_leaf() || level == 0 {
+                release_leaf(&entry, level);
+            } else {
+                let child = PageTable::from_page(entry.next_page(), self.mode);
+                self.destroy_intl(&child, level - 1);
+                free(child.page);
+            }
+        }
+    }
+}
+
+// This function clears and frees the page of a leaf in `level`, if it is owned by the table.
+// A shared page is freed when no table maps it.
+fn release_leaf(entry: &PageTableEntry, level: usize) {
+    if entry.flags & (PageTableEntryFlag::Shared as u16) != 0 {
+        ksm::put(entry.next_page().address().to_usize());
+        return;
+    }
+    if entry.flags & (PageTableEntryFlag::Owned as u16) == 0 {
+        return;
+    }
+    let base = entry.next_page().address().to_usize();
+    for i in 0..level_size(level) / (PAGE_SIZE as usize) {
+        Page::from_address(PhysicalAddress::new(base + i * (PAGE_SIZE as usize))).clear();
+    }
+    free(entry.next_page());
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum WalkError {
+    // the address is beyond the translation mode
+    OutOfRange,
+    // the entry in the level is not valid
+    NotMapped(usize),
+    // the entry in the level is broken (reserved permissions, a misaligned superpage, or a pointer
+    // in the last level)
+    Malformed(usize),
+}
+
+// Mappings
+/////
+
+// A range of addresses mapped to a continuous physical range with the same flags.
+#[derive(Copy, Clone, Debug)]
+pub struct Mapping {
+    pub vaddr: usize,
+    pub paddr: usize,
+    pub size: usize,
+    pub flags: u16,
+}
+
+impl core::fmt::Display for Mapping {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let flag = |bit: PageTableEntryFlag, c: char| {
+            if self.flags & (bit as u16) != 0 {
+                c
+            } else {
+                '-'
+            }
+        };
+        write!(
+            f,
+            "0x{:016x}-0x{:016x} -> 0x{:016x} {}{}{}{}{}{}{}{}{}",
+            self.vaddr,
+            self.vaddr + self.size,
+            self.paddr,
+            flag(PageTableEntryFlag::Read, 'r'),
+            flag(PageTableEntryFlag::Write, 'w'),
+            flag(PageTableEntryFlag::Execute, 'x'),
+            flag(PageTableEntryFlag::User, 'u'),
+            flag(PageTableEntryFlag::Global, 'g'),
+            flag(PageTableEntryFlag::Access, 'a'),
+            flag(PageTableEntryFlag::Dirty, 'd'),
+            flag(PageTableEntryFlag::Owned, 'o'),
+            flag(PageTableEntryFlag::Shared, 's'),
+        )
+    }
+}
+
+// The iterator over the mappings of a page table in the order of the addresses.
+// Adjacent leaves are coalesced if both the addresses and the flags are continuous.
+pub struct Mappings {
+    mode: Mode,
+    // (the table, the address mapped by its first entry, the next index) of each level from the root
+    stack: [(usize, usize, usize); 5],
+    depth: usize,
+    pending: Option<Mapping>,
+}
+
+impl Mappings {
+    // This function returns the next leaf.
+    fn next_leaf(&mut self) -> Option<Mapping> {
+        while self.depth > 0 {
+            let level = self.mode.levels() - self.depth;
+            let (table, base, index) = self.stack[self.depth - 1];
+            let entries = if self.depth == 1 {
+                1 << self.mode.root_index_bits()
             } else {
-                let next_page = entry.next_page();
-                let new_pt = PageTable::from_page(next_page);
-                self.map_intl(vaddr, dest, &new_pt, perm, level - 1);
+                512
             };
+            if index >= entries {
+                self.depth -= 1;
+                continue;
+            }
+            self.stack[self.depth - 1].2 += 1;
+
+            let entry =
+                PageTableEntry::from_value(unsafe { (table as *const usize).add(index).read() });
+            if !entry.is_valid() {
+                continue;
+            }
+            let vaddr = base + index * level_size(level);
+            if entry.is_leaf() || level == 0 {
+                return Some(Mapping {
+                    vaddr: vaddr,
+                    paddr: entry.next_page().address().to_usize(),
+                    size: level_size(level),
+                    flags: entry.flags,
+                });
+            }
+            self.stack[self.depth] = (entry.next_page().address().to_usize(), vaddr, 0);
+            self.depth += 1;
+        }
+        None
+    }
+}
+
+impl Iterator for Mappings {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        let mut current = match self.pending.take() {
+            Some(m) => m,
+            None => self.next_leaf()?,
+        };
+        while let Some(m) = self.next_leaf() {
+            if m.vaddr == current.vaddr + current.size
+                && m.paddr == current.paddr + current.size
+                && m.flags == current.flags
+            {
+                current.size += m.size;
+            } else {
+                self.pending = Some(m);
+                break;
+            }
+        }
+        Some(current)
+    }
+}
+
+// the size of the region mapped by an entry in `level` (0: 4 KiB, 1: 2 MiB, 2: 1 GiB, ...)
+pub fn level_size(level: usize) -> usize {
+    (PAGE_SIZE as usize) << (9 * level)
+}
+
+// Address translation of the hypervisor
+/////
+// HS-mode runs with Sv39 and identity mappings. The image is mapped with the permissions of each
+// section (W^X), the rest of DRAM is mapped directly for the page allocator, and the MMIO regions
+// are mapped read/write without execution. The page below each stack is left unmapped to catch
+// stack overflows.
+// NOTE: the memory type of MMIO regions is given by the PMAs of the platform (Svpbmt is not used).
+
+// This function builds the page table of HS-mode and enables the translation.
+// NOTE: this must be called after `init`.
+pub fn enable_hs_paging() -> Result<(), Error> {
+    let pt = PageTable::from_page(alloc_order(Mode::Sv39.root_order())?, Mode::Sv39);
+    let page_size = PAGE_SIZE as usize;
+    let global = PageTableEntryFlag::Global as u16;
+    let r = PageTableEntryFlag::Read as u16 | global;
+    let rw = r | PageTableEntryFlag::Write as u16;
+    let rx = r | PageTableEntryFlag::Execute as u16;
+
+    unsafe {
+        // the image
+        let guards = [
+            memlayout::hs_stack_start() - page_size,
+            memlayout::intr_stack_start() - page_size,
+        ];
+        let mut addr = memlayout::elf_start();
+        while addr < START {
+            let perm = if addr < memlayout::text_end() {
+                rx
+            } else if addr < memlayout::rodata_end() {
+                r
+            } else {
+                rw
+            };
+            if !guards.contains(&addr) {
+                pt.map(
+                    VirtualAddress::new(addr),
+                    &Page::from_address(PhysicalAddress::new(addr)),
+                    perm,
+                )?;
+            }
+            addr += page_size;
         }
+
+        // the memory managed by the page allocator (including this page table)
+        map_identity(&pt, START, END - START, rw)?;
+    }
+
+    // MMIO
+    let mmio = [
+        (memlayout::UART_BASE, page_size),
+        (
+            memlayout::VIRTIO0_BASE,
+            memlayout::VIRTIO_MMIO_SLOTS * memlayout::VIRTIO_MMIO_SLOT_SIZE,
+        ),
+        (memlayout::PLIC_BASE, memlayout::PLIC_SIZE),
+        (memlayout::PCIE_ECAM_BASE, memlayout::PCIE_ECAM_SIZE),
+        (memlayout::PCIE_MMIO_BASE, memlayout::PCIE_MMIO_SIZE),
+        (memlayout::IMSIC_S_BASE, memlayout::IMSIC_S_SIZE),
+        (memlayout::RTC_BASE, page_size),
+        (memlayout::TEST_BASE, page_size),
+    ];
+    for (base, size) in mmio.iter() {
+        map_identity(&pt, *base, *size, rw)?;
+    }
+
+    riscv::csr::satp::set(riscv::csr::satp::Setting::new(
+        riscv::csr::satp::Mode::Sv39,
+        0,
+        pt.page.address().to_usize() >> 12,
+    ));
+    riscv::instruction::sfenve_vma();
+    log::info!(
+        "HS-mode translation was enabled (root: 0x{:016x})",
+        pt.page.address().to_usize()
+    );
+    Ok(())
+}
+
+// This function maps [start, start + size) to itself with the largest pages which fit.
+fn map_identity(pt: &PageTable, start: usize, size: usize, perm: u16) -> Result<(), Error> {
+    let end = start + size;
+    let mut addr = start;
+    while addr < end {
+        let level = (0..=2)
+            .rev()
+            .find(|l| addr % level_size(*l) == 0 && addr + level_size(*l) <= end)
+            .unwrap_or(0);
+        let page = Page::from_address(PhysicalAddress::new(addr));
+        pt.map_superpage(VirtualAddress::new(addr), &page, perm, level)?;
+        addr += level_size(level);
     }
+    Ok(())
 }
@@ -0,0 +1,285 @@
+// PCI host bridge
+/////
+// This enumerates the generic ECAM PCIe host bridge of QEMU virt machine, and assigns
+// the memory BARs of the functions from the PCI MMIO window.
+// INTx interrupts are routed to the PLIC (see `Function::irq`).
+// TODO (enhancement): bridges (only bus 0 is scanned), I/O BARs and MSI/MSI-X
+
+use crate::memlayout;
+
+pub const MAX_FUNCTIONS: usize = 32;
+
+// offsets in the configuration space
+pub const VENDOR_ID: usize = 0x00;
+pub const DEVICE_ID: usize = 0x02;
+pub const COMMAND: usize = 0x04;
+pub const STATUS: usize = 0x06;
+pub const CLASS_REVISION: usize = 0x08;
+pub const HEADER_TYPE: usize = 0x0e;
+pub const BAR0: usize = 0x10;
+pub const SUBSYSTEM_ID: usize = 0x2e;
+pub const CAPABILITIES_POINTER: usize = 0x34;
+pub const INTERRUPT_LINE: usize = 0x3c;
+pub const INTERRUPT_PIN: usize = 0x3d;
+
+const COMMAND_IO: u16 = 1 << 0;
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+const HEADER_TYPE_MULTI_FUNCTION: u8 = 1 << 7;
+
+const BAR_IO: u32 = 1 << 0;
+const BAR_TYPE_MASK: u32 = 0b11 << 1;
+const BAR_TYPE_64: u32 = 0b10 << 1;
+const BAR_FLAGS_MASK: u32 = 0xf;
+
+#[derive(Copy, Clone)]
+pub struct Bar {
+    pub address: usize,
+    pub size: usize,
+}
+
+#[derive(Copy, Clone)]
+pub struct Function {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub subsystem_id: u16,
+    // class code, subclass, prog-if and revision
+    pub class: u32,
+    pub bars: [Option<Bar>; 6],
+    // the interrupt source in the PLIC for INTx
+    pub irq: Option<u32>,
+}
+
+static mut FUNCTIONS: [Option<Function>; MAX_FUNCTIONS] = [None; MAX_FUNCTIONS];
+static mut NEXT_MMIO: usize = 0;
+
+fn config_address(bus: u8, device: u8, function: u8, offset: usize) -> usize {
+    memlayout::PCIE_ECAM_BASE
+        + ((bus as usize) << 20)
+        + ((device as usize) << 15)
+        + ((function as usize) << 12)
+        + offset
+}
+
+impl Function {
+    fn new(bus: u8, device: u8, function: u8) -> Function {
+        Function {
+            bus: bus,
+            device: device,
+            function: function,
+            vendor_id: 0,
+            device_id: 0,
+            subsystem_id: 0,
+            class: 0,
+            bars: [None; 6],
+            irq: None,
+        }
+    }
+
+    fn address(&self, offset: usize) -> usize {
+        config_address(self.bus, self.device, self.function, offset)
+    }
+
+    pub fn read32(&self, offset: usize) -> u32 {
+        unsafe { (self.address(offset) as *mut u32).read_volatile() }
+    }
+
+    pub fn read16(&self, offset: usize) -> u16 {
+        unsafe { (self.address(offset) as *mut u16).read_volatile() }
+    }
+
+    pub fn read8(&self, offset: usize) -> u8 {
+        unsafe { (self.address(offset) as *mut u8).read_volatile() }
+    }
+
+    pub fn write32(&self, offset: usize, value: u32) {
+        unsafe { (self.address(offset) as *mut u32).write_volatile(value) }
+    }
+
+    pub fn write16(&self, offset: usize, value: u16) {
+        unsafe { (self.address(offset) as *mut u16).write_volatile(value) }
+    }
+
+    pub fn write8(&self, offset: usize, value: u8) {
+        unsafe { (self.address(offset) as *mut u8).write_volatile(value) }
+    }
+
+    // This function returns an iterator over the capabilities as (offset, ID).
+    pub fn capabilities(&self) -> Capabilities {
+        let next = if self.read16(STATUS) & STATUS_CAPABILITIES != 0 {
+            self.read8(CAPABILITIES_POINTER) & !0b11
+        } else {
+            0
+        };
+        Capabilities {
+            function: *self,
+            next: next,
+        }
+    }
+
+    // This function sizes the memory BARs and assigns addresses to them from the PCI MMIO window.
+    fn setup_bars(&mut self) {
+        // disable decoding while sizing
+        let command = self.read16(COMMAND);
+        self.write16(COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+
+        let mut i = 0;
+        while i < 6 {
+            let offset = BAR0 + i * 4;
+            let orig = self.read32(offset);
+            if orig & BAR_IO != 0 {
+                i += 1;
+                continue;
+            }
+            let is64 = orig & BAR_TYPE_MASK == BAR_TYPE_64;
+
+            self.write32(offset, 0xffff_ffff);
+            let mut mask = (self.read32(offset) & !BAR_FLAGS_MASK) as u64;
+            if is64 {
+                self.write32(offset + 4, 0xffff_ffff);
+                mask |= (self.read32(offset + 4) as u64) << 32;
+            } else if mask != 0 {
+                mask |= 0xffff_ffff_0000_0000;
+            }
+
+            if mask != 0 {
+                let size = (!mask).wrapping_add(1) as usize;
+                match alloc_mmio(size) {
+                    Some(address) => {
+                        self.write32(offset, address as u32 | (orig & BAR_FLAGS_MASK));
+                        if is64 {
+                            self.write32(offset + 4, (address as u64 >> 32) as u32);
+                        }
+                        self.bars[i] = Some(Bar {
+                            address: address,
+                            size: size,
+                        });
+                    }
+                    None => {
+                        log::info!(
+                            "pci: no space for BAR{} of {:02x}:{:02x}.{} (size: 0x{:x})",
+                            i,
+                            self.bus,
+                            self.device,
+                            self.function,
+                            size
+                        );
+                        self.write32(offset, 0);
+                        if is64 {
+                            self.write32(offset + 4, 0);
+                        }
+                    }
+                }
+            }
+            i += if is64 { 2 } else { 1 };
+        }
+
+        self.write16(COMMAND, command | COMMAND_MEMORY | COMMAND_BUS_MASTER);
+    }
+
+    // This function routes INTx of the function (swizzled as QEMU virt machine does).
+    fn setup_irq(&mut self) {
+        let pin = self.read8(INTERRUPT_PIN);
+        if pin == 0 {
+            return;
+        }
+        let irq = memlayout::PCIE_IRQ as u32 + (self.device as u32 + pin as u32 - 1) % 4;
+        self.write8(INTERRUPT_LINE, irq as u8);
+        self.irq = Some(irq);
+    }
+}
+
+pub struct Capabilities {
+    function: Function,
+    next: u8,
+}
+
+impl Iterator for Capabilities {
+    type Item = (usize, u8);
+
+    fn next(&mut self) -> Option<(usize, u8)> {
+        if self.next == 0 {
+            return None;
+        }
+        let offset = self.next as usize;
+        let id = self.function.read8(offset);
+        self.next = self.function.read8(offset + 1) & !0b11;
+        Some((offset, id))
+    }
+}
+
+fn alloc_mmio(size: usize) -> Option<usize> {
+    unsafe {
+        // BARs are naturally aligned
+        let address = (memlayout::PCIE_MMIO_BASE + NEXT_MMIO + size - 1) & !(size - 1);
+        let end = address - memlayout::PCIE_MMIO_BASE + size;
+        if end > memlayout::PCIE_MMIO_SIZE {
+            return None;
+        }
+        NEXT_MMIO = end;
+        Some(address)
+    }
+}
+
+fn probe(bus: u8, device: u8, function: u8) -> Option<Function> {
+    let mut f = Function::new(bus, device, function);
+    f.vendor_id = f.read16(VENDOR_ID);
+    if f.vendor_id == 0xffff {
+        return None;
+    }
+    f.device_id = f.read16(DEVICE_ID);
+    f.subsystem_id = f.read16(SUBSYSTEM_ID);
+    f.class = f.read32(CLASS_REVISION);
+    Some(f)
+}
+
+fn add(mut f: Function, count: &mut usize) {
+    if *count >= MAX_FUNCTIONS {
+        log::info!("pci: too many functions");
+        return;
+    }
+    f.setup_bars();
+    f.setup_irq();
+    log::info!(
+        "pci: {:02x}:{:02x}.{} {:04x}:{:04x} (class: 0x{:06x})",
+        f.bus,
+        f.device,
+        f.function,
+        f.vendor_id,
+        f.device_id,
+        f.class >> 8
+    );
+    unsafe {
+        FUNCTIONS[*count] = Some(f);
+    }
+    *count += 1;
+}
+
+pub fn init() {
+    let mut count = 0;
+    for device in 0..32 {
+        let f = match probe(0, device, 0) {
+            Some(f) => f,
+            None => continue,
+        };
+        let multi_function = f.read8(HEADER_TYPE) & HEADER_TYPE_MULTI_FUNCTION != 0;
+        add(f, &mut count);
+        if multi_function {
+            for function in 1..8 {
+                if let Some(f) = probe(0, device, function) {
+                    add(f, &mut count);
+                }
+            }
+        }
+    }
+    log::info!("pci: {} functions found", count);
+}
+
+// This function returns an iterator over the functions found by `init`.
+pub fn functions() -> impl Iterator<Item = &'static Function> {
+    unsafe { FUNCTIONS.iter().flatten() }
+}
@@ -1,24 +1,44 @@
 use crate::memlayout;
 
 pub fn enable_interrupt() {
-    // TODO (enhancement): UART0
-
     // configure PLIC
+    // NOTE: interrupts from virtio devices are enabled by their owners (the host driver or a guest).
+    set_priority(memlayout::UART0_IRQ as u32, 1);
+    enable(memlayout::UART0_IRQ as u32);
     unsafe {
         let plic_base = memlayout::PLIC_BASE as *mut u32;
-        plic_base
-            .offset(memlayout::VIRTIO0_IRQ as isize)
-            .write_volatile(1);
-        plic_base
-            .offset(memlayout::UART0_IRQ as isize)
-            .write_volatile(1);
-        plic_base
-            .offset(0x2080 / 4)
-            .write_volatile((1 << memlayout::VIRTIO0_IRQ) | (1 << memlayout::UART0_IRQ));
         plic_base.offset(0x201000 / 4).write_volatile(0);
     }
 }
 
+// NOTE: the hypervisor uses the context for S-mode of hart 0 (context 1).
+const ENABLE_BASE: isize = 0x2080;
+
+pub fn set_priority(interrupt: u32, priority: u32) {
+    let plic_base = memlayout::PLIC_BASE as *mut u32;
+    unsafe {
+        plic_base
+            .offset(interrupt as isize)
+            .write_volatile(priority)
+    }
+}
+
+pub fn enable(interrupt: u32) {
+    let plic_base = memlayout::PLIC_BASE as *mut u32;
+    unsafe {
+        let reg = plic_base.offset(ENABLE_BASE / 4 + (interrupt / 32) as isize);
+        reg.write_volatile(reg.read_volatile() | (1 << (interrupt % 32)));
+    }
+}
+
+pub fn disable(interrupt: u32) {
+    let plic_base = memlayout::PLIC_BASE as *mut u32;
+    unsafe {
+        let reg = plic_base.offset(ENABLE_BASE / 4 + (interrupt / 32) as isize);
+        reg.write_volatile(reg.read_volatile() & !(1 << (interrupt % 32)));
+    }
+}
+
 pub fn complete(interrupt: u32) {
     let plic_base = memlayout::PLIC_BASE as *mut u32;
     unsafe { plic_base.offset(0x201004 / 4).write_volatile(interrupt) }
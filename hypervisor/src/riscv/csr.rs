@@ -13,13 +13,18 @@ pub enum VirtualzationMode {
     Guest = 1,
 }
 
+pub mod mcounteren;
 pub mod medeleg;
+pub mod menvcfg;
 pub mod mepc;
 pub mod mideleg;
 pub mod mie;
+pub mod mip;
 pub mod misa;
 pub mod mstatus;
 pub mod mtvec;
+pub mod pmpaddr;
+pub mod pmpcfg;
 
 pub mod satp;
 pub mod sepc;
@@ -27,9 +32,12 @@ pub mod sie;
 pub mod sscratch;
 pub mod sstatus;
 pub mod stvec;
+pub mod time;
 
 pub mod hcontext;
+pub mod hcounteren;
 pub mod hedeleg;
+pub mod henvcfg;
 pub mod hgatp;
 pub mod hgeie;
 pub mod hgeip;
@@ -37,7 +45,17 @@ pub mod hideleg;
 pub mod hie;
 pub mod hip;
 pub mod hstatus;
+pub mod htimedelta;
+pub mod htinst;
 pub mod htval;
 pub mod hvip;
 
+pub mod vsatp;
+pub mod vscause;
 pub mod vsepc;
+pub mod vsie;
+pub mod vsscratch;
+pub mod vsstatus;
+pub mod vstimecmp;
+pub mod vstval;
+pub mod vstvec;
@@ -0,0 +1,6 @@
+define_read!(0x606);
+define_write!(0x606);
+
+pub const CY: usize = 1 << 0;
+pub const TM: usize = 1 << 1;
+pub const IR: usize = 1 << 2;
@@ -0,0 +1,4 @@
+define_read!(0x60A);
+define_write!(0x60A);
+
+pub const STCE: usize = 1 << 63;
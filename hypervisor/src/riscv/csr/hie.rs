@@ -1,2 +1,4 @@
 define_read!(0x604);
 define_write!(0x604);
+
+pub const SGEIE: usize = 1 << 12;
@@ -6,3 +6,22 @@ pub fn set_spv(mode: crate::riscv::csr::VirtualzationMode) {
     let spv_mask = !(0b1 << 7 as usize);
     write((hstatus & spv_mask) | ((mode as usize) << 7))
 }
+
+// SPVP: the privilege of the guest when it trapped (true: VS-mode, false: VU-mode)
+pub fn spvp() -> bool {
+    read() & (1 << 8) != 0
+}
+
+// VTW: make WFI in VS-mode raise a virtual instruction exception
+pub fn set_vtw(enabled: bool) {
+    let hstatus = read();
+    let vtw_mask = !(0b1 << 21 as usize);
+    write((hstatus & vtw_mask) | (if enabled { 1 << 21 } else { 0 }))
+}
+
+// VGEIN: select the guest interrupt file connected to VS-level external interrupts (0: none)
+pub fn set_vgein(file: usize) {
+    let hstatus = read();
+    let vgein_mask = !(0b11_1111 << 12 as usize);
+    write((hstatus & vgein_mask) | ((file & 0b11_1111) << 12))
+}
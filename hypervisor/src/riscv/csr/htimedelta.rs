@@ -0,0 +1,2 @@
+define_read!(0x605);
+define_write!(0x605);
@@ -0,0 +1,2 @@
+define_read!(0x64A);
+define_write!(0x64A);
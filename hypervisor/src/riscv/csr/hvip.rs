@@ -1,2 +1,6 @@
 define_read!(0x645);
 define_write!(0x645);
+
+pub const VSEIP: usize = 1 << 10;
+pub const VSTIP: usize = 1 << 6;
+pub const VSSIP: usize = 1 << 2;
@@ -11,6 +11,36 @@ macro_rules! define_read {
     };
 }
 
+// for the CSRs which are numbered (e.g. pmpaddr0 - pmpaddr63)
+// `read(n)`/`write(n, v)` access the CSR of `(n, csr_number)`.
+macro_rules! define_read_indexed {
+    ($(($index:expr, $csr_number:expr)),*) => {
+        pub fn read(n: usize) -> usize {
+            match n {
+                $($index => unsafe {
+                    let r: usize;
+                    llvm_asm!("csrrs $0, $1, x0" : "=r"(r) : "i"($csr_number) :: "volatile");
+                    r
+                },)*
+                _ => panic!("invalid CSR index: {}", n),
+            }
+        }
+    };
+}
+
+macro_rules! define_write_indexed {
+    ($(($index:expr, $csr_number:expr)),*) => {
+        pub fn write(n: usize, v: usize) {
+            match n {
+                $($index => unsafe {
+                    llvm_asm!("csrrw x0, $1, $0" :: "r"(v), "i"($csr_number) :: "volatile");
+                },)*
+                _ => panic!("invalid CSR index: {}", n),
+            }
+        }
+    };
+}
+
 macro_rules! define_write {
     ($csr_number:expr) => {
         pub fn write(v: usize) {
@@ -0,0 +1,4 @@
+define_read!(0x30A);
+define_write!(0x30A);
+
+pub const STCE: usize = 1 << 63;
@@ -1,2 +1,4 @@
 define_read!(0x304);
 define_write!(0x304);
+
+pub const MTIE: usize = 1 << 7;
@@ -1,2 +1,4 @@
 define_read!(0x344);
 define_write!(0x344);
+
+pub const STIP: usize = 1 << 5;
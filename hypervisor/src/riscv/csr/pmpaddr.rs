@@ -0,0 +1,22 @@
+// pmpaddr0 - pmpaddr63
+// NOTE: the address is shifted right by 2 bits.
+define_read_indexed!(
+    (0, 0x3B0), (1, 0x3B1), (2, 0x3B2), (3, 0x3B3), (4, 0x3B4), (5, 0x3B5), (6, 0x3B6), (7, 0x3B7),
+    (8, 0x3B8), (9, 0x3B9), (10, 0x3BA), (11, 0x3BB), (12, 0x3BC), (13, 0x3BD), (14, 0x3BE), (15, 0x3BF),
+    (16, 0x3C0), (17, 0x3C1), (18, 0x3C2), (19, 0x3C3), (20, 0x3C4), (21, 0x3C5), (22, 0x3C6), (23, 0x3C7),
+    (24, 0x3C8), (25, 0x3C9), (26, 0x3CA), (27, 0x3CB), (28, 0x3CC), (29, 0x3CD), (30, 0x3CE), (31, 0x3CF),
+    (32, 0x3D0), (33, 0x3D1), (34, 0x3D2), (35, 0x3D3), (36, 0x3D4), (37, 0x3D5), (38, 0x3D6), (39, 0x3D7),
+    (40, 0x3D8), (41, 0x3D9), (42, 0x3DA), (43, 0x3DB), (44, 0x3DC), (45, 0x3DD), (46, 0x3DE), (47, 0x3DF),
+    (48, 0x3E0), (49, 0x3E1), (50, 0x3E2), (51, 0x3E3), (52, 0x3E4), (53, 0x3E5), (54, 0x3E6), (55, 0x3E7),
+    (56, 0x3E8), (57, 0x3E9), (58, 0x3EA), (59, 0x3EB), (60, 0x3EC), (61, 0x3ED), (62, 0x3EE), (63, 0x3EF)
+);
+define_write_indexed!(
+    (0, 0x3B0), (1, 0x3B1), (2, 0x3B2), (3, 0x3B3), (4, 0x3B4), (5, 0x3B5), (6, 0x3B6), (7, 0x3B7),
+    (8, 0x3B8), (9, 0x3B9), (10, 0x3BA), (11, 0x3BB), (12, 0x3BC), (13, 0x3BD), (14, 0x3BE), (15, 0x3BF),
+    (16, 0x3C0), (17, 0x3C1), (18, 0x3C2), (19, 0x3C3), (20, 0x3C4), (21, 0x3C5), (22, 0x3C6), (23, 0x3C7),
+    (24, 0x3C8), (25, 0x3C9), (26, 0x3CA), (27, 0x3CB), (28, 0x3CC), (29, 0x3CD), (30, 0x3CE), (31, 0x3CF),
+    (32, 0x3D0), (33, 0x3D1), (34, 0x3D2), (35, 0x3D3), (36, 0x3D4), (37, 0x3D5), (38, 0x3D6), (39, 0x3D7),
+    (40, 0x3D8), (41, 0x3D9), (42, 0x3DA), (43, 0x3DB), (44, 0x3DC), (45, 0x3DD), (46, 0x3DE), (47, 0x3DF),
+    (48, 0x3E0), (49, 0x3E1), (50, 0x3E2), (51, 0x3E3), (52, 0x3E4), (53, 0x3E5), (54, 0x3E6), (55, 0x3E7),
+    (56, 0x3E8), (57, 0x3E9), (58, 0x3EA), (59, 0x3EB), (60, 0x3EC), (61, 0x3ED), (62, 0x3EE), (63, 0x3EF)
+);
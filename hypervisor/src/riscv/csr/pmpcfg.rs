@@ -0,0 +1,19 @@
+// pmpcfg0, pmpcfg2, ..., pmpcfg14 (RV64: each of them holds the configurations of 8 entries)
+define_read_indexed!((0, 0x3A0), (2, 0x3A2), (4, 0x3A4), (6, 0x3A6), (8, 0x3A8), (10, 0x3AA), (12, 0x3AC), (14, 0x3AE));
+define_write_indexed!((0, 0x3A0), (2, 0x3A2), (4, 0x3A4), (6, 0x3A6), (8, 0x3A8), (10, 0x3AA), (12, 0x3AC), (14, 0x3AE));
+
+pub const R: u8 = 1 << 0;
+pub const W: u8 = 1 << 1;
+pub const X: u8 = 1 << 2;
+pub const A_OFF: u8 = 0 << 3;
+pub const A_TOR: u8 = 1 << 3;
+pub const A_NA4: u8 = 2 << 3;
+pub const A_NAPOT: u8 = 3 << 3;
+pub const L: u8 = 1 << 7;
+
+// This function sets the configuration of the PMP entry `entry`.
+pub fn set(entry: usize, cfg: u8) {
+    let n = entry / 8 * 2;
+    let shift = (entry % 8) * 8;
+    write(n, (read(n) & !(0xff << shift)) | ((cfg as usize) << shift));
+}
@@ -2,3 +2,5 @@ define_read!(0x104);
 define_write!(0x104);
 
 pub const SEIE: usize = 1 << 9;
+
+pub const STIE: usize = 1 << 5;
@@ -1,6 +1,10 @@
 define_read!(0x100);
 define_write!(0x100);
 
+pub const SIE: usize = 1 << 1;
+// FS: the state of the FP unit (Off: 0, Dirty: 3)
+pub const FS: usize = 0b11 << 13;
+
 pub fn set_spp(mode: crate::riscv::csr::CpuMode) {
     if mode == crate::riscv::csr::CpuMode::M {
         log::debug!("set_spp was called riscv::csr::CpuMode::M")
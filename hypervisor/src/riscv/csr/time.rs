@@ -0,0 +1 @@
+define_read!(0xC01);
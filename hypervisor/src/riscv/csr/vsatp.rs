@@ -0,0 +1,2 @@
+define_read!(0x280);
+define_write!(0x280);
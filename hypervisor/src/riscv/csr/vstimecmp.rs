@@ -0,0 +1,2 @@
+define_read!(0x24D);
+define_write!(0x24D);
@@ -0,0 +1,2 @@
+define_read!(0x205);
+define_write!(0x205);
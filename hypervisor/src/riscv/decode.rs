@@ -0,0 +1,96 @@
+// Decoder of load/store instructions
+/////
+// This is used to emulate the memory accesses of guests to emulated devices.
+
+pub struct LoadStore {
+    pub is_store: bool,
+    // access width in bytes
+    pub width: usize,
+    // whether a loaded value is sign-extended
+    pub signed: bool,
+    // the destination register of a load, or the source register of a store
+    pub reg: usize,
+    // the length of the instruction in bytes
+    pub len: usize,
+}
+
+// This function decodes a (possibly compressed) load/store instruction.
+// A transformed instruction in htinst, whose bits 1:0 are 0b01 when the original one is compressed,
+// can also be passed.
+pub fn decode_load_store(inst: u32) -> Option<LoadStore> {
+    match inst & 0b11 {
+        0b11 => decode_standard(inst, 4),
+        0b01 => decode_standard(inst | 0b10, 2),
+        _ => decode_compressed(inst as u16),
+    }
+}
+
+fn decode_standard(inst: u32, len: usize) -> Option<LoadStore> {
+    let opcode = inst & 0x7f;
+    let funct3 = (inst >> 12) & 0b111;
+    let rd = ((inst >> 7) & 0x1f) as usize;
+    let rs2 = ((inst >> 20) & 0x1f) as usize;
+    match opcode {
+        // LOAD
+        0x03 => {
+            let (width, signed) = match funct3 {
+                0 => (1, true),
+                1 => (2, true),
+                2 => (4, true),
+                3 => (8, true),
+                4 => (1, false),
+                5 => (2, false),
+                6 => (4, false),
+                _ => return None,
+            };
+            Some(LoadStore {
+                is_store: false,
+                width: width,
+                signed: signed,
+                reg: rd,
+                len: len,
+            })
+        }
+        // STORE
+        0x23 => {
+            if funct3 > 3 {
+                return None;
+            }
+            Some(LoadStore {
+                is_store: true,
+                width: 1 << funct3,
+                signed: false,
+                reg: rs2,
+                len: len,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn decode_compressed(inst: u16) -> Option<LoadStore> {
+    let quadrant = inst & 0b11;
+    let funct3 = (inst >> 13) & 0b111;
+    // rd' / rs2' in bits 4:2 (x8 - x15)
+    let reg_prime = (((inst >> 2) & 0b111) + 8) as usize;
+    let (is_store, width, reg) = match (quadrant, funct3) {
+        // C.LW / C.LD / C.SW / C.SD
+        (0b00, 0b010) => (false, 4, reg_prime),
+        (0b00, 0b011) => (false, 8, reg_prime),
+        (0b00, 0b110) => (true, 4, reg_prime),
+        (0b00, 0b111) => (true, 8, reg_prime),
+        // C.LWSP / C.LDSP (rd in bits 11:7) / C.SWSP / C.SDSP (rs2 in bits 6:2)
+        (0b10, 0b010) => (false, 4, ((inst >> 7) & 0x1f) as usize),
+        (0b10, 0b011) => (false, 8, ((inst >> 7) & 0x1f) as usize),
+        (0b10, 0b110) => (true, 4, ((inst >> 2) & 0x1f) as usize),
+        (0b10, 0b111) => (true, 8, ((inst >> 2) & 0x1f) as usize),
+        _ => return None,
+    };
+    Some(LoadStore {
+        is_store: is_store,
+        width: width,
+        signed: !is_store,
+        reg: reg,
+        len: 2,
+    })
+}
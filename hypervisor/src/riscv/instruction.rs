@@ -30,6 +30,10 @@ pub fn sfenve_vma() {
 
 extern "C" {
     fn __hfence_gvma_all();
+    fn __hfence_gvma_vmid(vmid: usize);
+    fn __hlvx_hu(addr: usize) -> usize;
+    fn __save_fp(fregs: *mut usize) -> usize;
+    fn __load_fp(fregs: *const usize, fcsr: usize);
 }
 
 pub fn hfence_gvma() {
@@ -38,6 +42,42 @@ pub fn hfence_gvma() {
     }
 }
 
+// This function flushes the G-stage TLB entries of the guest with `vmid`.
+pub fn hfence_gvma_vmid(vmid: u16) {
+    unsafe {
+        __hfence_gvma_vmid(vmid as usize);
+    }
+}
+
+// This function reads a halfword at the guest virtual address `addr` with the permission for execution,
+// as the guest would fetch an instruction.
+// NOTE: the caller must make sure that `addr` is mapped; otherwise a fault is raised in HS-mode.
+pub unsafe fn hlvx_hu(addr: usize) -> u16 {
+    __hlvx_hu(addr) as u16
+}
+
+// These functions save / load the FP registers (f0-f31 and fcsr), which are shared by HS-mode and
+// the current guest. FP is enabled in sstatus.FS only while they are accessed.
+pub fn save_fp(fregs: &mut [usize; 32]) -> usize {
+    let sstatus = crate::riscv::csr::sstatus::read();
+    crate::riscv::csr::sstatus::write(sstatus | crate::riscv::csr::sstatus::FS);
+    let fcsr = unsafe { __save_fp(fregs.as_mut_ptr()) };
+    crate::riscv::csr::sstatus::write(sstatus);
+    fcsr
+}
+
+pub fn load_fp(fregs: &[usize; 32], fcsr: usize) {
+    let sstatus = crate::riscv::csr::sstatus::read();
+    crate::riscv::csr::sstatus::write(sstatus | crate::riscv::csr::sstatus::FS);
+    unsafe {
+        __load_fp(fregs.as_ptr(), fcsr);
+    }
+    crate::riscv::csr::sstatus::write(sstatus);
+}
+
+// the encoding of wfi
+pub const WFI: u32 = 0x1050_0073;
+
 pub fn wfi() {
     unsafe {
         asm!("wfi");
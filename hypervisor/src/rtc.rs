@@ -0,0 +1,49 @@
+// Wall-clock time of the host
+/////
+// The time is taken from the Goldfish RTC of the host at boot if available
+// (otherwise `config::RTC_EPOCH_SECS` is used), and is advanced with the time CSR.
+
+use crate::config;
+use crate::memlayout;
+use crate::riscv;
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+// the wall-clock time and the value of the time CSR at boot
+static mut BOOT_NS: u64 = 0;
+static mut BOOT_TICKS: u64 = 0;
+
+fn read_host_rtc() -> u64 {
+    unsafe {
+        let base = memlayout::RTC_BASE as *mut u32;
+        // NOTE: reading TIME_LOW latches TIME_HIGH
+        let lo = base.offset(0).read_volatile() as u64;
+        let hi = base.offset(1).read_volatile() as u64;
+        (hi << 32) | lo
+    }
+}
+
+pub fn init() {
+    let ticks = riscv::csr::time::read() as u64;
+    let seed = if config::HOST_RTC { read_host_rtc() } else { 0 };
+    let boot_ns = if seed != 0 {
+        log::info!("wall-clock time was taken from the host RTC: {} ns", seed);
+        seed
+    } else {
+        log::info!(
+            "no RTC is available; the wall-clock time starts from {} s",
+            config::RTC_EPOCH_SECS
+        );
+        config::RTC_EPOCH_SECS * NSEC_PER_SEC
+    };
+    unsafe {
+        BOOT_NS = boot_ns;
+        BOOT_TICKS = ticks;
+    }
+}
+
+// This function returns the current time in nanoseconds since the UNIX epoch.
+pub fn now() -> u64 {
+    let ticks = riscv::csr::time::read() as u64;
+    unsafe { BOOT_NS + (ticks - BOOT_TICKS) * (NSEC_PER_SEC / memlayout::TIMEBASE_FREQUENCY) }
+}
@@ -0,0 +1,169 @@
+// SBI for guests
+/////
+// The hypervisor serves as the SBI implementation of guests.
+// Only the base extension, the timer extension (TIME) and the system reset extension (SRST) are implemented,
+// with an extension of rvvisor in the firmware-specific space (see `rvvisor`).
+
+use crate::guest::{Guest, State};
+use crate::hypervisor::TrapFrame;
+use crate::snapshot;
+
+// extension IDs
+const EXT_BASE: usize = 0x10;
+const EXT_TIME: usize = 0x5449_4D45;
+const EXT_SRST: usize = 0x5352_5354;
+const EXT_RVVISOR: usize = 0x0A00_0000 | IMPL_ID;
+
+// error codes
+const SUCCESS: isize = 0;
+const ERR_FAILED: isize = -1;
+const ERR_NOT_SUPPORTED: isize = -2;
+const ERR_INVALID_PARAM: isize = -3;
+
+// the version of SBI specification (v0.3)
+const SPEC_VERSION: usize = (0 << 24) | 3;
+// NOTE: this is not a registered implementation ID
+const IMPL_ID: usize = 0x7276;
+const IMPL_VERSION: usize = 0;
+
+// reset types and reasons of SRST
+const RESET_TYPE_SHUTDOWN: usize = 0;
+const RESET_TYPE_COLD_REBOOT: usize = 1;
+const RESET_TYPE_WARM_REBOOT: usize = 2;
+const RESET_REASON_SYSTEM_FAILURE: usize = 1;
+
+// functions of the rvvisor extension
+const RVVISOR_SNAPSHOT: usize = 0;
+const RVVISOR_SET_BALLOON_TARGET: usize = 1;
+const RVVISOR_START_DIRTY_LOG: usize = 2;
+const RVVISOR_TAKE_DIRTY_PAGES: usize = 3;
+
+// This function handles an ecall from the guest at `sepc`.
+// The result is written to a0 (error) and a1 (value) of the guest.
+pub fn handle_ecall(guest: &mut Guest, frame: *mut TrapFrame, sepc: usize) {
+    let (eid, fid, arg0, arg1) = unsafe {
+        (
+            (*frame).regs[17],
+            (*frame).regs[16],
+            (*frame).regs[10],
+            (*frame).regs[11],
+        )
+    };
+    let ret = match eid {
+        EXT_BASE => base(fid, arg0),
+        EXT_TIME => time(guest, fid, arg0),
+        EXT_SRST => srst(guest, fid, arg0, arg1),
+        EXT_RVVISOR => rvvisor(guest, fid, arg0, frame, sepc),
+        _ => {
+            log::debug!("sbi: unsupported call (eid: 0x{:x}, fid: {})", eid, fid);
+            Err(ERR_NOT_SUPPORTED)
+        }
+    };
+    let (error, value) = match ret {
+        Ok(v) => (SUCCESS, v),
+        Err(e) => (e, 0),
+    };
+    unsafe {
+        (*frame).regs[10] = error as usize;
+        (*frame).regs[11] = value;
+    }
+}
+
+fn base(fid: usize, arg0: usize) -> Result<usize, isize> {
+    match fid {
+        0 => Ok(SPEC_VERSION),
+        1 => Ok(IMPL_ID),
+        2 => Ok(IMPL_VERSION),
+        // probe_extension
+        3 => Ok(match arg0 {
+            EXT_BASE | EXT_TIME | EXT_SRST | EXT_RVVISOR => 1,
+            _ => 0,
+        }),
+        // mvendorid, marchid and mimpid
+        // TODO (enhancement): return the values of the host
+        4 | 5 | 6 => Ok(0),
+        _ => Err(ERR_NOT_SUPPORTED),
+    }
+}
+
+fn time(guest: &mut Guest, fid: usize, stime_value: usize) -> Result<usize, isize> {
+    if fid != 0 {
+        return Err(ERR_NOT_SUPPORTED);
+    }
+    guest.set_timer(stime_value as u64);
+    Ok(0)
+}
+
+fn srst(guest: &mut Guest, fid: usize, reset_type: usize, reason: usize) -> Result<usize, isize> {
+    if fid != 0 {
+        return Err(ERR_NOT_SUPPORTED);
+    }
+    match reset_type {
+        RESET_TYPE_SHUTDOWN => {
+            let code = if reason == RESET_REASON_SYSTEM_FAILURE {
+                1
+            } else {
+                0
+            };
+            guest.state = State::Stopped(code);
+        }
+        RESET_TYPE_COLD_REBOOT | RESET_TYPE_WARM_REBOOT => {
+            guest.state = State::Rebooting;
+        }
+        _ => return Err(ERR_INVALID_PARAM),
+    }
+    // NOTE: the call does not return to the guest on success
+    Ok(0)
+}
+
+// This function handles the extension of rvvisor.
+// - snapshot (fid 0): saves the guest into its snapshot region. The call returns 0 when the
+//   snapshot is taken, and returns 1 again when the guest is restored from it.
+// - set_balloon_target (fid 1): sets the amount of RAM (in bytes, `arg0`) which the balloon
+//   device should take from the guest. The driver in the guest inflates / deflates to it.
+// - start_dirty_log (fid 2): starts tracking the pages of RAM written by the guest (e.g. to debug
+//   its working set), or restarts it with a clear log.
+// - take_dirty_pages (fid 3): returns the number of pages written since the start or the last call,
+//   and resets the log.
+fn rvvisor(
+    guest: &mut Guest,
+    fid: usize,
+    arg0: usize,
+    frame: *mut TrapFrame,
+    sepc: usize,
+) -> Result<usize, isize> {
+    match fid {
+        RVVISOR_SNAPSHOT => {
+            let mut resumed = unsafe { *frame };
+            resumed.regs[10] = SUCCESS as usize;
+            resumed.regs[11] = 1;
+            match snapshot::save(guest, &resumed, sepc + 4) {
+                Ok(()) => Ok(0),
+                Err(snapshot::Error::NotFound) | Err(snapshot::Error::Unsupported) => {
+                    Err(ERR_NOT_SUPPORTED)
+                }
+                Err(e) => {
+                    log::info!("failed to save a snapshot of {}: {:?}", guest.name, e);
+                    Err(ERR_FAILED)
+                }
+            }
+        }
+        RVVISOR_SET_BALLOON_TARGET => match guest.set_balloon_target(arg0) {
+            Ok(()) => Ok(0),
+            Err(_) => Err(ERR_INVALID_PARAM),
+        },
+        RVVISOR_START_DIRTY_LOG => match guest.start_dirty_log() {
+            Ok(()) => Ok(0),
+            Err(_) => Err(ERR_FAILED),
+        },
+        RVVISOR_TAKE_DIRTY_PAGES => {
+            let pages = match guest.dirty_log() {
+                Some(log) => log.iter().map(|word| word.count_ones() as usize).sum(),
+                None => return Err(ERR_FAILED),
+            };
+            guest.reset_dirty_log();
+            Ok(pages)
+        }
+        _ => Err(ERR_NOT_SUPPORTED),
+    }
+}
@@ -0,0 +1,698 @@
+// Snapshots of guests
+/////
+// A snapshot has the whole state of a guest: the vCPU (registers and CSRs), the emulated devices,
+// the contents of RAM and the copy-on-write overlay of the disk. It is written to a region of the
+// host disk (see `guest::Config::snapshot`), and a fresh guest can be restored from it at boot.
+// The region is laid out as follows:
+//   sector 0   the header (magic, version, the RAM of the guest, the length and the checksum)
+//              (ROM is not saved, since it is loaded from the disk when the guest is made)
+//   sector 1-  the state as a stream of little-endian values:
+//              the vCPU, the devices, the page map of RAM (a byte per page, see `PAGE_*`),
+//              the pages with data and the chunks of the overlay
+// The header is written last, so that an interrupted snapshot is never restored.
+// NOTE: a guest with a passthrough device or a guest interrupt file (IMSIC) can not be saved, since
+// the state of the device or the file is not known.
+
+use crate::blockdev;
+use crate::guest::Guest;
+use crate::hypervisor::TrapFrame;
+use crate::memlayout::PAGE_SIZE;
+use crate::paging;
+use crate::riscv;
+use crate::vdev;
+use crate::virtio::SECTOR_SIZE;
+use core::convert::TryInto;
+
+const MAGIC: [u8; 8] = *b"RVVSNAP\0";
+// NOTE: bump this whenever the layout of the state changes
+const VERSION: u32 = 3;
+
+// the states of the pages in the page map
+const PAGE_ZERO: u8 = 0;
+const PAGE_DATA: u8 = 1;
+// the page was taken by the balloon (not mapped)
+const PAGE_ABSENT: u8 = 2;
+
+// the buffer of the stream (16 pages)
+const BUFFER_ORDER: usize = 4;
+const BUFFER_SIZE: usize = (PAGE_SIZE as usize) << BUFFER_ORDER;
+
+#[derive(Debug)]
+pub enum Error {
+    // the guest has no region for snapshots, or the region has no snapshot
+    NotFound,
+    // the snapshot was taken from a guest with another version or configuration
+    Incompatible,
+    // the checksum does not match, or the stream ends unexpectedly
+    Corrupted,
+    NoSpace,
+    OutOfMemory,
+    Unsupported,
+    Disk(blockdev::Error),
+}
+
+impl From<blockdev::Error> for Error {
+    fn from(e: blockdev::Error) -> Error {
+        match e {
+            blockdev::Error::OutOfRange => Error::NoSpace,
+            e => Error::Disk(e),
+        }
+    }
+}
+
+// vCPU
+/////
+
+// The state of the vCPU which is not kept in `Guest`.
+// NOTE: FPRs are not saved on traps (see hypervisor.S); they are taken from the hart, since HS-mode
+// does not use FP.
+#[derive(Copy, Clone)]
+pub struct Vcpu {
+    regs: [usize; 32],
+    fregs: [usize; 32],
+    fcsr: usize,
+    pub pc: usize,
+    vsstatus: usize,
+    vsie: usize,
+    vstvec: usize,
+    vsscratch: usize,
+    vsepc: usize,
+    vscause: usize,
+    vstval: usize,
+    vsatp: usize,
+    hvip: usize,
+    // the time of the guest (the time of the host plus htimedelta)
+    time: u64,
+}
+
+impl Vcpu {
+    // This function captures the vCPU of the running guest, which resumes at `pc`.
+    pub fn capture(frame: &TrapFrame, pc: usize) -> Vcpu {
+        let mut fregs = [0; 32];
+        let fcsr = riscv::instruction::save_fp(&mut fregs);
+        Vcpu {
+            regs: frame.regs,
+            fregs: fregs,
+            fcsr: fcsr,
+            pc: pc,
+            vsstatus: riscv::csr::vsstatus::read(),
+            vsie: riscv::csr::vsie::read(),
+            vstvec: riscv::csr::vstvec::read(),
+            vsscratch: riscv::csr::vsscratch::read(),
+            vsepc: riscv::csr::vsepc::read(),
+            vscause: riscv::csr::vscause::read(),
+            vstval: riscv::csr::vstval::read(),
+            vsatp: riscv::csr::vsatp::read(),
+            hvip: riscv::csr::hvip::read(),
+            time: (riscv::csr::time::read() as u64)
+                .wrapping_add(riscv::csr::htimedelta::read() as u64),
+        }
+    }
+
+    // This function loads the vCPU into `frame` and the CSRs.
+    // It returns htimedelta, with which the time of the guest continues from the capture.
+    pub fn load(&self, frame: &mut TrapFrame) -> u64 {
+        frame.regs = self.regs;
+        riscv::instruction::load_fp(&self.fregs, self.fcsr);
+        frame.pc = self.pc;
+        riscv::csr::vsstatus::write(self.vsstatus);
+        riscv::csr::vsie::write(self.vsie);
+        riscv::csr::vstvec::write(self.vstvec);
+        riscv::csr::vsscratch::write(self.vsscratch);
+        riscv::csr::vsepc::write(self.vsepc);
+        riscv::csr::vscause::write(self.vscause);
+        riscv::csr::vstval::write(self.vstval);
+        riscv::csr::vsatp::write(self.vsatp);
+        riscv::csr::hvip::write(self.hvip);
+        self.time.wrapping_sub(riscv::csr::time::read() as u64)
+    }
+
+    fn save(&self, w: &mut Writer) {
+        for r in self.regs.iter().chain(self.fregs.iter()) {
+            w.usize(*r);
+        }
+        for csr in [
+            self.fcsr,
+            self.pc,
+            self.vsstatus,
+            self.vsie,
+            self.vstvec,
+            self.vsscratch,
+            self.vsepc,
+            self.vscause,
+            self.vstval,
+            self.vsatp,
+            self.hvip,
+        ]
+        .iter()
+        {
+            w.usize(*csr);
+        }
+        w.u64(self.time);
+    }
+
+    fn restore(r: &mut Reader) -> Result<Vcpu, Error> {
+        let mut regs = [0; 64];
+        for reg in regs.iter_mut() {
+            *reg = r.usize()?;
+        }
+        let mut csrs = [0; 11];
+        for csr in csrs.iter_mut() {
+            *csr = r.usize()?;
+        }
+        let mut vcpu = Vcpu {
+            regs: [0; 32],
+            fregs: [0; 32],
+            fcsr: csrs[0],
+            pc: csrs[1],
+            vsstatus: csrs[2],
+            vsie: csrs[3],
+            vstvec: csrs[4],
+            vsscratch: csrs[5],
+            vsepc: csrs[6],
+            vscause: csrs[7],
+            vstval: csrs[8],
+            vsatp: csrs[9],
+            hvip: csrs[10],
+            time: r.u64()?,
+        };
+        vcpu.regs.copy_from_slice(&regs[..32]);
+        vcpu.fregs.copy_from_slice(&regs[32..]);
+        Ok(vcpu)
+    }
+}
+
+// Save & restore
+/////
+
+// This function writes a snapshot of the running guest to its region.
+// The guest resumes at `pc` with the registers in `frame` when it is restored.
+pub fn save(guest: &mut Guest, frame: &TrapFrame, pc: usize) -> Result<(), Error> {
+    let region = match guest.snapshot {
+        Some(r) => r,
+        None => return Err(Error::NotFound),
+    };
+    if guest.passthrough.is_some() || guest.imsic_file.is_some() {
+        return Err(Error::Unsupported);
+    }
+    log::info!("saving a snapshot of {}", guest.name);
+
+    // the old snapshot is broken from here
+    let mut header = [0u8; SECTOR_SIZE];
+    region.write(0, header.as_ptr(), 1)?;
+
+    // vCPU & devices
+    let mut w = Writer::new(region)?;
+    guest.save_timer();
+    w.u64(guest.timer);
+    Vcpu::capture(frame, pc).save(&mut w);
+    guest.irqchip.save(&mut w);
+    guest.rtc.save(&mut w);
+    w.bool(guest.pci.is_some());
+    if let Some(pci) = &guest.pci {
+        pci.save(&mut w);
+    }
+
+    // RAM
+    let mem = guest.memory();
+    let page_size = PAGE_SIZE as usize;
+    let ram = || {
+        mem.ram_ranges()
+            .flat_map(move |(start, size)| (start..start + size).step_by(page_size))
+    };
+    let page = paging::alloc().map_err(|_| Error::OutOfMemory)?;
+    let buf =
+        unsafe { core::slice::from_raw_parts_mut(page.address().to_usize() as *mut u8, page_size) };
+    for gpa in ram() {
+        w.u8(match mem.read(gpa, buf) {
+            Ok(()) if buf.iter().all(|b| *b == 0) => PAGE_ZERO,
+            Ok(()) => PAGE_DATA,
+            Err(_) => PAGE_ABSENT,
+        });
+    }
+    let mut pages = 0;
+    for gpa in ram() {
+        if mem.read(gpa, buf).is_ok() && buf.iter().any(|b| *b != 0) {
+            w.bytes(buf);
+            pages += 1;
+        }
+    }
+    paging::free(page);
+
+    // the overlay of the disk
+    // NOTE: without the overlay, the writes of the guest are already in the host disk
+    let mut chunks = 0;
+    if let Some(overlay) = guest.disk.overlay() {
+        w.u64(overlay.chunks() as u64);
+        overlay.for_each_chunk(|chunk, data| {
+            w.u64(chunk);
+            w.bytes(unsafe { core::slice::from_raw_parts(data, page_size) });
+            chunks += 1;
+        });
+    } else {
+        w.u64(0);
+    }
+    let (sectors, checksum) = w.finish()?;
+
+    // header
+    header[0..8].copy_from_slice(&MAGIC);
+    header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+    header[16..24].copy_from_slice(&(guest.dram_start as u64).to_le_bytes());
+    header[24..32].copy_from_slice(&(guest.dram_size as u64).to_le_bytes());
+    header[32..40].copy_from_slice(&sectors.to_le_bytes());
+    header[40..48].copy_from_slice(&checksum.to_le_bytes());
+    header[48..56].copy_from_slice(&(ram_size(guest) as u64).to_le_bytes());
+    region.flush()?;
+    region.write(0, header.as_ptr(), 1)?;
+    region.flush()?;
+    log::info!(
+        "-> {} pages of RAM and {} chunks of the disk ({} sectors)",
+        pages,
+        chunks,
+        sectors
+    );
+    Ok(())
+}
+
+// This function restores a fresh guest (made by `Guest::new`) from the snapshot in its region.
+// The vCPU is loaded when the guest is entered.
+// The whole stream (the states of the devices, the page map and the checksum) is checked before
+// the guest is changed, so the guest is left untouched on `NotFound` and `Incompatible`. On the
+// other errors, it may be partially restored (e.g. on an error of the disk) and must not run.
+pub fn restore(guest: &mut Guest) -> Result<(), Error> {
+    let region = match guest.snapshot {
+        Some(r) => r,
+        None => return Err(Error::NotFound),
+    };
+
+    // header
+    let mut header = [0u8; SECTOR_SIZE];
+    region.read(0, header.as_mut_ptr(), 1)?;
+    if header[0..8] != MAGIC {
+        return Err(Error::NotFound);
+    }
+    let field = |offset: usize| u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap());
+    let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    if version != VERSION
+        || field(16) != guest.dram_start as u64
+        || field(24) != guest.dram_size as u64
+        || field(48) != ram_size(guest) as u64
+        || guest.passthrough.is_some()
+        || guest.imsic_file.is_some()
+    {
+        return Err(Error::Incompatible);
+    }
+    let (sectors, checksum) = (field(32), field(40));
+    let page_size = PAGE_SIZE as usize;
+
+    // check the stream. the devices are restored to copies, and the data is skipped.
+    let mut r = Reader::new(region, sectors)?;
+    let devices = Devices::restore(guest, &mut r)?;
+    let mut map = alloc::vec![0u8; ram_size(guest) / page_size];
+    r.bytes(&mut map)?;
+    let ram_offset = r.offset();
+    let mut pages = 0;
+    for state in map.iter() {
+        match *state {
+            PAGE_ZERO | PAGE_ABSENT => {}
+            PAGE_DATA => pages += 1,
+            _ => return Err(Error::Corrupted),
+        }
+    }
+    r.skip(pages * page_size)?;
+    let chunks = r.u64()?;
+    // NOTE: without the overlay, the chunks can not be put back
+    if chunks > 0 && guest.disk.overlay().is_none() {
+        return Err(Error::Incompatible);
+    }
+    for _ in 0..chunks {
+        r.u64()?;
+        r.skip(page_size)?;
+    }
+    if r.finish() != checksum {
+        return Err(Error::Corrupted);
+    }
+    log::info!("restoring {} from the snapshot", guest.name);
+
+    // RAM
+    let mut r = Reader::new(region, sectors)?;
+    r.skip(ram_offset)?;
+    let mem = guest.memory();
+    let page = paging::alloc().map_err(|_| Error::OutOfMemory)?;
+    let buf =
+        unsafe { core::slice::from_raw_parts_mut(page.address().to_usize() as *mut u8, page_size) };
+    let mut result = Ok(());
+    let ram = mem
+        .ram_ranges()
+        .flat_map(move |(start, size)| (start..start + size).step_by(page_size));
+    for (gpa, state) in ram.zip(map.iter()) {
+        result = match *state {
+            PAGE_ZERO => {
+                for b in buf.iter_mut() {
+                    *b = 0;
+                }
+                mem.write(gpa, buf).map_err(|_| Error::OutOfMemory)
+            }
+            PAGE_DATA => r
+                .bytes(buf)
+                .and_then(|_| mem.write(gpa, buf).map_err(|_| Error::OutOfMemory)),
+            PAGE_ABSENT => mem.discard(gpa).map_err(|_| Error::Corrupted),
+            _ => Err(Error::Corrupted),
+        };
+        if result.is_err() {
+            break;
+        }
+    }
+    paging::free(page);
+    result?;
+
+    // the overlay of the disk
+    r.u64()?;
+    if let Some(overlay) = guest.disk.overlay() {
+        let data = paging::alloc().map_err(|_| Error::OutOfMemory)?;
+        let buf = unsafe {
+            core::slice::from_raw_parts_mut(data.address().to_usize() as *mut u8, page_size)
+        };
+        let mut result = Ok(());
+        for _ in 0..chunks {
+            result = r.u64().and_then(|chunk| {
+                r.bytes(buf)?;
+                Ok(overlay.put_chunk(chunk, buf.as_ptr())?)
+            });
+            if result.is_err() {
+                break;
+            }
+        }
+        paging::free(data);
+        result?;
+    }
+
+    // vCPU & devices
+    devices.load(guest);
+    Ok(())
+}
+
+// The state of the vCPU and the devices in a snapshot, which is loaded into the guest after the
+// whole snapshot is checked.
+struct Devices {
+    timer: u64,
+    vcpu: Vcpu,
+    irqchip: vdev::Irqchip,
+    rtc: vdev::rtc::Rtc,
+    pci: Option<vdev::pci::Bridge>,
+}
+
+impl Devices {
+    fn restore(guest: &Guest, r: &mut Reader) -> Result<Devices, Error> {
+        let timer = r.u64()?;
+        let vcpu = Vcpu::restore(r)?;
+        let mut irqchip = guest.irqchip.clone();
+        irqchip.restore(r)?;
+        let mut rtc = guest.rtc.clone();
+        rtc.restore(r)?;
+        if r.bool()? != guest.pci.is_some() {
+            return Err(Error::Incompatible);
+        }
+        let mut pci = guest.pci.clone();
+        if let Some(pci) = &mut pci {
+            pci.restore(r)?;
+        }
+        Ok(Devices {
+            timer: timer,
+            vcpu: vcpu,
+            irqchip: irqchip,
+            rtc: rtc,
+            pci: pci,
+        })
+    }
+
+    fn load(self, guest: &mut Guest) {
+        guest.timer = self.timer;
+        guest.irqchip = self.irqchip;
+        guest.rtc = self.rtc;
+        guest.pci = self.pci;
+        guest.resume_vcpu = Some(self.vcpu);
+    }
+}
+
+// the size of RAM of the guest (the main RAM and the other banks)
+fn ram_size(guest: &Guest) -> usize {
+    guest.memory().ram_ranges().map(|(_, size)| size).sum()
+}
+
+// Stream
+/////
+
+// This function continues the FNV-1a hash `h` with `data`.
+fn fnv1a(mut h: u64, data: &[u8]) -> u64 {
+    for b in data {
+        h = (h ^ *b as u64).wrapping_mul(0x100_0000_01b3);
+    }
+    h
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+
+// The writer of the state following the header. Values are buffered and written in large requests.
+// Errors are kept until `finish`, so that devices can write their state without checking each value.
+pub struct Writer {
+    region: blockdev::Region,
+    // the next sector to write
+    sector: u64,
+    buffer: paging::Page,
+    len: usize,
+    hash: u64,
+    error: Option<Error>,
+}
+
+impl Writer {
+    fn new(region: blockdev::Region) -> Result<Writer, Error> {
+        Ok(Writer {
+            region: region,
+            sector: 1,
+            buffer: paging::alloc_order(BUFFER_ORDER).map_err(|_| Error::OutOfMemory)?,
+            len: 0,
+            hash: FNV_OFFSET_BASIS,
+            error: None,
+        })
+    }
+
+    fn base(&self) -> *mut u8 {
+        self.buffer.address().to_usize() as *mut u8
+    }
+
+    // This function writes the buffer to the disk. The last sector is padded with zeros.
+    fn flush_buffer(&mut self) {
+        let sectors = (self.len + SECTOR_SIZE - 1) / SECTOR_SIZE;
+        unsafe {
+            core::ptr::write_bytes(
+                self.base().add(self.len),
+                0,
+                sectors * SECTOR_SIZE - self.len,
+            );
+        }
+        if self.error.is_none() {
+            if let Err(e) = self.region.write(self.sector, self.base(), sectors) {
+                self.error = Some(e.into());
+            }
+        }
+        self.sector += sectors as u64;
+        self.len = 0;
+    }
+
+    pub fn bytes(&mut self, data: &[u8]) {
+        self.hash = fnv1a(self.hash, data);
+        let mut done = 0;
+        while done < data.len() {
+            let n = core::cmp::min(BUFFER_SIZE - self.len, data.len() - done);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data.as_ptr().add(done),
+                    self.base().add(self.len),
+                    n,
+                );
+            }
+            self.len += n;
+            done += n;
+            if self.len == BUFFER_SIZE {
+                self.flush_buffer();
+            }
+        }
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.bytes(&[v]);
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.u8(v as u8);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn usize(&mut self, v: usize) {
+        self.u64(v as u64);
+    }
+
+    pub fn option_u32(&mut self, v: Option<u32>) {
+        self.bool(v.is_some());
+        self.u32(v.unwrap_or(0));
+    }
+
+    // This function writes the rest of the buffer, and returns the number of sectors of the
+    // stream (without the header) and its checksum.
+    fn finish(mut self) -> Result<(u64, u64), Error> {
+        if self.len > 0 {
+            self.flush_buffer();
+        }
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok((self.sector - 1, self.hash)),
+        }
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        paging::free(paging::Page::from_address(self.buffer.address()));
+    }
+}
+
+// The reader of the state following the header.
+pub struct Reader {
+    region: blockdev::Region,
+    // the next sector to read and the end of the stream
+    sector: u64,
+    end: u64,
+    buffer: paging::Page,
+    pos: usize,
+    len: usize,
+    hash: u64,
+}
+
+impl Reader {
+    fn new(region: blockdev::Region, sectors: u64) -> Result<Reader, Error> {
+        Ok(Reader {
+            region: region,
+            sector: 1,
+            end: 1 + sectors,
+            buffer: paging::alloc_order(BUFFER_ORDER).map_err(|_| Error::OutOfMemory)?,
+            pos: 0,
+            len: 0,
+            hash: FNV_OFFSET_BASIS,
+        })
+    }
+
+    fn base(&self) -> *mut u8 {
+        self.buffer.address().to_usize() as *mut u8
+    }
+
+    fn fill_buffer(&mut self) -> Result<(), Error> {
+        let sectors = core::cmp::min((BUFFER_SIZE / SECTOR_SIZE) as u64, self.end - self.sector);
+        if sectors == 0 {
+            return Err(Error::Corrupted);
+        }
+        self.region
+            .read(self.sector, self.base(), sectors as usize)?;
+        self.sector += sectors;
+        self.pos = 0;
+        self.len = sectors as usize * SECTOR_SIZE;
+        Ok(())
+    }
+
+    pub fn bytes(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        let mut done = 0;
+        while done < buf.len() {
+            if self.pos == self.len {
+                self.fill_buffer()?;
+            }
+            let n = core::cmp::min(self.len - self.pos, buf.len() - done);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    self.base().add(self.pos),
+                    buf.as_mut_ptr().add(done),
+                    n,
+                );
+            }
+            self.pos += n;
+            done += n;
+        }
+        self.hash = fnv1a(self.hash, buf);
+        Ok(())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, Error> {
+        let mut buf = [0; 1];
+        self.bytes(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, Error> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> Result<u16, Error> {
+        let mut buf = [0; 2];
+        self.bytes(&mut buf)?;
+        Ok(u16::from_le_bytes(buf))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, Error> {
+        let mut buf = [0; 4];
+        self.bytes(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, Error> {
+        let mut buf = [0; 8];
+        self.bytes(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    pub fn usize(&mut self) -> Result<usize, Error> {
+        Ok(self.u64()? as usize)
+    }
+
+    pub fn option_u32(&mut self) -> Result<Option<u32>, Error> {
+        let some = self.bool()?;
+        let v = self.u32()?;
+        Ok(if some { Some(v) } else { None })
+    }
+
+    // This function reads `len` bytes and throws them away.
+    fn skip(&mut self, mut len: usize) -> Result<(), Error> {
+        let mut buf = [0; 512];
+        while len > 0 {
+            let n = core::cmp::min(len, buf.len());
+            self.bytes(&mut buf[..n])?;
+            len -= n;
+        }
+        Ok(())
+    }
+
+    // the number of bytes read so far
+    fn offset(&self) -> usize {
+        (self.sector - 1) as usize * SECTOR_SIZE - (self.len - self.pos)
+    }
+
+    // This function returns the checksum of the values read so far.
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        paging::free(paging::Page::from_address(self.buffer.address()));
+    }
+}
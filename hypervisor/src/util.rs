@@ -1,2 +1,3 @@
+pub mod fdt;
 pub mod jump;
 pub mod logger;
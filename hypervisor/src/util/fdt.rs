@@ -0,0 +1,185 @@
+// Flattened device tree builder
+/////
+// This writes a device tree blob (version 17) into a given buffer, node by node.
+// Nodes and properties must be added in order, as they appear in the tree.
+
+use core::fmt::Error;
+
+const MAGIC: u32 = 0xd00d_feed;
+const VERSION: u32 = 17;
+const LAST_COMP_VERSION: u32 = 16;
+
+const BEGIN_NODE: u32 = 0x1;
+const END_NODE: u32 = 0x2;
+const PROP: u32 = 0x3;
+const END: u32 = 0x9;
+
+const HEADER_SIZE: usize = 40;
+// an empty memory reservation block (only the terminator)
+const RSVMAP_SIZE: usize = 16;
+const STRINGS_SIZE: usize = 1024;
+
+pub struct Builder<'a> {
+    buf: &'a mut [u8],
+    // the end of the structure block
+    pos: usize,
+    strings: [u8; STRINGS_SIZE],
+    strings_len: usize,
+    // set if the buffer becomes full; reported by `finish`
+    overflow: bool,
+}
+
+impl<'a> Builder<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Builder<'a> {
+        let mut b = Builder {
+            buf: buf,
+            pos: HEADER_SIZE,
+            strings: [0; STRINGS_SIZE],
+            strings_len: 0,
+            overflow: false,
+        };
+        b.push(&[0; RSVMAP_SIZE]);
+        b
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        if self.pos + data.len() > self.buf.len() {
+            self.overflow = true;
+            return;
+        }
+        self.buf[self.pos..self.pos + data.len()].copy_from_slice(data);
+        self.pos += data.len();
+    }
+
+    fn push_u32(&mut self, v: u32) {
+        self.push(&v.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        while self.pos % 4 != 0 && !self.overflow {
+            self.push(&[0]);
+        }
+    }
+
+    // This function returns the offset of `name` in the strings block, adding it if needed.
+    fn string_offset(&mut self, name: &str) -> u32 {
+        let mut offset = 0;
+        while offset < self.strings_len {
+            let end = offset
+                + self.strings[offset..self.strings_len]
+                    .iter()
+                    .position(|c| *c == 0)
+                    .unwrap_or(self.strings_len - offset);
+            if &self.strings[offset..end] == name.as_bytes() {
+                return offset as u32;
+            }
+            offset = end + 1;
+        }
+
+        if self.strings_len + name.len() + 1 > STRINGS_SIZE {
+            self.overflow = true;
+            return 0;
+        }
+        let offset = self.strings_len;
+        self.strings[offset..offset + name.len()].copy_from_slice(name.as_bytes());
+        self.strings[offset + name.len()] = 0;
+        self.strings_len += name.len() + 1;
+        offset as u32
+    }
+
+    fn property_header(&mut self, name: &str, len: usize) {
+        let nameoff = self.string_offset(name);
+        self.push_u32(PROP);
+        self.push_u32(len as u32);
+        self.push_u32(nameoff);
+    }
+
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(BEGIN_NODE);
+        self.push(name.as_bytes());
+        self.push(&[0]);
+        self.align();
+    }
+
+    pub fn end_node(&mut self) {
+        self.push_u32(END_NODE);
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        self.property_header(name, value.len());
+        self.push(value);
+        self.align();
+    }
+
+    pub fn property_empty(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        self.property_header(name, cells.len() * 4);
+        for c in cells {
+            self.push_u32(*c);
+        }
+    }
+
+    // This function adds a property with 2 address cells and 2 size cells.
+    pub fn property_reg(&mut self, name: &str, address: u64, size: u64) {
+        self.property_cells(
+            name,
+            &[
+                (address >> 32) as u32,
+                address as u32,
+                (size >> 32) as u32,
+                size as u32,
+            ],
+        );
+    }
+
+    pub fn property_str(&mut self, name: &str, value: &str) {
+        self.property_strs(name, &[value]);
+    }
+
+    pub fn property_strs(&mut self, name: &str, values: &[&str]) {
+        let len = values.iter().map(|v| v.len() + 1).sum();
+        self.property_header(name, len);
+        for v in values {
+            self.push(v.as_bytes());
+            self.push(&[0]);
+        }
+        self.align();
+    }
+
+    // This function completes the blob and returns its size.
+    pub fn finish(mut self) -> Result<usize, Error> {
+        self.push_u32(END);
+        let struct_offset = HEADER_SIZE + RSVMAP_SIZE;
+        let struct_size = self.pos - struct_offset;
+        let strings_offset = self.pos;
+        let strings = self.strings;
+        self.push(&strings[..self.strings_len]);
+        if self.overflow {
+            return Err(Error);
+        }
+
+        let header = [
+            MAGIC,
+            self.pos as u32,
+            struct_offset as u32,
+            strings_offset as u32,
+            HEADER_SIZE as u32,
+            VERSION,
+            LAST_COMP_VERSION,
+            0, // boot_cpuid_phys
+            self.strings_len as u32,
+            struct_size as u32,
+        ];
+        for (i, v) in header.iter().enumerate() {
+            self.buf[i * 4..i * 4 + 4].copy_from_slice(&v.to_be_bytes());
+        }
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,182 @@
+// Device models for guests
+/////
+// Emulated devices are not mapped in the G-stage page tables of guests.
+// Accesses to them cause guest-page faults, and the hypervisor emulates the faulting
+// load/store with the device models in this module.
+
+pub mod aplic;
+pub mod pci;
+pub mod plic;
+pub mod rtc;
+pub mod syscon;
+pub mod virtio;
+
+use crate::guest::Guest;
+use crate::hypervisor::TrapFrame;
+use crate::memlayout;
+use crate::riscv;
+use crate::snapshot;
+
+// the interrupt controller of a guest: an APLIC on AIA platforms, or a PLIC otherwise
+#[derive(Clone)]
+pub enum Irqchip {
+    Plic(plic::Plic),
+    Aplic(aplic::Aplic),
+}
+
+impl Irqchip {
+    pub fn base(&self) -> usize {
+        match self {
+            Irqchip::Plic(_) => memlayout::GUEST_PLIC_BASE,
+            Irqchip::Aplic(_) => memlayout::GUEST_APLIC_BASE,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            Irqchip::Plic(_) => memlayout::GUEST_PLIC_SIZE,
+            Irqchip::Aplic(_) => memlayout::GUEST_APLIC_SIZE,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        match self {
+            Irqchip::Plic(p) => *p = plic::Plic::new(),
+            Irqchip::Aplic(a) => *a = aplic::Aplic::new(),
+        }
+    }
+
+    // This function writes the state of the interrupt controller to a snapshot.
+    pub fn save(&self, w: &mut snapshot::Writer) {
+        match self {
+            Irqchip::Plic(p) => {
+                w.u8(0);
+                p.save(w);
+            }
+            Irqchip::Aplic(a) => {
+                w.u8(1);
+                a.save(w);
+            }
+        }
+    }
+
+    // NOTE: the kind of the controller (which depends on AIA of the host) must not change.
+    pub fn restore(&mut self, r: &mut snapshot::Reader) -> Result<(), snapshot::Error> {
+        match (r.u8()?, self) {
+            (0, Irqchip::Plic(p)) => p.restore(r),
+            (1, Irqchip::Aplic(a)) => a.restore(r),
+            _ => Err(snapshot::Error::Incompatible),
+        }
+    }
+
+    pub fn raise(&mut self, irq: u32) {
+        match self {
+            Irqchip::Plic(p) => p.raise(irq),
+            Irqchip::Aplic(a) => a.raise(irq),
+        }
+    }
+
+    pub fn lower(&mut self, irq: u32) {
+        match self {
+            Irqchip::Plic(p) => p.lower(irq),
+            Irqchip::Aplic(a) => a.lower(irq),
+        }
+    }
+
+    // whether the external interrupt for the guest should be asserted through hvip
+    pub fn eip(&self) -> bool {
+        match self {
+            Irqchip::Plic(p) => p.eip(),
+            Irqchip::Aplic(a) => a.eip(),
+        }
+    }
+
+    // the EIID of an interrupt to be sent to the guest interrupt file, if any
+    pub fn next_msi(&mut self) -> Option<u32> {
+        match self {
+            Irqchip::Plic(_) => None,
+            Irqchip::Aplic(a) => a.next_msi(),
+        }
+    }
+
+    // the interrupt which the guest has finished handling, if any
+    pub fn take_completed(&mut self) -> Option<u32> {
+        match self {
+            Irqchip::Plic(p) => p.take_completed(),
+            Irqchip::Aplic(a) => a.take_completed(),
+        }
+    }
+
+    pub fn read(&mut self, offset: usize) -> u32 {
+        match self {
+            Irqchip::Plic(p) => p.read(offset),
+            Irqchip::Aplic(a) => a.read(offset),
+        }
+    }
+
+    pub fn write(&mut self, offset: usize, value: u32) {
+        match self {
+            Irqchip::Plic(p) => p.write(offset, value),
+            Irqchip::Aplic(a) => a.write(offset, value),
+        }
+    }
+}
+
+fn fetch_trapped_instruction(guest: &Guest, sepc: usize) -> Option<u32> {
+    // htinst may hold a transformed instruction (bit 0 is set in that case).
+    // otherwise, we fetch the instruction from the guest memory.
+    let htinst = riscv::csr::htinst::read();
+    if htinst & 1 == 1 {
+        return Some(htinst as u32);
+    }
+
+    match guest.memory().fetch(sepc) {
+        Ok(inst) => Some(inst),
+        Err(_) => {
+            log::info!("mmio: failed to fetch the instruction at 0x{:016x}", sepc);
+            None
+        }
+    }
+}
+
+fn extend(value: u64, width: usize, signed: bool) -> u64 {
+    let shift = 64 - width * 8;
+    if signed {
+        (((value << shift) as i64) >> shift) as u64
+    } else {
+        (value << shift) >> shift
+    }
+}
+
+// This function emulates the load/store at `sepc` which accessed `gpa`.
+// It returns the address of the next instruction, or `None` if the access cannot be emulated.
+pub fn emulate_mmio(
+    guest: &mut Guest,
+    gpa: usize,
+    sepc: usize,
+    frame: *mut TrapFrame,
+) -> Option<usize> {
+    let inst = fetch_trapped_instruction(guest, sepc)?;
+    let access = match riscv::decode::decode_load_store(inst) {
+        Some(a) => a,
+        None => {
+            log::info!("mmio: unsupported instruction: 0x{:08x}", inst);
+            return None;
+        }
+    };
+
+    unsafe {
+        if access.is_store {
+            let value = extend((*frame).regs[access.reg] as u64, access.width, false);
+            if !guest.mmio_write(gpa, access.width, value) {
+                return None;
+            }
+        } else {
+            let value = guest.mmio_read(gpa, access.width)?;
+            if access.reg != 0 {
+                (*frame).regs[access.reg] = extend(value, access.width, access.signed) as usize;
+            }
+        }
+    }
+    Some(sepc + access.len)
+}
@@ -0,0 +1,477 @@
+// Virtual APLIC
+/////
+// This emulates an APLIC interrupt domain at supervisor level for a guest on AIA platforms.
+// Both delivery modes are implemented:
+// - direct delivery mode: the interrupt delivery control (IDC) of hart 0 drives hvip.VSEIP (see `Aplic::eip`).
+// - MSI delivery mode: interrupts are forwarded as MSIs into the guest interrupt file (see `Aplic::next_msi`).
+// NOTE: the guest has a single vCPU; hart and guest indices in target registers are hardwired to zero,
+// and MSIs are always written to the interrupt file of the guest regardless of the MSI address configuration.
+// Delegation to child domains is not supported.
+
+use crate::snapshot;
+
+pub const NUM_SOURCES: usize = 96;
+const WORDS: usize = NUM_SOURCES / 32;
+
+const DOMAINCFG: usize = 0x0000;
+const SOURCECFG_BASE: usize = 0x0004;
+const MSIADDRCFG_BASE: usize = 0x1bc0;
+const SETIP_BASE: usize = 0x1c00;
+const SETIPNUM: usize = 0x1cdc;
+const IN_CLRIP_BASE: usize = 0x1d00;
+const CLRIPNUM: usize = 0x1ddc;
+const SETIE_BASE: usize = 0x1e00;
+const SETIENUM: usize = 0x1edc;
+const CLRIE_BASE: usize = 0x1f00;
+const CLRIENUM: usize = 0x1fdc;
+const SETIPNUM_LE: usize = 0x2000;
+const SETIPNUM_BE: usize = 0x2004;
+const GENMSI: usize = 0x3000;
+const TARGET_BASE: usize = 0x3004;
+const IDC_BASE: usize = 0x4000;
+
+// registers of the IDC of hart 0
+const IDELIVERY: usize = 0x00;
+const IFORCE: usize = 0x04;
+const ITHRESHOLD: usize = 0x08;
+const TOPI: usize = 0x18;
+const CLAIMI: usize = 0x1c;
+
+// domaincfg
+const DOMAINCFG_IE: u32 = 1 << 8;
+const DOMAINCFG_DM: u32 = 1 << 2;
+// the highest byte reads as 0x80
+const DOMAINCFG_FIXED: u32 = 0x8000_0000;
+
+// source modes (sourcecfg.SM)
+const SM_INACTIVE: u32 = 0;
+const SM_DETACHED: u32 = 1;
+const SM_EDGE1: u32 = 4;
+const SM_EDGE0: u32 = 5;
+const SM_LEVEL1: u32 = 6;
+const SM_LEVEL0: u32 = 7;
+
+// target
+const TARGET_IPRIO_MASK: u32 = 0xff;
+const TARGET_EIID_MASK: u32 = 0x7ff;
+
+#[derive(Clone)]
+pub struct Aplic {
+    domaincfg: u32,
+    sourcecfg: [u32; NUM_SOURCES],
+    target: [u32; NUM_SOURCES],
+    pending: [u32; WORDS],
+    enabled: [u32; WORDS],
+    // the input levels of sources (before rectification)
+    input: [u32; WORDS],
+    msiaddrcfg: [u32; 4],
+    // an MSI requested through genmsi, which is not sent yet
+    genmsi: Option<u32>,
+    idelivery: u32,
+    iforce: u32,
+    ithreshold: u32,
+    // the source which the guest has finished handling (see `Aplic::take_completed`)
+    completed: Option<u32>,
+}
+
+fn bit(irq: usize) -> (usize, u32) {
+    (irq / 32, 1 << (irq % 32))
+}
+
+impl Aplic {
+    pub fn new() -> Aplic {
+        Aplic {
+            domaincfg: 0,
+            sourcecfg: [0; NUM_SOURCES],
+            target: [0; NUM_SOURCES],
+            pending: [0; WORDS],
+            enabled: [0; WORDS],
+            input: [0; WORDS],
+            msiaddrcfg: [0; 4],
+            genmsi: None,
+            idelivery: 0,
+            iforce: 0,
+            ithreshold: 0,
+            completed: None,
+        }
+    }
+
+    // This function writes the state of the device to a snapshot.
+    pub fn save(&self, w: &mut snapshot::Writer) {
+        w.u32(self.domaincfg);
+        for v in self
+            .sourcecfg
+            .iter()
+            .chain(self.target.iter())
+            .chain(self.pending.iter())
+            .chain(self.enabled.iter())
+            .chain(self.input.iter())
+            .chain(self.msiaddrcfg.iter())
+        {
+            w.u32(*v);
+        }
+        w.option_u32(self.genmsi);
+        w.u32(self.idelivery);
+        w.u32(self.iforce);
+        w.u32(self.ithreshold);
+        w.option_u32(self.completed);
+    }
+
+    pub fn restore(&mut self, r: &mut snapshot::Reader) -> Result<(), snapshot::Error> {
+        self.domaincfg = r.u32()?;
+        for v in self
+            .sourcecfg
+            .iter_mut()
+            .chain(self.target.iter_mut())
+            .chain(self.pending.iter_mut())
+            .chain(self.enabled.iter_mut())
+            .chain(self.input.iter_mut())
+            .chain(self.msiaddrcfg.iter_mut())
+        {
+            *v = r.u32()?;
+        }
+        self.genmsi = r.option_u32()?;
+        self.idelivery = r.u32()?;
+        self.iforce = r.u32()?;
+        self.ithreshold = r.u32()?;
+        self.completed = r.option_u32()?;
+        Ok(())
+    }
+
+    fn msi_mode(&self) -> bool {
+        self.domaincfg & DOMAINCFG_DM != 0
+    }
+
+    fn mode(&self, irq: usize) -> u32 {
+        match self.sourcecfg[irq] & 0x7 {
+            m @ SM_DETACHED | m @ SM_EDGE1 | m @ SM_EDGE0 | m @ SM_LEVEL1 | m @ SM_LEVEL0 => m,
+            _ => SM_INACTIVE,
+        }
+    }
+
+    fn is_level(&self, irq: usize) -> bool {
+        let mode = self.mode(irq);
+        mode == SM_LEVEL1 || mode == SM_LEVEL0
+    }
+
+    // the input of the source after rectification (inverted for EDGE0 and LEVEL0)
+    fn rectified(&self, irq: usize) -> bool {
+        let (i, mask) = bit(irq);
+        let level = self.input[i] & mask != 0;
+        match self.mode(irq) {
+            SM_EDGE1 | SM_LEVEL1 => level,
+            SM_EDGE0 | SM_LEVEL0 => !level,
+            _ => false,
+        }
+    }
+
+    fn set_pending(&mut self, irq: usize, pending: bool) {
+        let (i, mask) = bit(irq);
+        if pending {
+            self.pending[i] |= mask;
+        } else {
+            self.pending[i] &= !mask;
+        }
+    }
+
+    // This function handles setip/setipnum. The rules depend on the mode of the source.
+    fn request_pending(&mut self, irq: usize) {
+        if irq == 0 || irq >= NUM_SOURCES {
+            return;
+        }
+        match self.mode(irq) {
+            SM_INACTIVE => {}
+            // the pending bit of a level-sensitive source follows its input in direct delivery mode
+            SM_LEVEL1 | SM_LEVEL0 if !self.msi_mode() => {}
+            SM_LEVEL1 | SM_LEVEL0 => {
+                if self.rectified(irq) {
+                    self.set_pending(irq, true);
+                }
+            }
+            _ => self.set_pending(irq, true),
+        }
+    }
+
+    // This function handles in_clrip/clripnum.
+    fn clear_pending(&mut self, irq: usize) {
+        if irq == 0 || irq >= NUM_SOURCES {
+            return;
+        }
+        if self.is_level(irq) && !self.msi_mode() {
+            return;
+        }
+        self.set_pending(irq, false);
+    }
+
+    fn set_enabled(&mut self, irq: usize, enabled: bool) {
+        if irq == 0 || irq >= NUM_SOURCES || self.mode(irq) == SM_INACTIVE {
+            return;
+        }
+        let (i, mask) = bit(irq);
+        if enabled {
+            self.enabled[i] |= mask;
+        } else {
+            self.enabled[i] &= !mask;
+        }
+    }
+
+    fn set_input(&mut self, irq: usize, level: bool) {
+        if irq == 0 || irq >= NUM_SOURCES {
+            log::info!("vaplic: invalid interrupt source: {}", irq);
+            return;
+        }
+        let old = self.rectified(irq);
+        let (i, mask) = bit(irq);
+        if level {
+            self.input[i] |= mask;
+        } else {
+            self.input[i] &= !mask;
+        }
+        let new = self.rectified(irq);
+        match self.mode(irq) {
+            SM_EDGE1 | SM_EDGE0 => {
+                if !old && new {
+                    self.set_pending(irq, true);
+                }
+            }
+            SM_LEVEL1 | SM_LEVEL0 => {
+                if !self.msi_mode() {
+                    self.set_pending(irq, new);
+                } else if !new {
+                    self.set_pending(irq, false);
+                } else if !old {
+                    self.set_pending(irq, true);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // This function asserts the input of the source `irq`.
+    pub fn raise(&mut self, irq: u32) {
+        self.set_input(irq as usize, true);
+    }
+
+    // This function de-asserts the input of the source `irq`.
+    pub fn lower(&mut self, irq: u32) {
+        self.set_input(irq as usize, false);
+    }
+
+    fn deliverable(&self, irq: usize) -> bool {
+        let (i, mask) = bit(irq);
+        self.domaincfg & DOMAINCFG_IE != 0
+            && self.pending[i] & self.enabled[i] & mask != 0
+            && self.mode(irq) != SM_INACTIVE
+    }
+
+    // This function returns the value of topi of the IDC (direct delivery mode).
+    fn topi(&self) -> u32 {
+        if self.msi_mode() {
+            return 0;
+        }
+        let mut best: Option<(usize, u32)> = None;
+        for irq in 1..NUM_SOURCES {
+            if !self.deliverable(irq) {
+                continue;
+            }
+            let prio = self.target[irq] & TARGET_IPRIO_MASK;
+            if self.ithreshold != 0 && prio >= self.ithreshold {
+                continue;
+            }
+            match best {
+                Some((_, p)) if p <= prio => {}
+                _ => best = Some((irq, prio)),
+            }
+        }
+        match best {
+            Some((irq, prio)) => ((irq as u32) << 16) | prio,
+            None => 0,
+        }
+    }
+
+    // whether the external interrupt for the guest should be asserted (direct delivery mode)
+    pub fn eip(&self) -> bool {
+        !self.msi_mode() && self.idelivery & 1 != 0 && (self.topi() != 0 || self.iforce & 1 != 0)
+    }
+
+    fn claim(&mut self) -> u32 {
+        let topi = self.topi();
+        if topi == 0 {
+            self.iforce = 0;
+            return 0;
+        }
+        let irq = (topi >> 16) as usize;
+        if !self.is_level(irq) {
+            self.set_pending(irq, false);
+        }
+        self.completed = Some(irq as u32);
+        topi
+    }
+
+    // This function returns the EIID of an interrupt to be sent to the guest interrupt file (MSI delivery mode).
+    // The interrupt is no longer pending after this call.
+    pub fn next_msi(&mut self) -> Option<u32> {
+        if !self.msi_mode() {
+            return None;
+        }
+        if let Some(eiid) = self.genmsi.take() {
+            return Some(eiid);
+        }
+        for irq in 1..NUM_SOURCES {
+            if self.deliverable(irq) {
+                self.set_pending(irq, false);
+                return Some(self.target[irq] & TARGET_EIID_MASK);
+            }
+        }
+        None
+    }
+
+    // This function returns the source which the guest has finished handling, if any:
+    // the one claimed through claimi (direct delivery mode), or re-triggered through setipnum (MSI delivery mode).
+    pub fn take_completed(&mut self) -> Option<u32> {
+        self.completed.take()
+    }
+
+    fn bits_of(&self, v: &[u32; WORDS], offset: usize) -> u32 {
+        let i = offset / 4;
+        if i < WORDS {
+            v[i]
+        } else {
+            0
+        }
+    }
+
+    // NOTE: the APLIC registers are 32-bit wide; other accesses are treated as 32-bit ones.
+    pub fn read(&mut self, offset: usize) -> u32 {
+        match offset {
+            DOMAINCFG => DOMAINCFG_FIXED | self.domaincfg,
+            o if o >= SOURCECFG_BASE && o < SOURCECFG_BASE + (NUM_SOURCES - 1) * 4 => {
+                self.sourcecfg[(o - SOURCECFG_BASE) / 4 + 1]
+            }
+            o if o >= MSIADDRCFG_BASE && o < MSIADDRCFG_BASE + 16 => {
+                self.msiaddrcfg[(o - MSIADDRCFG_BASE) / 4]
+            }
+            o if o >= SETIP_BASE && o < SETIPNUM => self.bits_of(&self.pending, o - SETIP_BASE),
+            o if o >= IN_CLRIP_BASE && o < CLRIPNUM => {
+                // rectified inputs
+                let i = (o - IN_CLRIP_BASE) / 4;
+                let mut v = 0;
+                for b in 0..32 {
+                    let irq = i * 32 + b;
+                    if irq > 0 && irq < NUM_SOURCES && self.rectified(irq) {
+                        v |= 1 << b;
+                    }
+                }
+                v
+            }
+            o if o >= SETIE_BASE && o < SETIENUM => self.bits_of(&self.enabled, o - SETIE_BASE),
+            GENMSI => 0,
+            o if o >= TARGET_BASE && o < TARGET_BASE + (NUM_SOURCES - 1) * 4 => {
+                self.target[(o - TARGET_BASE) / 4 + 1]
+            }
+            o if o >= IDC_BASE => match o - IDC_BASE {
+                IDELIVERY => self.idelivery,
+                IFORCE => self.iforce,
+                ITHRESHOLD => self.ithreshold,
+                TOPI => self.topi(),
+                CLAIMI => self.claim(),
+                _ => 0,
+            },
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: usize, value: u32) {
+        match offset {
+            DOMAINCFG => {
+                self.domaincfg = value & (DOMAINCFG_IE | DOMAINCFG_DM);
+            }
+            o if o >= SOURCECFG_BASE && o < SOURCECFG_BASE + (NUM_SOURCES - 1) * 4 => {
+                let irq = (o - SOURCECFG_BASE) / 4 + 1;
+                // delegation (bit 10) is not supported
+                self.sourcecfg[irq] = value & 0x7;
+                if self.mode(irq) == SM_INACTIVE {
+                    self.sourcecfg[irq] = 0;
+                    self.target[irq] = 0;
+                    let (i, mask) = bit(irq);
+                    self.pending[i] &= !mask;
+                    self.enabled[i] &= !mask;
+                } else if self.is_level(irq) {
+                    let rectified = self.rectified(irq);
+                    self.set_pending(irq, rectified);
+                }
+            }
+            o if o >= MSIADDRCFG_BASE && o < MSIADDRCFG_BASE + 16 => {
+                self.msiaddrcfg[(o - MSIADDRCFG_BASE) / 4] = value;
+            }
+            o if o >= SETIP_BASE && o < SETIPNUM => {
+                let base = (o - SETIP_BASE) / 4 * 32;
+                for b in 0..32 {
+                    if value & (1 << b) != 0 {
+                        self.request_pending(base + b);
+                    }
+                }
+            }
+            SETIPNUM | SETIPNUM_LE => {
+                self.request_pending(value as usize);
+                self.completed = Some(value);
+            }
+            SETIPNUM_BE => {
+                let irq = value.swap_bytes();
+                self.request_pending(irq as usize);
+                self.completed = Some(irq);
+            }
+            o if o >= IN_CLRIP_BASE && o < CLRIPNUM => {
+                let base = (o - IN_CLRIP_BASE) / 4 * 32;
+                for b in 0..32 {
+                    if value & (1 << b) != 0 {
+                        self.clear_pending(base + b);
+                    }
+                }
+            }
+            CLRIPNUM => self.clear_pending(value as usize),
+            o if o >= SETIE_BASE && o < SETIENUM => {
+                let base = (o - SETIE_BASE) / 4 * 32;
+                for b in 0..32 {
+                    if value & (1 << b) != 0 {
+                        self.set_enabled(base + b, true);
+                    }
+                }
+            }
+            SETIENUM => self.set_enabled(value as usize, true),
+            o if o >= CLRIE_BASE && o < CLRIENUM => {
+                let base = (o - CLRIE_BASE) / 4 * 32;
+                for b in 0..32 {
+                    if value & (1 << b) != 0 {
+                        self.set_enabled(base + b, false);
+                    }
+                }
+            }
+            CLRIENUM => self.set_enabled(value as usize, false),
+            GENMSI => {
+                if self.msi_mode() {
+                    self.genmsi = Some(value & TARGET_EIID_MASK);
+                }
+            }
+            o if o >= TARGET_BASE && o < TARGET_BASE + (NUM_SOURCES - 1) * 4 => {
+                let irq = (o - TARGET_BASE) / 4 + 1;
+                if self.mode(irq) == SM_INACTIVE {
+                    return;
+                }
+                self.target[irq] = if self.msi_mode() {
+                    value & TARGET_EIID_MASK
+                } else {
+                    // IPRIO 0 is not valid and is replaced with 1
+                    core::cmp::max(value & TARGET_IPRIO_MASK, 1)
+                };
+            }
+            o if o >= IDC_BASE => match o - IDC_BASE {
+                IDELIVERY => self.idelivery = value & 1,
+                IFORCE => self.iforce = value & 1,
+                ITHRESHOLD => self.ithreshold = value & TARGET_IPRIO_MASK,
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+}
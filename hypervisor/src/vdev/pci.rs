@@ -0,0 +1,360 @@
+// Virtual PCIe host bridge
+/////
+// An ECAM host bridge (bus 0 only) on which the virtio device models (see `vdev::virtio`) are
+// presented to a guest as virtio-pci functions. Device `n` is function 0 of device number `n`.
+// Each function has one 64-bit memory BAR (BAR0) holding the virtio structures:
+//   0x0000: common configuration
+//   0x1000: ISR status
+//   0x2000: device-specific configuration
+//   0x3000: notifications
+// The legacy interrupt (INTA) of device `n` is swizzled to `GUEST_PCIE_IRQ + n % 4`, as on QEMU virt machine.
+
+use crate::blockdev;
+use crate::guest::Memory;
+use crate::snapshot;
+use crate::vdev::virtio;
+
+pub const MAX_FUNCTIONS: usize = 4;
+pub const BAR_SIZE: usize = 0x4000;
+
+const COMMON_OFFSET: usize = 0x0000;
+const ISR_OFFSET: usize = 0x1000;
+const DEVICE_OFFSET: usize = 0x2000;
+const NOTIFY_OFFSET: usize = 0x3000;
+const STRUCTURE_SIZE: usize = 0x1000;
+const NOTIFY_OFF_MULTIPLIER: usize = 4;
+
+const VENDOR_ID: u32 = 0x1af4;
+// modern virtio devices have the device ID 0x1040 + the virtio device ID
+const DEVICE_ID_BASE: u32 = 0x1040;
+const SUBSYSTEM_ID: u32 = 0x1100;
+const REVISION_ID: u32 = 1;
+
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+const STATUS_INTERRUPT: u32 = 1 << 3;
+const STATUS_CAPABILITIES: u32 = 1 << 4;
+const BAR_TYPE_64: u32 = 0b10 << 1;
+const INTERRUPT_PIN_INTA: u32 = 1;
+
+// virtio-pci capabilities (struct virtio_pci_cap) in the config space
+const CAP_BASE: usize = 0x40;
+const CAP_SIZE: usize = 0x10;
+const CAP_VENDOR: u32 = 0x09;
+const VIRTIO_PCI_CAP_COMMON_CFG: u32 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u32 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u32 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u32 = 4;
+// (cfg_type, offset in BAR0) of each capability. the notification capability is the last one,
+// since it has an extra field (notify_off_multiplier).
+const CAPS: [(u32, usize); 4] = [
+    (VIRTIO_PCI_CAP_COMMON_CFG, COMMON_OFFSET),
+    (VIRTIO_PCI_CAP_ISR_CFG, ISR_OFFSET),
+    (VIRTIO_PCI_CAP_DEVICE_CFG, DEVICE_OFFSET),
+    (VIRTIO_PCI_CAP_NOTIFY_CFG, NOTIFY_OFFSET),
+];
+
+#[derive(Clone)]
+struct Function {
+    device: virtio::Device,
+    command: u16,
+    bar: u64,
+    interrupt_line: u8,
+}
+
+impl Function {
+    fn class(&self) -> u32 {
+        match self.device.kind {
+            // mass storage (other)
+            virtio::DeviceType::Block => 0x01_80_00,
+            // ethernet
+            virtio::DeviceType::Net => 0x02_00_00,
+            // communication (other)
+            virtio::DeviceType::Console => 0x07_80_00,
+            // unclassified
+            virtio::DeviceType::Rng | virtio::DeviceType::Balloon => 0xff_00_00,
+        }
+    }
+
+    fn decodes(&self, gpa: usize) -> bool {
+        self.command & COMMAND_MEMORY != 0
+            && self.bar != 0
+            && self.bar as usize <= gpa
+            // a BAR at the end of the address space (written by the guest) does not decode
+            && match (self.bar as usize).checked_add(BAR_SIZE) {
+                Some(end) => gpa < end,
+                None => false,
+            }
+    }
+
+    // the dword of the config space at `offset` (4-byte aligned)
+    fn read_dword(&self, offset: usize) -> u32 {
+        match offset {
+            0x00 => ((DEVICE_ID_BASE + self.device.kind as u32) << 16) | VENDOR_ID,
+            0x04 => {
+                let status = if self.device.irq_level() {
+                    STATUS_CAPABILITIES | STATUS_INTERRUPT
+                } else {
+                    STATUS_CAPABILITIES
+                };
+                (status << 16) | self.command as u32
+            }
+            0x08 => (self.class() << 8) | REVISION_ID,
+            // BAR0 (lower & upper half)
+            0x10 => (self.bar as u32 & !(BAR_SIZE as u32 - 1)) | BAR_TYPE_64,
+            0x14 => (self.bar >> 32) as u32,
+            0x2c => (SUBSYSTEM_ID << 16) | VENDOR_ID,
+            0x34 => CAP_BASE as u32,
+            0x3c => (INTERRUPT_PIN_INTA << 8) | self.interrupt_line as u32,
+            o if CAP_BASE <= o && o < CAP_BASE + CAP_SIZE * CAPS.len() + 4 => {
+                read_cap(o - CAP_BASE)
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_dword(&mut self, offset: usize, value: u32, mask: u32) {
+        match offset {
+            0x04 => {
+                let command = (self.command as u32 & !mask) | (value & mask);
+                self.command =
+                    command as u16 & (COMMAND_MEMORY | COMMAND_BUS_MASTER | COMMAND_INTX_DISABLE);
+            }
+            // the lower bits are hardwired to zero, so that the driver can determine the size
+            0x10 => {
+                let low = (self.bar as u32 & !mask) | (value & mask);
+                self.bar = (self.bar & !0xffff_ffff) | (low & !(BAR_SIZE as u32 - 1)) as u64;
+            }
+            0x14 => {
+                let high = ((self.bar >> 32) as u32 & !mask) | (value & mask);
+                self.bar = (self.bar & 0xffff_ffff) | ((high as u64) << 32);
+            }
+            0x3c => {
+                if mask & 0xff != 0 {
+                    self.interrupt_line = value as u8;
+                }
+            }
+            // read-only or unimplemented
+            _ => {}
+        }
+    }
+}
+
+// This function returns the dword at `offset` of the virtio-pci capabilities.
+fn read_cap(offset: usize) -> u32 {
+    let index = core::cmp::min(offset / CAP_SIZE, CAPS.len() - 1);
+    let (cfg_type, bar_offset) = CAPS[index];
+    let is_notify = cfg_type == VIRTIO_PCI_CAP_NOTIFY_CFG;
+    match offset - index * CAP_SIZE {
+        // cap_vndr, cap_next, cap_len, cfg_type
+        0x0 => {
+            let next = if is_notify {
+                0
+            } else {
+                CAP_BASE + (index + 1) * CAP_SIZE
+            };
+            let len = if is_notify { CAP_SIZE + 4 } else { CAP_SIZE };
+            CAP_VENDOR | (next as u32) << 8 | (len as u32) << 16 | cfg_type << 24
+        }
+        // bar, id, padding
+        0x4 => 0,
+        0x8 => bar_offset as u32,
+        0xc => STRUCTURE_SIZE as u32,
+        0x10 if is_notify => NOTIFY_OFF_MULTIPLIER as u32,
+        _ => 0,
+    }
+}
+
+fn mask(width: usize) -> u64 {
+    if width >= 8 {
+        u64::MAX
+    } else {
+        (1 << (width * 8)) - 1
+    }
+}
+
+#[derive(Clone)]
+pub struct Bridge {
+    functions: [Option<Function>; MAX_FUNCTIONS],
+}
+
+impl Bridge {
+    pub fn new(devices: &[virtio::DeviceType], disk: &blockdev::Disk) -> Bridge {
+        if devices.len() > MAX_FUNCTIONS {
+            panic!("too many virtio-pci devices: {}", devices.len());
+        }
+        let mut functions = [None, None, None, None];
+        for (i, kind) in devices.iter().enumerate() {
+            functions[i] = Some(Function {
+                device: virtio::Device::new(*kind, disk, i),
+                command: 0,
+                bar: 0,
+                interrupt_line: 0,
+            });
+        }
+        Bridge {
+            functions: functions,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        for f in self.functions.iter_mut().flatten() {
+            f.device.reset();
+            f.command = 0;
+            f.bar = 0;
+            f.interrupt_line = 0;
+        }
+    }
+
+    // the function at `offset` of the ECAM space, if any
+    fn function(&mut self, offset: usize) -> Option<&mut Function> {
+        let bus = offset >> 20;
+        let device = (offset >> 15) & 0x1f;
+        let function = (offset >> 12) & 0x7;
+        if bus != 0 || function != 0 || device >= MAX_FUNCTIONS {
+            return None;
+        }
+        self.functions[device].as_mut()
+    }
+
+    // This function handles a read at `offset` of the ECAM space.
+    pub fn read_config(&mut self, offset: usize, width: usize) -> u64 {
+        let f = match self.function(offset) {
+            Some(f) => f,
+            // no device: all ones
+            None => return mask(width),
+        };
+        let reg = offset & 0xfff;
+        let aligned = reg & !0x3;
+        let mut value = f.read_dword(aligned) as u64;
+        if width == 8 {
+            value |= (f.read_dword(aligned + 4) as u64) << 32;
+        }
+        (value >> ((reg - aligned) * 8)) & mask(width)
+    }
+
+    // This function handles a write at `offset` of the ECAM space.
+    pub fn write_config(&mut self, offset: usize, width: usize, value: u64) {
+        let f = match self.function(offset) {
+            Some(f) => f,
+            None => return,
+        };
+        let reg = offset & 0xfff;
+        let aligned = reg & !0x3;
+        let shift = (reg - aligned) * 8;
+        let value = value << shift;
+        let mask = mask(width) << shift;
+        f.write_dword(aligned, value as u32, mask as u32);
+        if width == 8 {
+            f.write_dword(aligned + 4, (value >> 32) as u32, (mask >> 32) as u32);
+        }
+    }
+
+    // This function handles a read in the memory window. It returns `None` if no BAR is there.
+    pub fn read_bar(&mut self, gpa: usize, width: usize) -> Option<u64> {
+        let f = self
+            .functions
+            .iter_mut()
+            .flatten()
+            .find(|f| f.decodes(gpa))?;
+        let offset = gpa - f.bar as usize;
+        Some(match offset {
+            o if o < COMMON_OFFSET + virtio::COMMON_CFG_SIZE => f.device.read_common(o, width),
+            o if o == ISR_OFFSET => f.device.read_isr() as u64,
+            o if DEVICE_OFFSET <= o && o < DEVICE_OFFSET + virtio::CONFIG_SIZE => {
+                f.device.read_config(o - DEVICE_OFFSET, width)
+            }
+            _ => 0,
+        })
+    }
+
+    // This function handles a write in the memory window. It returns `false` if no BAR is there.
+    pub fn write_bar(
+        &mut self,
+        gpa: usize,
+        width: usize,
+        value: u64,
+        mem: &Memory,
+        disk: &mut blockdev::Disk,
+    ) -> bool {
+        let f = match self.functions.iter_mut().flatten().find(|f| f.decodes(gpa)) {
+            Some(f) => f,
+            None => return false,
+        };
+        let offset = gpa - f.bar as usize;
+        match offset {
+            o if o < COMMON_OFFSET + virtio::COMMON_CFG_SIZE => {
+                f.device.write_common(o, width, value)
+            }
+            o if NOTIFY_OFFSET <= o && o < NOTIFY_OFFSET + STRUCTURE_SIZE => {
+                // DMA is done only when the driver allows it
+                if f.command & COMMAND_BUS_MASTER != 0 {
+                    let queue = (o - NOTIFY_OFFSET) / NOTIFY_OFF_MULTIPLIER;
+                    f.device.notify(queue, mem, disk);
+                }
+            }
+            o if DEVICE_OFFSET <= o && o < DEVICE_OFFSET + virtio::CONFIG_SIZE => {
+                f.device.write_config(o - DEVICE_OFFSET, width, value)
+            }
+            _ => {}
+        }
+        true
+    }
+
+    // This function writes the state of the functions to a snapshot.
+    pub fn save(&self, w: &mut snapshot::Writer) {
+        for f in self.functions.iter() {
+            match f {
+                Some(f) => {
+                    w.u8(f.device.kind as u8);
+                    w.u16(f.command);
+                    w.u64(f.bar);
+                    w.u8(f.interrupt_line);
+                    f.device.save(w);
+                }
+                None => w.u8(0),
+            }
+        }
+    }
+
+    // NOTE: the functions must be the same as when the snapshot was taken.
+    pub fn restore(&mut self, r: &mut snapshot::Reader) -> Result<(), snapshot::Error> {
+        for f in self.functions.iter_mut() {
+            let kind = r.u8()?;
+            match f {
+                Some(f) if f.device.kind as u8 == kind => {
+                    f.command = r.u16()?;
+                    f.bar = r.u64()?;
+                    f.interrupt_line = r.u8()?;
+                    f.device.restore(r)?;
+                }
+                None if kind == 0 => {}
+                _ => return Err(snapshot::Error::Incompatible),
+            }
+        }
+        Ok(())
+    }
+
+    // the balloon device, if any
+    pub fn balloon(&mut self) -> Option<&mut virtio::Device> {
+        self.functions
+            .iter_mut()
+            .flatten()
+            .map(|f| &mut f.device)
+            .find(|d| d.kind == virtio::DeviceType::Balloon)
+    }
+
+    // the levels of the INTx lines (INTA - INTD after swizzling)
+    pub fn intx_levels(&self) -> [bool; 4] {
+        let mut levels = [false; 4];
+        for (i, f) in self.functions.iter().enumerate() {
+            if let Some(f) = f {
+                if f.device.irq_level() && f.command & COMMAND_INTX_DISABLE == 0 {
+                    levels[i % 4] = true;
+                }
+            }
+        }
+        levels
+    }
+}
@@ -0,0 +1,226 @@
+// Virtual PLIC
+/////
+// This emulates the PLIC of QEMU virt machine for a guest.
+// Only the S-mode context of hart 0 (context 1) is implemented; the registers of the other
+// contexts read as zero and ignore writes.
+// Interrupts are delivered to the guest through hvip.VSEIP (see `Plic::eip`).
+
+use crate::snapshot;
+
+pub const NUM_SOURCES: usize = 96;
+const WORDS: usize = NUM_SOURCES / 32;
+
+const PRIORITY_BASE: usize = 0x0000;
+const PENDING_BASE: usize = 0x1000;
+const ENABLE_BASE: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_BASE: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+
+// the context for S-mode of hart 0
+const CONTEXT: usize = 1;
+
+#[derive(Clone)]
+pub struct Plic {
+    priority: [u32; NUM_SOURCES],
+    pending: [u32; WORDS],
+    enable: [u32; WORDS],
+    // interrupts which were claimed but not completed yet
+    in_service: [u32; WORDS],
+    threshold: u32,
+    // the interrupt which the guest has completed (see `Plic::take_completed`)
+    completed: Option<u32>,
+}
+
+fn bit(irq: usize) -> (usize, u32) {
+    (irq / 32, 1 << (irq % 32))
+}
+
+impl Plic {
+    pub fn new() -> Plic {
+        Plic {
+            priority: [0; NUM_SOURCES],
+            pending: [0; WORDS],
+            enable: [0; WORDS],
+            in_service: [0; WORDS],
+            threshold: 0,
+            completed: None,
+        }
+    }
+
+    // This function writes the state of the device to a snapshot.
+    pub fn save(&self, w: &mut snapshot::Writer) {
+        for v in self
+            .priority
+            .iter()
+            .chain(self.pending.iter())
+            .chain(self.enable.iter())
+            .chain(self.in_service.iter())
+        {
+            w.u32(*v);
+        }
+        w.u32(self.threshold);
+        w.option_u32(self.completed);
+    }
+
+    pub fn restore(&mut self, r: &mut snapshot::Reader) -> Result<(), snapshot::Error> {
+        for v in self
+            .priority
+            .iter_mut()
+            .chain(self.pending.iter_mut())
+            .chain(self.enable.iter_mut())
+            .chain(self.in_service.iter_mut())
+        {
+            *v = r.u32()?;
+        }
+        self.threshold = r.u32()?;
+        self.completed = r.option_u32()?;
+        Ok(())
+    }
+
+    // This function makes the interrupt `irq` pending.
+    pub fn raise(&mut self, irq: u32) {
+        let irq = irq as usize;
+        if irq == 0 || irq >= NUM_SOURCES {
+            log::info!("vplic: invalid interrupt source: {}", irq);
+            return;
+        }
+        let (i, mask) = bit(irq);
+        self.pending[i] |= mask;
+    }
+
+    // This function withdraws the pending interrupt `irq` (e.g. when a level-triggered source is de-asserted).
+    pub fn lower(&mut self, irq: u32) {
+        let irq = irq as usize;
+        if irq == 0 || irq >= NUM_SOURCES {
+            return;
+        }
+        let (i, mask) = bit(irq);
+        self.pending[i] &= !mask;
+    }
+
+    // This function returns the interrupt with the highest priority which can be claimed.
+    fn best(&self) -> Option<usize> {
+        let mut best: Option<usize> = None;
+        for irq in 1..NUM_SOURCES {
+            let (i, mask) = bit(irq);
+            let ready = self.pending[i] & self.enable[i] & !self.in_service[i] & mask != 0;
+            if ready && self.priority[irq] > self.threshold {
+                match best {
+                    Some(b) if self.priority[b] >= self.priority[irq] => {}
+                    _ => best = Some(irq),
+                }
+            }
+        }
+        best
+    }
+
+    // whether the external interrupt for the guest should be asserted
+    pub fn eip(&self) -> bool {
+        self.best().is_some()
+    }
+
+    fn claim(&mut self) -> u32 {
+        match self.best() {
+            Some(irq) => {
+                let (i, mask) = bit(irq);
+                self.pending[i] &= !mask;
+                self.in_service[i] |= mask;
+                irq as u32
+            }
+            None => 0,
+        }
+    }
+
+    fn complete(&mut self, irq: u32) {
+        let irq = irq as usize;
+        if irq == 0 || irq >= NUM_SOURCES {
+            return;
+        }
+        let (i, mask) = bit(irq);
+        if self.in_service[i] & mask == 0 {
+            return;
+        }
+        self.in_service[i] &= !mask;
+        self.completed = Some(irq as u32);
+    }
+
+    // This function returns the interrupt which the guest has completed since the last call, if any.
+    pub fn take_completed(&mut self) -> Option<u32> {
+        self.completed.take()
+    }
+
+    // NOTE: the PLIC registers are 32-bit wide; other accesses are treated as 32-bit ones.
+    pub fn read(&mut self, offset: usize) -> u32 {
+        match offset {
+            o if o < PENDING_BASE => {
+                let irq = (o - PRIORITY_BASE) / 4;
+                if irq < NUM_SOURCES {
+                    self.priority[irq]
+                } else {
+                    0
+                }
+            }
+            o if o < ENABLE_BASE => {
+                let i = (o - PENDING_BASE) / 4;
+                if i < WORDS {
+                    self.pending[i]
+                } else {
+                    0
+                }
+            }
+            o if o < CONTEXT_BASE => {
+                let context = (o - ENABLE_BASE) / ENABLE_STRIDE;
+                let i = (o - ENABLE_BASE) % ENABLE_STRIDE / 4;
+                if context == CONTEXT && i < WORDS {
+                    self.enable[i]
+                } else {
+                    0
+                }
+            }
+            o => {
+                let context = (o - CONTEXT_BASE) / CONTEXT_STRIDE;
+                if context != CONTEXT {
+                    return 0;
+                }
+                match (o - CONTEXT_BASE) % CONTEXT_STRIDE {
+                    0 => self.threshold,
+                    4 => self.claim(),
+                    _ => 0,
+                }
+            }
+        }
+    }
+
+    pub fn write(&mut self, offset: usize, value: u32) {
+        match offset {
+            o if o < PENDING_BASE => {
+                let irq = (o - PRIORITY_BASE) / 4;
+                if irq > 0 && irq < NUM_SOURCES {
+                    self.priority[irq] = value & 0x7;
+                }
+            }
+            // pending bits are read-only
+            o if o < ENABLE_BASE => {}
+            o if o < CONTEXT_BASE => {
+                let context = (o - ENABLE_BASE) / ENABLE_STRIDE;
+                let i = (o - ENABLE_BASE) % ENABLE_STRIDE / 4;
+                if context == CONTEXT && i < WORDS {
+                    // source 0 does not exist
+                    self.enable[i] = if i == 0 { value & !1 } else { value };
+                }
+            }
+            o => {
+                let context = (o - CONTEXT_BASE) / CONTEXT_STRIDE;
+                if context != CONTEXT {
+                    return;
+                }
+                match (o - CONTEXT_BASE) % CONTEXT_STRIDE {
+                    0 => self.threshold = value & 0x7,
+                    4 => self.complete(value),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
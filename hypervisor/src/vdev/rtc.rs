@@ -0,0 +1,149 @@
+// Virtual Goldfish RTC
+/////
+// This emulates the Goldfish RTC of QEMU virt machine for a guest.
+// Each guest has its own offset from the wall-clock time of the host, so that it can set its own time.
+// The alarm is checked whenever the hypervisor polls the device (see `Rtc::poll`).
+
+use crate::rtc;
+use crate::snapshot;
+
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+const ALARM_LOW: usize = 0x08;
+const ALARM_HIGH: usize = 0x0c;
+const IRQ_ENABLED: usize = 0x10;
+const CLEAR_ALARM: usize = 0x14;
+const ALARM_STATUS: usize = 0x18;
+const CLEAR_INTERRUPT: usize = 0x1c;
+
+#[derive(Clone)]
+pub struct Rtc {
+    // the time of the guest minus the time of the host (in nanoseconds)
+    offset: i64,
+    // TIME_HIGH latched by the last read of TIME_LOW
+    time_high: u32,
+    alarm_next: u64,
+    alarm_running: bool,
+    irq_pending: bool,
+    irq_enabled: bool,
+}
+
+fn deposit(v: u64, shift: usize, value: u32) -> u64 {
+    (v & !(0xffff_ffff << shift)) | ((value as u64) << shift)
+}
+
+impl Rtc {
+    pub fn new(offset_secs: i64) -> Rtc {
+        Rtc {
+            offset: offset_secs * 1_000_000_000,
+            time_high: 0,
+            alarm_next: 0,
+            alarm_running: false,
+            irq_pending: false,
+            irq_enabled: false,
+        }
+    }
+
+    // This function resets the device. The time of the guest is kept, as a real RTC does.
+    pub fn reset(&mut self) {
+        *self = Rtc {
+            offset: self.offset,
+            ..Rtc::new(0)
+        };
+    }
+
+    // This function writes the state of the device to a snapshot.
+    // NOTE: the offset from the wall-clock time is saved, so the time of the guest goes on while
+    // it is not running, as a real RTC does.
+    pub fn save(&self, w: &mut snapshot::Writer) {
+        w.u64(self.offset as u64);
+        w.u32(self.time_high);
+        w.u64(self.alarm_next);
+        w.bool(self.alarm_running);
+        w.bool(self.irq_pending);
+        w.bool(self.irq_enabled);
+    }
+
+    pub fn restore(&mut self, r: &mut snapshot::Reader) -> Result<(), snapshot::Error> {
+        self.offset = r.u64()? as i64;
+        self.time_high = r.u32()?;
+        self.alarm_next = r.u64()?;
+        self.alarm_running = r.bool()?;
+        self.irq_pending = r.bool()?;
+        self.irq_enabled = r.bool()?;
+        Ok(())
+    }
+
+    // the current time of the guest in nanoseconds
+    fn now(&self) -> u64 {
+        (rtc::now() as i64).wrapping_add(self.offset) as u64
+    }
+
+    fn set_time(&mut self, t: u64) {
+        self.offset = (t as i64).wrapping_sub(rtc::now() as i64);
+    }
+
+    fn set_alarm(&mut self) {
+        if self.alarm_next <= self.now() {
+            self.alarm_running = false;
+            self.irq_pending = true;
+        } else {
+            self.alarm_running = true;
+        }
+    }
+
+    // This function fires the alarm if the time has come, and returns the level of the interrupt line.
+    pub fn poll(&mut self) -> bool {
+        if self.alarm_running && self.alarm_next <= self.now() {
+            self.alarm_running = false;
+            self.irq_pending = true;
+        }
+        self.irq_pending && self.irq_enabled
+    }
+
+    pub fn read(&mut self, offset: usize) -> u32 {
+        match offset {
+            TIME_LOW => {
+                let now = self.now();
+                self.time_high = (now >> 32) as u32;
+                now as u32
+            }
+            TIME_HIGH => self.time_high,
+            ALARM_LOW => self.alarm_next as u32,
+            ALARM_HIGH => (self.alarm_next >> 32) as u32,
+            IRQ_ENABLED => self.irq_enabled as u32,
+            ALARM_STATUS => self.alarm_running as u32,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: usize, value: u32) {
+        match offset {
+            TIME_LOW => {
+                let t = deposit(self.now(), 0, value);
+                self.set_time(t);
+            }
+            TIME_HIGH => {
+                let t = deposit(self.now(), 32, value);
+                self.set_time(t);
+            }
+            ALARM_LOW => {
+                self.alarm_next = deposit(self.alarm_next, 0, value);
+                self.set_alarm();
+            }
+            ALARM_HIGH => {
+                self.alarm_next = deposit(self.alarm_next, 32, value);
+            }
+            IRQ_ENABLED => {
+                self.irq_enabled = value & 1 != 0;
+            }
+            CLEAR_ALARM => {
+                self.alarm_running = false;
+            }
+            CLEAR_INTERRUPT => {
+                self.irq_pending = false;
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,28 @@
+// Virtual SiFive test device (syscon)
+/////
+// This emulates the test device of QEMU virt machine, through which a guest powers off or reboots itself.
+// Reads return zero.
+
+use crate::syscon;
+
+pub enum Request {
+    // power off with the exit code
+    PowerOff(u32),
+    Reboot,
+}
+
+// This function decodes a store to the device.
+pub fn write(offset: usize, value: u32) -> Option<Request> {
+    if offset != 0 {
+        return None;
+    }
+    match value & 0xffff {
+        syscon::FINISHER_PASS => Some(Request::PowerOff(0)),
+        syscon::FINISHER_FAIL => Some(Request::PowerOff(value >> 16)),
+        syscon::FINISHER_RESET => Some(Request::Reboot),
+        _ => {
+            log::debug!("syscon: unknown command: 0x{:08x}", value);
+            None
+        }
+    }
+}
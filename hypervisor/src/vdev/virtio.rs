@@ -0,0 +1,778 @@
+// Virtio device models for guests
+/////
+// The devices are presented to guests as virtio-pci functions (see `vdev::pci`), and this module
+// implements the transport-independent part: the common configuration, the device-specific
+// configuration and the split virtqueues.
+// Requests are processed synchronously when the guest notifies a queue.
+
+use crate::blockdev;
+use crate::guest::Memory;
+use crate::memlayout;
+use crate::riscv;
+use crate::snapshot;
+use crate::uart;
+use core::convert::TryInto;
+use core::fmt::Error;
+use core::sync::atomic::{fence, Ordering};
+
+pub const MAX_QUEUES: usize = 2;
+const QUEUE_SIZE_MAX: u16 = 256;
+// the longest descriptor chain which can be handled
+const MAX_CHAIN: usize = 16;
+// the size of the device-specific configuration
+pub const CONFIG_SIZE: usize = 0x40;
+
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+// the driver tells the device before it uses the deflated pages
+const VIRTIO_BALLOON_F_MUST_TELL_HOST: u64 = 1 << 0;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_DEVICE_NEEDS_RESET: u8 = 64;
+
+const ISR_QUEUE: u8 = 1;
+const ISR_CONFIG: u8 = 2;
+// the value for "no MSI-X vector"
+const NO_VECTOR: u16 = 0xffff;
+
+const SECTOR_SIZE: usize = 512;
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+const VIRTIO_BLK_ID: &[u8] = b"rvvisor-vblk";
+
+// the page numbers in the balloon queues are always in 4 KiB pages
+const VIRTIO_BALLOON_PFN_SHIFT: usize = 12;
+// struct virtio_balloon_config { le32 num_pages; le32 actual; }
+const BALLOON_NUM_PAGES: usize = 0;
+const BALLOON_ACTUAL: usize = 4;
+
+// the layout of struct virtio_pci_common_cfg
+const DEVICE_FEATURE_SELECT: usize = 0x00;
+const DEVICE_FEATURE: usize = 0x04;
+const DRIVER_FEATURE_SELECT: usize = 0x08;
+const DRIVER_FEATURE: usize = 0x0c;
+const MSIX_CONFIG: usize = 0x10;
+const NUM_QUEUES: usize = 0x12;
+const DEVICE_STATUS: usize = 0x14;
+const CONFIG_GENERATION: usize = 0x15;
+const QUEUE_SELECT: usize = 0x16;
+const QUEUE_SIZE: usize = 0x18;
+const QUEUE_MSIX_VECTOR: usize = 0x1a;
+const QUEUE_ENABLE: usize = 0x1c;
+const QUEUE_NOTIFY_OFF: usize = 0x1e;
+const QUEUE_DESC: usize = 0x20;
+const QUEUE_DRIVER: usize = 0x28;
+const QUEUE_DEVICE: usize = 0x30;
+pub const COMMON_CFG_SIZE: usize = 0x38;
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum DeviceType {
+    Net = 1,
+    Block = 2,
+    Console = 3,
+    Rng = 4,
+    Balloon = 5,
+}
+
+impl DeviceType {
+    fn num_queues(&self) -> u16 {
+        match self {
+            // receiveq & transmitq (inflateq & deflateq for balloon)
+            DeviceType::Net | DeviceType::Console | DeviceType::Balloon => 2,
+            DeviceType::Block | DeviceType::Rng => 1,
+        }
+    }
+}
+
+// Virtqueue
+/////
+
+#[derive(Copy, Clone)]
+struct Virtqueue {
+    size: u16,
+    enabled: bool,
+    // the guest physical addresses of the descriptor table, the available ring and the used ring
+    desc: u64,
+    driver: u64,
+    device: u64,
+    last_avail: u16,
+}
+
+impl Virtqueue {
+    fn new() -> Virtqueue {
+        Virtqueue {
+            size: QUEUE_SIZE_MAX,
+            enabled: false,
+            desc: 0,
+            driver: 0,
+            device: 0,
+            last_avail: 0,
+        }
+    }
+
+    // This function takes the head of the next available descriptor chain, if any.
+    fn pop(&mut self, mem: &Memory) -> Result<Option<u16>, Error> {
+        let idx = read_u16(mem, self.driver as usize + 2)?;
+        if idx == self.last_avail {
+            return Ok(None);
+        }
+        fence(Ordering::SeqCst);
+        let slot = (self.last_avail % self.size) as usize;
+        let head = read_u16(mem, self.driver as usize + 4 + 2 * slot)?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+        Ok(Some(head))
+    }
+
+    // This function returns the chain at `head` to the driver with the number of bytes written.
+    fn push_used(&self, mem: &Memory, head: u16, len: u32) -> Result<(), Error> {
+        let idx = read_u16(mem, self.device as usize + 2)?;
+        let elem = self.device as usize + 4 + 8 * (idx % self.size) as usize;
+        mem.write(elem, &(head as u32).to_le_bytes())?;
+        mem.write(elem + 4, &len.to_le_bytes())?;
+        fence(Ordering::SeqCst);
+        mem.write(self.device as usize + 2, &idx.wrapping_add(1).to_le_bytes())
+    }
+
+    fn chain(&self, mem: &Memory, head: u16) -> Result<Chain, Error> {
+        let mut chain = Chain {
+            readable: [Buffer { addr: 0, len: 0 }; MAX_CHAIN],
+            num_readable: 0,
+            writable: [Buffer { addr: 0, len: 0 }; MAX_CHAIN],
+            num_writable: 0,
+        };
+        let mut index = head;
+        for _ in 0..MAX_CHAIN {
+            if index >= self.size {
+                return Err(Error);
+            }
+            // struct virtq_desc { le64 addr; le32 len; le16 flags; le16 next; }
+            let mut desc = [0u8; 16];
+            mem.read(self.desc as usize + 16 * index as usize, &mut desc)?;
+            let buffer = Buffer {
+                addr: u64::from_le_bytes(desc[0..8].try_into().unwrap()) as usize,
+                len: u32::from_le_bytes(desc[8..12].try_into().unwrap()) as usize,
+            };
+            let flags = u16::from_le_bytes(desc[12..14].try_into().unwrap());
+            if flags & VIRTQ_DESC_F_WRITE != 0 {
+                chain.writable[chain.num_writable] = buffer;
+                chain.num_writable += 1;
+            } else {
+                // readable buffers must come first
+                if chain.num_writable > 0 {
+                    return Err(Error);
+                }
+                chain.readable[chain.num_readable] = buffer;
+                chain.num_readable += 1;
+            }
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                return Ok(chain);
+            }
+            index = u16::from_le_bytes(desc[14..16].try_into().unwrap());
+        }
+        // TODO (enhancement): support indirect descriptors and longer chains
+        Err(Error)
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Buffer {
+    addr: usize,
+    len: usize,
+}
+
+// A descriptor chain: the buffers which the device reads, followed by the ones it writes.
+struct Chain {
+    readable: [Buffer; MAX_CHAIN],
+    num_readable: usize,
+    writable: [Buffer; MAX_CHAIN],
+    num_writable: usize,
+}
+
+impl Chain {
+    fn readable_len(&self) -> usize {
+        self.readable[..self.num_readable]
+            .iter()
+            .map(|b| b.len)
+            .sum()
+    }
+
+    fn writable_len(&self) -> usize {
+        self.writable[..self.num_writable]
+            .iter()
+            .map(|b| b.len)
+            .sum()
+    }
+
+    // This function copies the readable part from `offset` into `buf`.
+    // It returns the number of bytes copied.
+    fn read(&self, mem: &Memory, offset: usize, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut done = 0;
+        let buffers = &self.readable[..self.num_readable];
+        for_each_range(buffers, offset, buf.len(), |addr, len| {
+            mem.read(addr, &mut buf[done..done + len])?;
+            done += len;
+            Ok(())
+        })?;
+        Ok(done)
+    }
+
+    // This function copies `data` into the writable part from `offset`.
+    // It returns the number of bytes copied.
+    fn write(&self, mem: &Memory, offset: usize, data: &[u8]) -> Result<usize, Error> {
+        let mut done = 0;
+        let buffers = &self.writable[..self.num_writable];
+        for_each_range(buffers, offset, data.len(), |addr, len| {
+            mem.write(addr, &data[done..done + len])?;
+            done += len;
+            Ok(())
+        })?;
+        Ok(done)
+    }
+}
+
+// This function calls `f(gpa, length)` for each part of `buffers` in [offset, offset + len),
+// as if the buffers were concatenated.
+fn for_each_range<F: FnMut(usize, usize) -> Result<(), Error>>(
+    buffers: &[Buffer],
+    offset: usize,
+    len: usize,
+    mut f: F,
+) -> Result<(), Error> {
+    let mut skip = offset;
+    let mut left = len;
+    for b in buffers {
+        if left == 0 {
+            break;
+        }
+        if skip >= b.len {
+            skip -= b.len;
+            continue;
+        }
+        let n = core::cmp::min(b.len - skip, left);
+        f(b.addr + skip, n)?;
+        skip = 0;
+        left -= n;
+    }
+    Ok(())
+}
+
+fn read_u16(mem: &Memory, gpa: usize) -> Result<u16, Error> {
+    let mut buf = [0u8; 2];
+    mem.read(gpa, &mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+// This function extracts the `width`-byte field at `offset` of a register at `base`.
+fn extract(value: u64, base: usize, offset: usize, width: usize) -> u64 {
+    let value = value >> ((offset - base) * 8);
+    if width >= 8 {
+        value
+    } else {
+        value & ((1 << (width * 8)) - 1)
+    }
+}
+
+// This function replaces the `width`-byte field at `offset` of a register at `base`.
+fn merge(old: u64, base: usize, offset: usize, width: usize, value: u64) -> u64 {
+    let shift = (offset - base) * 8;
+    let mask = if width >= 8 {
+        u64::MAX
+    } else {
+        (1 << (width * 8)) - 1
+    };
+    (old & !(mask << shift)) | ((value & mask) << shift)
+}
+
+// Device
+/////
+
+#[derive(Clone)]
+pub struct Device {
+    pub kind: DeviceType,
+    features: u64,
+    config: [u8; CONFIG_SIZE],
+    device_feature_select: u32,
+    driver_feature_select: u32,
+    driver_features: u64,
+    status: u8,
+    queue_select: u16,
+    queues: [Virtqueue; MAX_QUEUES],
+    isr: u8,
+    // the state of the random number generator (rng)
+    seed: u64,
+}
+
+impl Device {
+    // `index` distinguishes devices of the same guest (e.g. for MAC addresses).
+    pub fn new(kind: DeviceType, disk: &blockdev::Disk, index: usize) -> Device {
+        let mut config = [0u8; CONFIG_SIZE];
+        let features = VIRTIO_F_VERSION_1
+            | match kind {
+                DeviceType::Block => {
+                    // struct virtio_blk_config { le64 capacity; ... }
+                    config[0..8].copy_from_slice(&disk.capacity().to_le_bytes());
+                    VIRTIO_BLK_F_FLUSH
+                        | if disk.is_read_only() {
+                            VIRTIO_BLK_F_RO
+                        } else {
+                            0
+                        }
+                }
+                DeviceType::Net => {
+                    // struct virtio_net_config { u8 mac[6]; ... }
+                    config[0..6].copy_from_slice(&[
+                        0x52,
+                        0x54,
+                        0x00,
+                        0x12,
+                        0x34,
+                        0x56 + index as u8,
+                    ]);
+                    VIRTIO_NET_F_MAC
+                }
+                DeviceType::Balloon => VIRTIO_BALLOON_F_MUST_TELL_HOST,
+                DeviceType::Console | DeviceType::Rng => 0,
+            };
+        Device {
+            kind: kind,
+            features: features,
+            config: config,
+            device_feature_select: 0,
+            driver_feature_select: 0,
+            driver_features: 0,
+            status: 0,
+            queue_select: 0,
+            queues: [Virtqueue::new(); MAX_QUEUES],
+            isr: 0,
+            seed: (riscv::csr::time::read() as u64) | 1,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.device_feature_select = 0;
+        self.driver_feature_select = 0;
+        self.driver_features = 0;
+        self.status = 0;
+        self.queue_select = 0;
+        self.queues = [Virtqueue::new(); MAX_QUEUES];
+        self.isr = 0;
+    }
+
+    // This function writes the state of the device to a snapshot.
+    // NOTE: the offered features and the kind are given by the configuration of the guest.
+    pub fn save(&self, w: &mut snapshot::Writer) {
+        w.bytes(&self.config);
+        w.u32(self.device_feature_select);
+        w.u32(self.driver_feature_select);
+        w.u64(self.driver_features);
+        w.u8(self.status);
+        w.u16(self.queue_select);
+        for q in self.queues.iter() {
+            w.u16(q.size);
+            w.bool(q.enabled);
+            w.u64(q.desc);
+            w.u64(q.driver);
+            w.u64(q.device);
+            w.u16(q.last_avail);
+        }
+        w.u8(self.isr);
+        w.u64(self.seed);
+    }
+
+    pub fn restore(&mut self, r: &mut snapshot::Reader) -> Result<(), snapshot::Error> {
+        r.bytes(&mut self.config)?;
+        self.device_feature_select = r.u32()?;
+        self.driver_feature_select = r.u32()?;
+        self.driver_features = r.u64()?;
+        if self.driver_features & !self.features != 0 {
+            return Err(snapshot::Error::Incompatible);
+        }
+        self.status = r.u8()?;
+        self.queue_select = r.u16()?;
+        for q in self.queues.iter_mut() {
+            q.size = r.u16()?;
+            q.enabled = r.bool()?;
+            q.desc = r.u64()?;
+            q.driver = r.u64()?;
+            q.device = r.u64()?;
+            q.last_avail = r.u16()?;
+        }
+        self.isr = r.u8()?;
+        self.seed = r.u64()?;
+        Ok(())
+    }
+
+    // whether the interrupt of the device is asserted
+    pub fn irq_level(&self) -> bool {
+        self.isr != 0
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut Virtqueue> {
+        if self.queue_select < self.kind.num_queues() {
+            Some(&mut self.queues[self.queue_select as usize])
+        } else {
+            None
+        }
+    }
+
+    pub fn read_common(&mut self, offset: usize, width: usize) -> u64 {
+        let (base, value) = match offset {
+            DEVICE_FEATURE_SELECT..=0x03 => {
+                (DEVICE_FEATURE_SELECT, self.device_feature_select as u64)
+            }
+            DEVICE_FEATURE..=0x07 => (
+                DEVICE_FEATURE,
+                match self.device_feature_select {
+                    0 => self.features & 0xffff_ffff,
+                    1 => self.features >> 32,
+                    _ => 0,
+                },
+            ),
+            DRIVER_FEATURE_SELECT..=0x0b => {
+                (DRIVER_FEATURE_SELECT, self.driver_feature_select as u64)
+            }
+            DRIVER_FEATURE..=0x0f => (
+                DRIVER_FEATURE,
+                match self.driver_feature_select {
+                    0 => self.driver_features & 0xffff_ffff,
+                    1 => self.driver_features >> 32,
+                    _ => 0,
+                },
+            ),
+            MSIX_CONFIG..=0x11 => (MSIX_CONFIG, NO_VECTOR as u64),
+            NUM_QUEUES..=0x13 => (NUM_QUEUES, self.kind.num_queues() as u64),
+            DEVICE_STATUS => (DEVICE_STATUS, self.status as u64),
+            CONFIG_GENERATION => (CONFIG_GENERATION, 0),
+            QUEUE_SELECT..=0x17 => (QUEUE_SELECT, self.queue_select as u64),
+            QUEUE_SIZE..=0x19 => (
+                QUEUE_SIZE,
+                self.selected_queue().map_or(0, |q| q.size as u64),
+            ),
+            QUEUE_MSIX_VECTOR..=0x1b => (QUEUE_MSIX_VECTOR, NO_VECTOR as u64),
+            QUEUE_ENABLE..=0x1d => (
+                QUEUE_ENABLE,
+                self.selected_queue().map_or(0, |q| q.enabled as u64),
+            ),
+            QUEUE_NOTIFY_OFF..=0x1f => (QUEUE_NOTIFY_OFF, self.queue_select as u64),
+            QUEUE_DESC..=0x27 => (QUEUE_DESC, self.selected_queue().map_or(0, |q| q.desc)),
+            QUEUE_DRIVER..=0x2f => (QUEUE_DRIVER, self.selected_queue().map_or(0, |q| q.driver)),
+            QUEUE_DEVICE..=0x37 => (QUEUE_DEVICE, self.selected_queue().map_or(0, |q| q.device)),
+            _ => return 0,
+        };
+        extract(value, base, offset, width)
+    }
+
+    pub fn write_common(&mut self, offset: usize, width: usize, value: u64) {
+        match offset {
+            DEVICE_FEATURE_SELECT..=0x03 => {
+                let old = self.device_feature_select as u64;
+                self.device_feature_select =
+                    merge(old, DEVICE_FEATURE_SELECT, offset, width, value) as u32;
+            }
+            DRIVER_FEATURE_SELECT..=0x0b => {
+                let old = self.driver_feature_select as u64;
+                self.driver_feature_select =
+                    merge(old, DRIVER_FEATURE_SELECT, offset, width, value) as u32;
+            }
+            DRIVER_FEATURE..=0x0f => {
+                let shift = match self.driver_feature_select {
+                    0 => 0,
+                    1 => 32,
+                    _ => return,
+                };
+                let old = (self.driver_features >> shift) & 0xffff_ffff;
+                let half = merge(old, DRIVER_FEATURE, offset, width, value) & 0xffff_ffff;
+                self.driver_features =
+                    (self.driver_features & !(0xffff_ffff << shift)) | (half << shift);
+                // the driver can not accept features which are not offered
+                self.driver_features &= self.features;
+            }
+            DEVICE_STATUS => {
+                if value == 0 {
+                    self.reset();
+                } else {
+                    self.status = value as u8;
+                }
+            }
+            QUEUE_SELECT..=0x17 => {
+                self.queue_select =
+                    merge(self.queue_select as u64, QUEUE_SELECT, offset, width, value) as u16;
+            }
+            QUEUE_SIZE..=0x19 => {
+                if let Some(q) = self.selected_queue() {
+                    let size = merge(q.size as u64, QUEUE_SIZE, offset, width, value) as u16;
+                    // the queue size must be a power of 2
+                    if size.is_power_of_two() && size <= QUEUE_SIZE_MAX {
+                        q.size = size;
+                    }
+                }
+            }
+            QUEUE_ENABLE..=0x1d => {
+                if let Some(q) = self.selected_queue() {
+                    q.enabled = value & 1 == 1;
+                }
+            }
+            QUEUE_DESC..=0x27 => {
+                if let Some(q) = self.selected_queue() {
+                    q.desc = merge(q.desc, QUEUE_DESC, offset, width, value);
+                }
+            }
+            QUEUE_DRIVER..=0x2f => {
+                if let Some(q) = self.selected_queue() {
+                    q.driver = merge(q.driver, QUEUE_DRIVER, offset, width, value);
+                }
+            }
+            QUEUE_DEVICE..=0x37 => {
+                if let Some(q) = self.selected_queue() {
+                    q.device = merge(q.device, QUEUE_DEVICE, offset, width, value);
+                }
+            }
+            // read-only or unsupported (MSI-X)
+            _ => {}
+        }
+    }
+
+    // Reading the ISR status acknowledges the interrupt.
+    pub fn read_isr(&mut self) -> u8 {
+        let isr = self.isr;
+        self.isr = 0;
+        isr
+    }
+
+    pub fn read_config(&self, offset: usize, width: usize) -> u64 {
+        let mut value = 0;
+        for i in (0..width).rev() {
+            let byte = self.config.get(offset + i).copied().unwrap_or(0);
+            value = (value << 8) | byte as u64;
+        }
+        value
+    }
+
+    // Only the fields written by the driver (`actual` of balloon) are writable.
+    pub fn write_config(&mut self, offset: usize, width: usize, value: u64) {
+        for i in 0..width {
+            let o = offset + i;
+            if self.kind == DeviceType::Balloon && BALLOON_ACTUAL <= o && o < BALLOON_ACTUAL + 4 {
+                self.config[o] = (value >> (i * 8)) as u8;
+            }
+        }
+    }
+
+    // This function asks the driver to keep `pages` pages in the balloon (only for balloon).
+    pub fn set_balloon_target(&mut self, pages: u32) {
+        let field = BALLOON_NUM_PAGES..BALLOON_NUM_PAGES + 4;
+        if self.config[field.clone()] != pages.to_le_bytes() {
+            self.config[field].copy_from_slice(&pages.to_le_bytes());
+            if self.status & STATUS_DRIVER_OK != 0 {
+                self.isr |= ISR_CONFIG;
+            }
+        }
+    }
+
+    // the number of pages in the balloon reported by the driver (only for balloon)
+    pub fn balloon_size(&self) -> u32 {
+        u32::from_le_bytes(
+            self.config[BALLOON_ACTUAL..BALLOON_ACTUAL + 4]
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    // This function processes the requests in the queue `index`.
+    pub fn notify(&mut self, index: usize, mem: &Memory, disk: &mut blockdev::Disk) {
+        if index >= self.kind.num_queues() as usize
+            || !self.queues[index].enabled
+            || self.status & STATUS_DRIVER_OK == 0
+        {
+            return;
+        }
+        // receiveq: the buffers are kept until there is an input, which never comes for now
+        if (self.kind == DeviceType::Net || self.kind == DeviceType::Console) && index == 0 {
+            return;
+        }
+
+        let mut queue = self.queues[index];
+        loop {
+            let head = match queue.pop(mem) {
+                Ok(Some(head)) => head,
+                Ok(None) => break,
+                Err(_) => {
+                    self.fail();
+                    break;
+                }
+            };
+            let result = queue
+                .chain(mem, head)
+                .and_then(|chain| self.process(index, &chain, mem, disk))
+                .and_then(|len| queue.push_used(mem, head, len));
+            if result.is_err() {
+                self.fail();
+                break;
+            }
+            self.isr |= ISR_QUEUE;
+        }
+        self.queues[index] = queue;
+    }
+
+    // This function is called when the driver gives a broken request.
+    fn fail(&mut self) {
+        log::info!("virtio: a broken request was given to {:?}", self.kind);
+        self.status |= STATUS_DEVICE_NEEDS_RESET;
+    }
+
+    // This function handles a request, and returns the number of bytes written to the chain.
+    fn process(
+        &mut self,
+        index: usize,
+        chain: &Chain,
+        mem: &Memory,
+        disk: &mut blockdev::Disk,
+    ) -> Result<u32, Error> {
+        match self.kind {
+            DeviceType::Block => process_block(chain, mem, disk, self.driver_features),
+            DeviceType::Console => {
+                // transmitq: print the data on the console of the hypervisor
+                let mut buf = [0u8; 64];
+                let mut offset = 0;
+                loop {
+                    let n = chain.read(mem, offset, &mut buf)?;
+                    if n == 0 {
+                        break;
+                    }
+                    let mut uart = uart::Uart::new(memlayout::UART_BASE);
+                    for c in &buf[..n] {
+                        uart.put(*c);
+                    }
+                    offset += n;
+                }
+                Ok(0)
+            }
+            DeviceType::Rng => {
+                // TODO: this is not cryptographically secure. use a hardware source (e.g. Zkr) if any.
+                let len = chain.writable_len();
+                let mut offset = 0;
+                while offset < len {
+                    self.seed ^= self.seed << 13;
+                    self.seed ^= self.seed >> 7;
+                    self.seed ^= self.seed << 17;
+                    offset += chain.write(mem, offset, &self.seed.to_le_bytes())?;
+                }
+                Ok(len as u32)
+            }
+            DeviceType::Net => {
+                // TODO (enhancement): connect the device to a backend. the packets are dropped for now.
+                Ok(0)
+            }
+            DeviceType::Balloon => {
+                process_balloon(chain, mem, index == 0)?;
+                Ok(0)
+            }
+        }
+    }
+}
+
+// This function takes the pages listed in a request of inflateq from the guest (`inflate`), or
+// gives back the pages listed in a request of deflateq.
+fn process_balloon(chain: &Chain, mem: &Memory, inflate: bool) -> Result<(), Error> {
+    // the request is an array of le32 page numbers
+    let mut buf = [0u8; 256];
+    let mut offset = 0;
+    loop {
+        let n = chain.read(mem, offset, &mut buf)? / 4 * 4;
+        if n == 0 {
+            break;
+        }
+        for pfn in buf[..n].chunks(4) {
+            let pfn = u32::from_le_bytes(pfn.try_into().unwrap()) as usize;
+            let gpa = pfn << VIRTIO_BALLOON_PFN_SHIFT;
+            let result = if inflate {
+                mem.discard(gpa)
+            } else {
+                mem.populate(gpa).map(|_| ())
+            };
+            // pages out of RAM are ignored, but the lack of memory is reported to the driver
+            if result.is_err() && !inflate {
+                return Err(Error);
+            }
+        }
+        offset += n;
+    }
+    Ok(())
+}
+
+fn process_block(
+    chain: &Chain,
+    mem: &Memory,
+    disk: &mut blockdev::Disk,
+    features: u64,
+) -> Result<u32, Error> {
+    // struct virtio_blk_req { le32 type; le32 reserved; le64 sector; u8 data[]; u8 status; }
+    let mut header = [0u8; 16];
+    if chain.read(mem, 0, &mut header)? < header.len() || chain.writable_len() == 0 {
+        return Err(Error);
+    }
+    let kind = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let sector = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    // the last writable byte holds the status
+    let data_len = chain.writable_len() - 1;
+
+    let mut buf = [0u8; 8 * SECTOR_SIZE];
+    let mut written = 0;
+    let status = match kind {
+        VIRTIO_BLK_T_IN if data_len % SECTOR_SIZE == 0 => {
+            let mut status = VIRTIO_BLK_S_OK;
+            while written < data_len {
+                let n = core::cmp::min(buf.len(), data_len - written);
+                let s = sector + (written / SECTOR_SIZE) as u64;
+                if disk.read(s, buf.as_mut_ptr(), n / SECTOR_SIZE).is_err() {
+                    status = VIRTIO_BLK_S_IOERR;
+                    break;
+                }
+                written += chain.write(mem, written, &buf[..n])?;
+            }
+            status
+        }
+        VIRTIO_BLK_T_OUT => {
+            let len = chain.readable_len() - header.len();
+            let mut status = if len % SECTOR_SIZE == 0 {
+                VIRTIO_BLK_S_OK
+            } else {
+                VIRTIO_BLK_S_IOERR
+            };
+            let mut done = 0;
+            while status == VIRTIO_BLK_S_OK && done < len {
+                let n = core::cmp::min(buf.len(), len - done);
+                chain.read(mem, header.len() + done, &mut buf[..n])?;
+                let s = sector + (done / SECTOR_SIZE) as u64;
+                if disk.write(s, buf.as_ptr(), n / SECTOR_SIZE).is_err() {
+                    status = VIRTIO_BLK_S_IOERR;
+                }
+                done += n;
+            }
+            status
+        }
+        VIRTIO_BLK_T_FLUSH if features & VIRTIO_BLK_F_FLUSH != 0 => match disk.flush() {
+            Ok(()) => VIRTIO_BLK_S_OK,
+            Err(_) => VIRTIO_BLK_S_IOERR,
+        },
+        VIRTIO_BLK_T_GET_ID => {
+            let n = core::cmp::min(VIRTIO_BLK_ID.len(), data_len);
+            written = chain.write(mem, 0, &VIRTIO_BLK_ID[..n])?;
+            VIRTIO_BLK_S_OK
+        }
+        VIRTIO_BLK_T_IN => VIRTIO_BLK_S_IOERR,
+        _ => VIRTIO_BLK_S_UNSUPP,
+    };
+    chain.write(mem, data_len, &[status])?;
+    Ok(written as u32 + 1)
+}
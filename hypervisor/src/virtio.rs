@@ -1,9 +1,12 @@
 use crate::memlayout;
 use crate::paging;
+use crate::pci;
 use crate::riscv;
 use core::mem::size_of;
+use core::sync::atomic::{fence, Ordering};
 
 pub const SECTOR_SIZE: usize = 512;
+pub const VIRTIO_BLK_ID_BYTES: usize = 20;
 
 const VIRTIO_DESC_F_NEXT: u16 = 1 << 0;
 const VIRTIO_DESC_F_WRITE: u16 = 1 << 1;
@@ -12,12 +15,25 @@ const VIRTIO_MAGIC: u32 = 0x74_72_69_76;
 const VIRTIO_VENDOR: u32 = 0x55_4d_45_51;
 
 pub const VIRTIO_BLK_F_RO: u32 = 1 << 5;
+pub const VIRTIO_BLK_F_BLK_SIZE: u32 = 1 << 6;
 pub const VIRTIO_BLK_F_SCSI: u32 = 1 << 7;
+pub const VIRTIO_BLK_F_FLUSH: u32 = 1 << 9;
 pub const VIRTIO_BLK_F_CONFIG_WCE: u32 = 1 << 11;
 pub const VIRTIO_BLK_F_MQ: u32 = 1 << 12;
 pub const VIRTIO_F_ANY_LAYOUT: u32 = 1 << 27;
 pub const VIRTIO_RING_F_INDIRECT_DESC: u32 = 1 << 28;
 pub const VIRTIO_RING_F_EVENT_IDX: u32 = 1 << 29;
+// NOTE: this is bit 32, i.e. bit 0 of the second feature word
+pub const VIRTIO_F_VERSION_1: u32 = 1 << 0;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
 
 const VIRTIO_RING_SIZE: usize = 8;
 
@@ -51,6 +67,265 @@ impl Offset {
 	}
 }
 
+// layout of struct virtio_blk_config
+#[repr(usize)]
+#[derive(Copy, Clone)]
+enum BlkConfig {
+	Capacity = 0x00,
+	BlkSize = 0x14,
+}
+
+impl BlkConfig {
+	fn read32(&self, config: *mut u8) -> u32 {
+		unsafe { (config.add(*self as usize) as *mut u32).read_volatile() }
+	}
+
+	fn read64(&self, config: *mut u8) -> u64 {
+		// the config space is only guaranteed to be accessible with 32-bit width
+		let lo = self.read32(config) as u64;
+		let hi = unsafe { (config.add(*self as usize + 4) as *mut u32).read_volatile() as u64 };
+		(hi << 32) | lo
+	}
+}
+
+// virtio-pci (modern) transport
+/////
+
+const VIRTIO_PCI_VENDOR: u16 = 0x1af4;
+const VIRTIO_PCI_DEVICE_BLK: u16 = 0x1042;
+const VIRTIO_PCI_DEVICE_BLK_TRANSITIONAL: u16 = 0x1001;
+
+const PCI_CAP_VENDOR: u8 = 0x09;
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+// layout of struct virtio_pci_common_cfg
+#[repr(usize)]
+#[derive(Copy, Clone)]
+enum CommonCfg {
+	DeviceFeatureSelect = 0x00,
+	DeviceFeature = 0x04,
+	DriverFeatureSelect = 0x08,
+	DriverFeature = 0x0c,
+	DeviceStatus = 0x14,
+	QueueSelect = 0x16,
+	QueueSize = 0x18,
+	QueueEnable = 0x1c,
+	QueueNotifyOff = 0x1e,
+	QueueDesc = 0x20,
+	QueueDriver = 0x28,
+	QueueDevice = 0x30,
+}
+
+impl CommonCfg {
+	fn apply(&self, common: usize) -> usize {
+		common + *self as usize
+	}
+}
+
+#[derive(Copy, Clone)]
+pub struct PciTransport {
+	common: usize,
+	// the notification address of queue 0 (fixed after the queue is set up)
+	notify: usize,
+	notify_off_multiplier: u32,
+	isr: usize,
+	device: usize,
+}
+
+impl PciTransport {
+	// This function locates the configuration structures of a virtio-pci device.
+	// It returns `None` for legacy-only devices.
+	fn from_function(f: &pci::Function) -> Option<PciTransport> {
+		let mut common = None;
+		let mut notify = None;
+		let mut isr = None;
+		let mut device = None;
+		for (offset, id) in f.capabilities() {
+			if id != PCI_CAP_VENDOR {
+				continue;
+			}
+			let bar = match f.bars.get(f.read8(offset + 4) as usize) {
+				Some(Some(bar)) => bar,
+				_ => continue,
+			};
+			let address = bar.address + f.read32(offset + 8) as usize;
+			match f.read8(offset + 3) {
+				VIRTIO_PCI_CAP_COMMON_CFG => common = Some(address),
+				VIRTIO_PCI_CAP_NOTIFY_CFG => notify = Some((address, f.read32(offset + 16))),
+				VIRTIO_PCI_CAP_ISR_CFG => isr = Some(address),
+				VIRTIO_PCI_CAP_DEVICE_CFG => device = Some(address),
+				_ => {}
+			}
+		}
+		let (notify, notify_off_multiplier) = notify?;
+		Some(PciTransport {
+			common: common?,
+			notify: notify,
+			notify_off_multiplier: notify_off_multiplier,
+			isr: isr?,
+			device: device?,
+		})
+	}
+}
+
+// Transport
+/////
+
+#[derive(Copy, Clone)]
+pub enum Transport {
+	// the base address of a virtio-mmio (legacy) device
+	Mmio(*mut u32),
+	Pci(PciTransport),
+}
+
+impl Transport {
+	fn status(&self) -> u32 {
+		unsafe {
+			match self {
+				Transport::Mmio(base) => Offset::Status.apply(base).read_volatile(),
+				Transport::Pci(t) => {
+					(CommonCfg::DeviceStatus.apply(t.common) as *mut u8).read_volatile() as u32
+				}
+			}
+		}
+	}
+
+	fn set_status(&self, status: u32) {
+		unsafe {
+			match self {
+				Transport::Mmio(base) => Offset::Status.apply(base).write_volatile(status),
+				Transport::Pci(t) => (CommonCfg::DeviceStatus.apply(t.common) as *mut u8)
+					.write_volatile(status as u8),
+			}
+		}
+	}
+
+	// This function reads the 32-bit word `sel` of the features offered by the device.
+	fn device_features(&self, sel: u32) -> u32 {
+		unsafe {
+			match self {
+				Transport::Mmio(base) => {
+					Offset::HostFeaturesSel.apply(base).write_volatile(sel);
+					Offset::HostFeatures.apply(base).read_volatile()
+				}
+				Transport::Pci(t) => {
+					(CommonCfg::DeviceFeatureSelect.apply(t.common) as *mut u32)
+						.write_volatile(sel);
+					(CommonCfg::DeviceFeature.apply(t.common) as *mut u32).read_volatile()
+				}
+			}
+		}
+	}
+
+	fn set_driver_features(&self, sel: u32, features: u32) {
+		unsafe {
+			match self {
+				Transport::Mmio(base) => {
+					Offset::GuestFeaturesSel.apply(base).write_volatile(sel);
+					Offset::GuestFeatures.apply(base).write_volatile(features);
+				}
+				Transport::Pci(t) => {
+					(CommonCfg::DriverFeatureSelect.apply(t.common) as *mut u32)
+						.write_volatile(sel);
+					(CommonCfg::DriverFeature.apply(t.common) as *mut u32).write_volatile(features);
+				}
+			}
+		}
+	}
+
+	// the device-specific configuration space
+	fn config(&self) -> *mut u8 {
+		match self {
+			Transport::Mmio(base) => Offset::Config.apply(base) as *mut u8,
+			Transport::Pci(t) => t.device as *mut u8,
+		}
+	}
+
+	// This function sets up the queue 0 with the rings in `queue`.
+	fn setup_queue(&mut self, queue: *mut Queue) {
+		let queue_num = VIRTIO_RING_SIZE as u32;
+		unsafe {
+			match self {
+				Transport::Mmio(base) => {
+					// tell our page size to virtio
+					Offset::GuestPageSize
+						.apply(base)
+						.write_volatile(memlayout::PAGE_SIZE as u32);
+
+					// set first queue selector
+					Offset::QueueSel.apply(base).write_volatile(0);
+
+					// set our queue num
+					let queue_max = Offset::QueueNumMax.apply(base).read_volatile();
+					if queue_num > queue_max {
+						panic!("virtio disk has invalid queue max setting");
+					}
+					Offset::QueueNum.apply(base).write_volatile(queue_num);
+
+					// set our queue addr
+					Offset::QueuePfn
+						.apply(base)
+						.write_volatile(((queue as usize) >> 12) as u32);
+				}
+				Transport::Pci(t) => {
+					(CommonCfg::QueueSelect.apply(t.common) as *mut u16).write_volatile(0);
+					let queue_size = CommonCfg::QueueSize.apply(t.common) as *mut u16;
+					if queue_num > queue_size.read_volatile() as u32 {
+						panic!("virtio disk has invalid queue max setting");
+					}
+					queue_size.write_volatile(queue_num as u16);
+					(CommonCfg::QueueDesc.apply(t.common) as *mut u64)
+						.write_volatile(&(*queue).desc as *const _ as u64);
+					(CommonCfg::QueueDriver.apply(t.common) as *mut u64)
+						.write_volatile(&(*queue).avail as *const _ as u64);
+					(CommonCfg::QueueDevice.apply(t.common) as *mut u64)
+						.write_volatile(&(*queue).used as *const _ as u64);
+					let notify_off =
+						(CommonCfg::QueueNotifyOff.apply(t.common) as *mut u16).read_volatile();
+					t.notify += notify_off as usize * t.notify_off_multiplier as usize;
+					(CommonCfg::QueueEnable.apply(t.common) as *mut u16).write_volatile(1);
+				}
+			}
+		}
+	}
+
+	fn notify(&self) {
+		unsafe {
+			match self {
+				Transport::Mmio(base) => Offset::QueueNotify.apply(base).write_volatile(0),
+				Transport::Pci(t) => (t.notify as *mut u16).write_volatile(0),
+			}
+		}
+	}
+
+	fn ack_interrupt(&self) {
+		unsafe {
+			match self {
+				Transport::Mmio(base) => {
+					let status = Offset::InterruptStatus.apply(base).read_volatile();
+					Offset::InterruptAck.apply(base).write_volatile(status);
+				}
+				// reading the ISR status clears it
+				Transport::Pci(t) => {
+					(t.isr as *mut u8).read_volatile();
+				}
+			}
+		}
+	}
+}
+
+#[derive(Debug)]
+pub enum RequestError {
+	ReadOnly,
+	OutOfRange,
+	NoDescriptor,
+	IoError,
+	Unsupported,
+}
+
 enum StatusFlag {
 	Acknowledge = 1,
 	Driver = 2,
@@ -117,89 +392,326 @@ pub struct Queue {
 	pub header: [BlkOuthdr; VIRTIO_RING_SIZE],
 
 	pub notify_slot: [bool; VIRTIO_RING_SIZE],
-	pub device_base_addr: *mut u32,
+	pub free_slot: [bool; VIRTIO_RING_SIZE],
+	pub transport: Transport,
+
+	pub features: u32,
+	pub capacity: u64,
+	pub block_size: u32,
 }
 
 pub static mut QUEUE: Option<*mut Queue> = None;
-static mut INITIALIZED: bool = false;
+static mut IRQ: Option<u32> = None;
+
+pub fn slot_base(slot: usize) -> *mut u32 {
+	(memlayout::VIRTIO0_BASE + slot * memlayout::VIRTIO_MMIO_SLOT_SIZE) as *mut u32
+}
+
+pub fn slot_irq(slot: usize) -> u32 {
+	memlayout::VIRTIO0_IRQ as u32 + slot as u32
+}
+
+// This function resets the device in `slot` (e.g. when the guest which owns it is rebooted).
+pub fn reset_slot(slot: usize) {
+	unsafe {
+		Offset::Status.apply(&slot_base(slot)).write_volatile(0);
+	}
+}
+
+// the interrupt of the block device used by the hypervisor
+pub fn irq() -> Option<u32> {
+	unsafe { IRQ }
+}
 
 impl Queue {
 	pub fn from_page(p: paging::Page) -> *mut Queue {
 		unsafe {
 			let queue = p.address().to_usize() as *mut Queue;
 			(*queue).used_idx = 0;
+			for i in 0..VIRTIO_RING_SIZE {
+				(*queue).free_slot[i] = true;
+			}
 			queue
 		}
 	}
 
-	fn request(&mut self, sector: u64, buf_addr: *const (), is_write: bool) -> usize {
-		unsafe {
-			// TODO: select unused descriptors
-			let idx = [0, 1, 2];
+	fn alloc_desc(&mut self) -> Option<usize> {
+		for i in 0..VIRTIO_RING_SIZE {
+			if self.free_slot[i] {
+				self.free_slot[i] = false;
+				return Some(i);
+			}
+		}
+		None
+	}
 
-			self.header[idx[0] as usize].typ = if is_write { 1 } else { 0 };
-			self.header[idx[0] as usize].reserved = 0;
-			self.header[idx[0] as usize].sector = sector;
+	fn free_chain(&mut self, head: usize) {
+		let mut i = head;
+		loop {
+			self.free_slot[i] = true;
+			if self.desc[i].flags & VIRTIO_DESC_F_NEXT == 0 {
+				break;
+			}
+			i = self.desc[i].next as usize;
+		}
+	}
 
-			self.desc[idx[0]].addr = &self.header[idx[0] as usize] as *const BlkOuthdr as u64;
-			self.desc[idx[0]].len = size_of::<BlkOuthdr>() as u32;
-			self.desc[idx[0]].flags = VIRTIO_DESC_F_NEXT;
-			self.desc[idx[0]].next = idx[1] as u16;
-			self.desc[idx[1]].addr = buf_addr as u64;
-			self.desc[idx[1]].len = SECTOR_SIZE as u32;
-			self.desc[idx[1]].flags =
-				VIRTIO_DESC_F_NEXT | (if !is_write { VIRTIO_DESC_F_WRITE } else { 0 });
-			self.desc[idx[1]].next = idx[2] as u16;
+	// This function sends a request which consists of a header, an optional data buffer and a status byte.
+	// It returns the index of the head descriptor, which is also used to identify the request.
+	fn request(
+		&mut self,
+		typ: u32,
+		sector: u64,
+		buf_addr: *const u8,
+		len: usize,
+		device_writes: bool,
+	) -> Result<usize, RequestError> {
+		let chain_len = if len > 0 { 3 } else { 2 };
+		let mut idx = [0; 3];
+		for i in 0..chain_len {
+			match self.alloc_desc() {
+				Some(d) => idx[i] = d,
+				None => {
+					for j in 0..i {
+						self.free_slot[idx[j]] = true;
+					}
+					return Err(RequestError::NoDescriptor);
+				}
+			}
+		}
+		let head = idx[0];
+		let status_idx = idx[chain_len - 1];
 
-			self.vinfo[idx[0] as usize].status = 0xff;
-			self.desc[idx[2]].addr = &self.vinfo[idx[0] as usize].status as *const u8 as u64;
-			self.desc[idx[2]].len = 1;
-			self.desc[idx[2]].flags = VIRTIO_DESC_F_WRITE;
-			self.desc[idx[2]].next = 0 as u16;
+		self.header[head].typ = typ;
+		self.header[head].reserved = 0;
+		self.header[head].sector = sector;
 
-			self.avail.ring[self.avail.idx as usize % VIRTIO_RING_SIZE] = idx[0] as u16;
-			self.avail.idx += 1;
-			self.notify_slot[idx[0]] = false;
-			Offset::QueueNotify
-				.apply(&self.device_base_addr)
-				.write_volatile(0);
-			idx[0]
+		self.desc[head].addr = &self.header[head] as *const BlkOuthdr as u64;
+		self.desc[head].len = size_of::<BlkOuthdr>() as u32;
+		self.desc[head].flags = VIRTIO_DESC_F_NEXT;
+		self.desc[head].next = idx[1] as u16;
+
+		if len > 0 {
+			self.desc[idx[1]].addr = buf_addr as u64;
+			self.desc[idx[1]].len = len as u32;
+			self.desc[idx[1]].flags = VIRTIO_DESC_F_NEXT
+				| (if device_writes {
+					VIRTIO_DESC_F_WRITE
+				} else {
+					0
+				});
+			self.desc[idx[1]].next = status_idx as u16;
 		}
+
+		self.vinfo[head].status = 0xff;
+		self.desc[status_idx].addr = &self.vinfo[head].status as *const u8 as u64;
+		self.desc[status_idx].len = 1;
+		self.desc[status_idx].flags = VIRTIO_DESC_F_WRITE;
+		self.desc[status_idx].next = 0;
+
+		self.notify_slot[head] = false;
+		self.avail.ring[self.avail.idx as usize % VIRTIO_RING_SIZE] = head as u16;
+		// descriptors must be visible to the device before the index is published
+		fence(Ordering::SeqCst);
+		self.avail.idx = self.avail.idx.wrapping_add(1);
+		fence(Ordering::SeqCst);
+		self.transport.notify();
+		Ok(head)
 	}
 
-	pub fn read(&mut self, sector: u64, buf_addr: *const ()) {
-		let idx = self.request(sector, buf_addr, false);
-		log::debug!("request was sent. watching id: {}", idx);
+	// This function blocks until the request identified by `head` is handled by the device.
+	// The used ring is also drained here, so that requests issued with interrupts disabled
+	// (e.g. from a trap handler) can complete.
+	fn wait(&mut self, head: usize) -> Result<(), RequestError> {
+		log::debug!("request was sent. watching id: {}", head);
 
 		// TODO (enhancement): this spin lock is too heavy; we can do better
-		unsafe { while !(&self.notify_slot[idx] as *const bool).read_volatile() {} }
+		loop {
+			let sstatus = riscv::csr::sstatus::read();
+			riscv::csr::sstatus::write(sstatus & !riscv::csr::sstatus::SIE);
+			self.collect_used();
+			let finished = unsafe { (&self.notify_slot[head] as *const bool).read_volatile() };
+			riscv::csr::sstatus::write(sstatus);
+			if finished {
+				break;
+			}
+		}
 
-		log::debug!("request was handled: {}", idx);
+		log::debug!("request was handled: {}", head);
+		let status = unsafe { (&self.vinfo[head].status as *const u8).read_volatile() };
+		self.free_chain(head);
+		match status {
+			VIRTIO_BLK_S_OK => Ok(()),
+			VIRTIO_BLK_S_UNSUPP => Err(RequestError::Unsupported),
+			VIRTIO_BLK_S_IOERR | _ => Err(RequestError::IoError),
+		}
 	}
 
-	pub fn write(&mut self, sector: u64, buf_addr: *const ()) {
-		let idx = self.request(sector, buf_addr, true);
-		log::debug!("request was sent. watching id: {}", idx);
+	fn check_range(&self, sector: u64, count: usize) -> Result<(), RequestError> {
+		match sector.checked_add(count as u64) {
+			Some(end) if end <= self.capacity => Ok(()),
+			_ => Err(RequestError::OutOfRange),
+		}
+	}
 
-		// TODO (enhancement): this spin lock is too heavy; we can do better
-		unsafe { while !(&self.notify_slot[idx] as *const bool).read_volatile() {} }
+	// This function reads `count` sectors starting from `sector` into `buf_addr`.
+	pub fn read(
+		&mut self,
+		sector: u64,
+		buf_addr: *mut u8,
+		count: usize,
+	) -> Result<(), RequestError> {
+		self.check_range(sector, count)?;
+		if count == 0 {
+			return Ok(());
+		}
+		let head = self.request(VIRTIO_BLK_T_IN, sector, buf_addr, count * SECTOR_SIZE, true)?;
+		self.wait(head)
+	}
+
+	// This function writes `count` sectors from `buf_addr` into the disk starting from `sector`.
+	pub fn write(
+		&mut self,
+		sector: u64,
+		buf_addr: *const u8,
+		count: usize,
+	) -> Result<(), RequestError> {
+		if self.is_read_only() {
+			return Err(RequestError::ReadOnly);
+		}
+		self.check_range(sector, count)?;
+		if count == 0 {
+			return Ok(());
+		}
+		let head = self.request(
+			VIRTIO_BLK_T_OUT,
+			sector,
+			buf_addr,
+			count * SECTOR_SIZE,
+			false,
+		)?;
+		self.wait(head)
+	}
+
+	// This function makes all the completed writes persistent.
+	// NOTE: a device without VIRTIO_BLK_F_FLUSH is write-through, so nothing has to be done.
+	pub fn flush(&mut self) -> Result<(), RequestError> {
+		if self.features & VIRTIO_BLK_F_FLUSH == 0 {
+			return Ok(());
+		}
+		let head = self.request(VIRTIO_BLK_T_FLUSH, 0, core::ptr::null(), 0, false)?;
+		self.wait(head)
+	}
 
-		log::debug!("request was handled: {}", idx);
+	// This function reads the device identity (the serial of the disk) into `buf`.
+	// The string is NUL-padded and not NUL-terminated if it has exactly 20 bytes.
+	pub fn get_id(&mut self, buf: &mut [u8; VIRTIO_BLK_ID_BYTES]) -> Result<(), RequestError> {
+		let head = self.request(
+			VIRTIO_BLK_T_GET_ID,
+			0,
+			buf.as_mut_ptr(),
+			VIRTIO_BLK_ID_BYTES,
+			true,
+		)?;
+		self.wait(head)
+	}
+
+	// the number of 512-byte sectors in the disk
+	pub fn capacity(&self) -> u64 {
+		self.capacity
+	}
+
+	// the optimal block size reported by the device (in bytes)
+	pub fn block_size(&self) -> u32 {
+		self.block_size
+	}
+
+	pub fn is_read_only(&self) -> bool {
+		self.features & VIRTIO_BLK_F_RO != 0
 	}
 
 	pub fn mark_finished(&mut self, id: usize) {
 		self.notify_slot[id] = true;
 	}
+
+	fn collect_used(&mut self) {
+		fence(Ordering::SeqCst);
+		while self.used_idx != unsafe { (&self.used.idx as *const u16).read_volatile() } {
+			let used_elem = &self.used.ring[self.used_idx as usize % VIRTIO_RING_SIZE];
+			let finished_id = used_elem.id;
+			log::debug!("used_elem: id={}, len={}", used_elem.id, used_elem.len);
+			self.mark_finished(finished_id as usize);
+			self.used_idx = self.used_idx.wrapping_add(1);
+		}
+	}
 }
 
-pub fn init() {
-	// log::info!("virtio0 addr: 0x{:016x}", memlayout::VIRTIO0_BASE);
-	let base = memlayout::VIRTIO0_BASE as *mut u32;
-	assert_device_status(&base);
-	assert_device_type(&base, 2);
-	log::info!("a block device found");
+// This function returns the first virtio-blk device among the virtio-mmio slots with its interrupt.
+// Slots whose bits are set in `reserved_slots` (e.g. devices passed through to guests) are skipped.
+fn find_mmio_device(reserved_slots: u32) -> Option<(Transport, u32)> {
+	for slot in 0..memlayout::VIRTIO_MMIO_SLOTS {
+		if reserved_slots & (1 << slot) != 0 {
+			log::info!("virtio slot {} is reserved for a guest", slot);
+			continue;
+		}
+		let base = slot_base(slot);
+		if is_device_type(&base, 2) {
+			assert_device_status(&base);
+			assert_device_type(&base, 2);
+			log::info!("a block device found at slot {}", slot);
+			return Some((Transport::Mmio(base), slot_irq(slot)));
+		}
+	}
+	None
+}
+
+// This function returns the first virtio-blk-pci device with its interrupt.
+fn find_pci_device() -> Option<(Transport, u32)> {
+	for f in pci::functions() {
+		let is_blk = f.vendor_id == VIRTIO_PCI_VENDOR
+			&& (f.device_id == VIRTIO_PCI_DEVICE_BLK
+				|| (f.device_id == VIRTIO_PCI_DEVICE_BLK_TRANSITIONAL && f.subsystem_id == 2));
+		if !is_blk {
+			continue;
+		}
+		let irq = match f.irq {
+			Some(irq) => irq,
+			None => continue,
+		};
+		match PciTransport::from_function(f) {
+			Some(t) => {
+				log::info!(
+					"a block device found at pci {:02x}:{:02x}.{}",
+					f.bus,
+					f.device,
+					f.function
+				);
+				return Some((Transport::Pci(t), irq));
+			}
+			None => log::info!("virtio-pci: legacy-only devices are not supported"),
+		}
+	}
+	None
+}
+
+// This function initializes the first virtio-blk device, looking for virtio-mmio slots first and then PCI.
+// Slots whose bits are set in `reserved_slots` (e.g. devices passed through to guests) are skipped.
+pub fn init(reserved_slots: u32) {
+	let (transport, irq) = match find_mmio_device(reserved_slots).or_else(find_pci_device) {
+		Some(d) => d,
+		None => {
+			log::info!("no block device found");
+			return;
+		}
+	};
 
-	let queue_page = paging::alloc_continuous(2);
+	let queue_page = match paging::alloc_continuous(2) {
+		Ok(p) => p,
+		Err(_) => {
+			log::info!("no memory for the queue of the block device");
+			return;
+		}
+	};
 	log::info!(
 		"-> allocated query object: 0x{:016x}",
 		queue_page.address().to_usize()
@@ -208,65 +720,85 @@ pub fn init() {
 	let queue = Queue::from_page(queue_page);
 	unsafe {
 		// TODO (enhancement): support multi core
-		(*queue).device_base_addr = base;
+		(*queue).transport = transport;
 		QUEUE = Some(queue);
-		INITIALIZED = true;
+		IRQ = Some(irq);
+	}
+	init_block_device(queue);
+
+	unsafe {
+		log::info!(
+			"-> capacity: {} sectors, block size: {} bytes, read-only: {}",
+			(*queue).capacity(),
+			(*queue).block_size(),
+			(*queue).is_read_only()
+		);
+		let mut id = [0u8; VIRTIO_BLK_ID_BYTES];
+		match (*queue).get_id(&mut id) {
+			Ok(()) => {
+				let len = id.iter().position(|&c| c == 0).unwrap_or(id.len());
+				log::info!(
+					"-> device id: {}",
+					core::str::from_utf8(&id[..len]).unwrap_or("(non-utf8)")
+				);
+			}
+			Err(e) => log::info!("-> device id: unavailable ({:?})", e),
+		}
 	}
-	init_block_device(&base, queue);
 }
 
-fn init_block_device(base: &*mut u32, queue: *mut Queue) {
+fn init_block_device(queue: *mut Queue) {
 	let mut status: u32 = 0;
 
 	unsafe {
-		let status_addr = Offset::Status.apply(base);
+		let transport = &mut (*queue).transport;
 
 		// start to config
 		status |= StatusFlag::Acknowledge as u32;
-		status_addr.write_volatile(status);
+		transport.set_status(status);
 
 		status |= StatusFlag::Driver as u32;
-		status_addr.write_volatile(status);
+		transport.set_status(status);
 
 		// set features
-		let mut features: u32 = Offset::HostFeatures.apply(base).read_volatile();
-		features &= !(VIRTIO_BLK_F_RO as u32);
+		let mut features: u32 = transport.device_features(0);
 		features &= !(VIRTIO_BLK_F_SCSI as u32);
 		features &= !(VIRTIO_BLK_F_CONFIG_WCE as u32);
 		features &= !(VIRTIO_BLK_F_MQ as u32);
 		features &= !(VIRTIO_F_ANY_LAYOUT as u32);
 		features &= !(VIRTIO_RING_F_EVENT_IDX as u32);
 		features &= !(VIRTIO_RING_F_INDIRECT_DESC as u32);
-		Offset::HostFeatures.apply(base).write_volatile(features);
+		transport.set_driver_features(0, features);
+		if let Transport::Pci(_) = transport {
+			// the modern interface requires VIRTIO_F_VERSION_1
+			if transport.device_features(1) & VIRTIO_F_VERSION_1 == 0 {
+				panic!("virtio-pci device does not offer VIRTIO_F_VERSION_1");
+			}
+			transport.set_driver_features(1, VIRTIO_F_VERSION_1);
+		}
+		(*queue).features = features;
 
 		// finish feature configuration
 		status |= StatusFlag::FeaturesOk as u32;
-		status_addr.write_volatile(status);
-
-		// finish configuration
-		status |= StatusFlag::DriverOk as u32;
-		status_addr.write_volatile(status);
-
-		// tell our page size to virtio
-		Offset::GuestPageSize
-			.apply(base)
-			.write_volatile(memlayout::PAGE_SIZE as u32);
+		transport.set_status(status);
+		if transport.status() & StatusFlag::FeaturesOk as u32 == 0 {
+			panic!("virtio disk did not accept the features");
+		}
 
-		// set first queue selector
-		Offset::QueueSel.apply(base).write_volatile(0);
+		// read the device configuration
+		let config = transport.config();
+		(*queue).capacity = BlkConfig::Capacity.read64(config);
+		(*queue).block_size = if features & VIRTIO_BLK_F_BLK_SIZE != 0 {
+			BlkConfig::BlkSize.read32(config)
+		} else {
+			SECTOR_SIZE as u32
+		};
 
-		// set our queue num
-		let queue_num = VIRTIO_RING_SIZE as u32;
-		let queue_max = Offset::QueueNumMax.apply(base).read_volatile();
-		if queue_num > queue_max {
-			panic!("virtio disk has invalid queue max setting");
-		}
-		Offset::QueueNum.apply(base).write_volatile(queue_num);
+		transport.setup_queue(queue);
 
-		// set our queue addr
-		Offset::QueuePfn
-			.apply(base)
-			.write_volatile(((queue as usize) >> 12) as u32);
+		// finish configuration
+		status |= StatusFlag::DriverOk as u32;
+		transport.set_status(status);
 	}
 }
 
@@ -288,6 +820,10 @@ fn assert_device_status(base: &*mut u32) {
 	}
 }
 
+fn is_device_type(base: &*mut u32, t: u32) -> bool {
+	unsafe { base.read_volatile() == VIRTIO_MAGIC && base.offset(2).read_volatile() == t }
+}
+
 fn assert_device_type(base: &*mut u32, t: u32) {
 	unsafe {
 		let device_id = base.offset(2).read_volatile();
@@ -298,26 +834,17 @@ fn assert_device_type(base: &*mut u32, t: u32) {
 }
 
 pub fn handle_interrupt(interrupt: u32) {
-	let device_id = interrupt as usize - 1;
-	if device_id == 0 {
+	if irq() == Some(interrupt) {
 		unsafe {
 			// TODO (enhancement): notify related contes here
 			if let Some(_queue) = QUEUE {
-				while ((*_queue).used_idx as usize % VIRTIO_RING_SIZE)
-					!= ((*_queue).used.idx as usize % VIRTIO_RING_SIZE)
-				{
-					let used_elem =
-						&(*_queue).used.ring[(*_queue).used_idx as usize % VIRTIO_RING_SIZE];
-					let finished_id = used_elem.id;
-					log::debug!("used_elem: id={}, len={}", used_elem.id, used_elem.len);
-					(*_queue).mark_finished(finished_id as usize);
-					(*_queue).used_idx += 1;
-				}
+				(*_queue).transport.ack_interrupt();
+				(*_queue).collect_used();
 			} else {
 				panic!("virtio queue uninitialized")
 			}
 		}
 	} else {
-		panic!("invalid interrupt from virtio0: {}", interrupt);
+		panic!("invalid interrupt from virtio: {}", interrupt);
 	}
 }